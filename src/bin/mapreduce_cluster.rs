@@ -0,0 +1,44 @@
+use std::{env, io};
+use MapReduce::local_cluster::LocalCluster;
+
+/// 开发用: 在本机上启动一个 server 和若干个 worker daemon, 打印它们的地址, 按回车一起关掉. \
+/// mapreduce_cluster `base_dir` `worker_num` [`capacity`] [`hdfs_client_host` `username`] \
+/// 每个进程的工作目录在 base_dir 下; capacity 是每个 worker daemon 最多同时执行的子任务数, 默认是 2.
+/// mapreduce_server 与 mapreduce_daemon 要和它在同一个目录下(cargo build 会一起编译).
+fn main() {
+    let args : Vec<String> = env::args().collect();
+    if args.len() < 3 || args.len() == 5 || args.len() > 6 {
+        eprintln!("usage: {} <base_dir> <worker_num> [capacity] [hdfs_client_host username]", args[0]);
+        std::process::exit(2);
+    }
+    let number = |index : usize, default : usize| -> usize {
+        match args.get(index) {
+            Some(number) => number.parse().unwrap_or_else(|e| {
+                eprintln!("invalid number {} : {}", number, e);
+                std::process::exit(2);
+            }),
+            None => default,
+        }
+    };
+    let worker_num = number(2, 0);
+    let capacity = number(3, 2);
+    let hdfs = if args.len() == 6 { Some((args[4].as_str(), args[5].as_str())) } else { None };
+
+    let cluster = match LocalCluster::start(&args[1], worker_num, capacity, hdfs) {
+        Ok(cluster) => cluster,
+        Err(e) => {
+            eprintln!("cannot start the local cluster : {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Local cluster is ready.");
+    println!("server\t{}\t{}", cluster.server_host(), cluster.server_dir().display());
+    for worker in cluster.workers() {
+        println!("worker daemon {}\tshuffle {}\t{}", worker.worker_id, worker.shuffle_host, worker.dir.display());
+    }
+    println!("Press Enter to shut down the cluster.");
+    let mut line = String::new();
+    let _ = io::stdin().read_line(&mut line);
+    cluster.shutdown();
+    println!("Local cluster is shut down.");
+}
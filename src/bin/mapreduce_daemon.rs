@@ -0,0 +1,32 @@
+use std::env;
+use MapReduce::{run_worker_daemon, map_reduce::SETUP_GLOBAL_HDFS_CLIENT};
+
+/// worker daemon, 一个独立的worker进程: 连接server并注册自己, 然后执行server分配来的 mapper/reducer 子任务. \
+/// mapreduce_daemon `server_host` [`capacity`] [`hdfs_client_host` `username`] \
+/// capacity 是最多同时执行的子任务数, 默认是cpu的核数. mapper 的输出由daemon自己的 shuffle 服务提供.
+/// mapper 直接读hdfs上的输入文件, 所以要给出 hdfs 客户端的 host 与用户名(此时必须给出 capacity);
+/// 插件的路径是server上的路径, 所以daemon要和server在同一台机器上(或者共享文件系统).
+fn main() {
+    let args : Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() == 4 || args.len() > 5 {
+        eprintln!("usage: {} <server_host> [capacity] [hdfs_client_host username]", args[0]);
+        std::process::exit(2);
+    }
+    let capacity = match args.get(2) {
+        Some(capacity) => capacity.parse().unwrap_or_else(|e| {
+            eprintln!("invalid capacity {} : {}", capacity, e);
+            std::process::exit(2);
+        }),
+        None => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+    };
+    if args.len() == 5 {
+        if let Err(e) = SETUP_GLOBAL_HDFS_CLIENT(&args[3], &args[4]) {
+            eprintln!("cannot set up the hdfs client : {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = run_worker_daemon(&args[1], capacity) {
+        eprintln!("mapreduce_daemon failed : {}", e);
+        std::process::exit(1);
+    }
+}
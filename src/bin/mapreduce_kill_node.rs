@@ -0,0 +1,23 @@
+use std::env;
+use MapReduce::map_reduce_client::kill_simulated_node;
+
+/// 开发用: 杀掉模拟模式的server中的一个节点, 它的本地文件消失, 正在执行的子任务重新分配. \
+/// mapreduce_kill_node `server_host` `node_id`
+fn main() {
+    let args : Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <server_host> <node_id>", args[0]);
+        std::process::exit(2);
+    }
+    let node_id = args[2].parse().unwrap_or_else(|e| {
+        eprintln!("invalid node id {} : {}", args[2], e);
+        std::process::exit(2);
+    });
+    match kill_simulated_node(&args[1], node_id) {
+        Ok(()) => println!("Node {} is killed.", node_id),
+        Err(e) => {
+            eprintln!("cannot kill node {} : {}", node_id, e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::env;
+use MapReduce::{run_server, run_simulated_server, map_reduce::SETUP_GLOBAL_HDFS_CLIENT};
+
+/// mapreduce server. \
+/// mapreduce_server [`--nodes` `node_num`] `host` [`master_num`] [`worker_num`] [`hdfs_client_host` `username`] \
+/// master_num 与 worker_num 默认都是 2; 给出 hdfs 客户端的 host 与用户名时先初始化 hdfs 客户端
+/// (client 通过 hdfs 传输输入与结果文件, 真正执行任务时需要). 见 mapreduce_cluster. \
+/// host 的端口可以是 0(由系统分配), 开始接受连接时打印一行 `MAPREDUCE_READY {实际的地址}`. \
+/// 给出 --nodes 时以模拟模式运行: worker 线程分成 node_num 个虚拟节点, 每个节点 worker_num 个线程, 见 mapreduce_kill_node.
+fn main() {
+    let mut args : Vec<String> = env::args().collect();
+    let usage = format!("usage: {} [--nodes node_num] <host> [master_num] [worker_num] [hdfs_client_host username]", args[0]);
+    let number = |arg : Option<&String>, default : usize| -> usize {
+        match arg {
+            Some(number) => number.parse().unwrap_or_else(|e| {
+                eprintln!("invalid number {} : {}", number, e);
+                std::process::exit(2);
+            }),
+            None => default,
+        }
+    };
+    let mut node_num = None;
+    if args.get(1).is_some_and(|arg| arg == "--nodes") {
+        if args.len() < 3 {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        }
+        node_num = Some(number(args.get(2), 0));
+        args.drain(1..3);
+    }
+    if args.len() < 2 || args.len() == 5 || args.len() > 6 {
+        eprintln!("{}", usage);
+        std::process::exit(2);
+    }
+    let master_num = number(args.get(2), 2);
+    let worker_num = number(args.get(3), 2);
+    if args.len() == 6 {
+        if let Err(e) = SETUP_GLOBAL_HDFS_CLIENT(&args[4], &args[5]) {
+            eprintln!("cannot set up the hdfs client : {}", e);
+            std::process::exit(1);
+        }
+    }
+    match node_num {
+        Some(node_num) => run_simulated_server(&args[1], master_num, node_num, worker_num),
+        None => run_server(&args[1], master_num, worker_num),
+    }
+}
@@ -0,0 +1,14 @@
+use MapReduce::{run_worker_process, WorkerAllocator};
+
+// 分配失败时以约定的退出码退出, server 据此报告 MemoryLimitExceeded.
+#[global_allocator]
+static ALLOCATOR : WorkerAllocator = WorkerAllocator;
+
+/// worker子进程, 由server在以 Process 方式执行子任务时启动, 一般不需要手动运行. \
+/// 它从stdin读入一个子任务的描述, 加载用户插件执行, 然后把结果写到stdout.
+fn main() {
+    if let Err(e) = run_worker_process() {
+        eprintln!("mapreduce_worker failed : {}", e);
+        std::process::exit(1);
+    }
+}
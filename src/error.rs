@@ -13,9 +13,10 @@ pub enum MapReduceError{
     #[error("Unknown task id received")]
     WrongTaskId,
 
-    #[error("Couldn't load pub fn {fntype:?}")]
+    #[error("Couldn't load pub fn {fntype:?}: {reason}")]
     DllLoadingError{
         fntype : String,
+        reason : String,
     },
 
     #[error("File IO errors occur")]
@@ -27,4 +28,26 @@ pub enum MapReduceError{
 
     #[error("Task Failed.")]
     TaskFailed,
+
+    #[error("User {fntype} panicked: {message}")]
+    UserCodePanicked{
+        fntype : String,
+        message : String,
+    },
+
+    #[error("User {fntype} ran out of fuel")]
+    UserCodeOutOfFuel{
+        fntype : String,
+    },
+
+    #[error("User {fntype} exceeded the wasm memory limit")]
+    UserCodeOutOfMemory{
+        fntype : String,
+    },
+
+    #[error("Couldn't fetch the output of mapper {mapper}: {reason}")]
+    ShuffleFetchFailed{
+        mapper : u32,
+        reason : String,
+    },
 }
\ No newline at end of file
@@ -3,7 +3,7 @@
 /// 应该用些宏定义简化代码...
 /// 存在一个平台的差异：Win下以write权限打开文件如果没有会直接创建一个；Linux下如果没有会报错。必须在option上加上.create(true)来保证.
 use std::{
-    io::{prelude::*, BufReader},
+    io::{prelude::*, BufReader, SeekFrom},
     fs,
     fs::File,
     path::Path, sync::Once, str::FromStr, os::unix::net::SocketAddr, borrow::BorrowMut,
@@ -45,7 +45,7 @@ impl HdfsSetting {
     pub fn get_global_client() -> IOResult<&'static HdfsClient> {
         let r = HDFS_CLIENT_INSTANCE.get();
         if r.is_none() {
-            return Err(MapReduceError::FileIOError(std::io::Error::new(std::io::ErrorKind::Other, "HDFS client unset.")));
+            return Err(MapReduceError::FileIOError(std::io::Error::other("HDFS client unset.")));
         }
         let r = r.unwrap();
         Ok(&r.hdfs_client)
@@ -171,14 +171,13 @@ pub fn iowrapper_get_absolute_path(path:&String) -> IOResult<String> {
 /// 如果是hdfs，返回false可能是hdfs正常但路径不存在，也可能是hdfs不正常(比如客户端连接失败)
 pub fn iowrapper_exist(path:&String) -> bool {
     let hdfs_head = HdfsSetting::path_head();
-    if path.starts_with(hdfs_head) {
-        let path = &path[hdfs_head.len()..];
+    if let Some(path) = path.strip_prefix(hdfs_head) {
         let client = HdfsSetting::get_global_client();
         if client.is_err() {
             return false;
         }
         let client = client.unwrap();
-        match client.metadata(&path) {
+        match client.metadata(path) {
             Ok(_) => {return true;},
             Err(_) => {return false;}
         };
@@ -324,7 +323,7 @@ impl IOWrapperFile{
         } else {
             // let f : T = File::options().write(true).open(path)?;
             // Ok(IOWrapperFile { f })
-            let f = File::options().write(true).create(true).open(path)?;
+            let f = File::options().write(true).create(true).truncate(true).open(path)?;
             Ok(IOWrapperFile { f_std : Some(f), f_hdrs : None})
         }
     }
@@ -350,7 +349,7 @@ impl IOWrapperFile{
         if path.starts_with(HdfsSetting::path_head()) {
             let path = &path[HdfsSetting::path_head().len()..];
             let client = HdfsSetting::get_global_client()?;
-            let f = client.open_file().read(true).open(&path)?;
+            let f = client.open_file().read(true).open(path)?;
             // Ok(IOWrapperFile { f })
             Ok(IOWrapperFile { f_std : None, f_hdrs : Some(f)})
         } else {
@@ -363,11 +362,11 @@ impl IOWrapperFile{
     /// 向文件中写入内容.
     pub fn write(&mut self, content : &[u8]) -> IOResult<()> {
         if let Some(ref mut f) = self.f_std {
-            f.write(content)?;
+            f.write_all(content)?;
         }
         else {
             let mut f = self.f_hdrs.as_ref().unwrap();
-            f.write(content)?;
+            f.write_all(content)?;
         }
         Ok(())
         //Ok(self.f.write(content)?)
@@ -385,62 +384,66 @@ impl Read for IOWrapperFile {
     }
 }
 
-/// 文件分块
-pub fn iowrapper_file_blocking(file_to_block:&String, target_dir:&String, n_blocks:u32)
-        -> IOResult<()> {
-    // 结果文件命名格式为 target_dir/%d.xxx
-    // 直接简单粗暴地按字节截取可能会导致把某些字符截到了一半(因为是utf-8...)
-    // 所以试试按行数尽量均匀划分，每次读写一行...
-    // 文件操作应该全部使用iowrapper中的内容.
-    if !iowrapper_exist(file_to_block) {
-        return Err(
-            MapReduceError::FileIOError(std::io::Error::new(std::io::ErrorKind::NotFound,
-                                format!("File {} does not exist.",file_to_block)))
-        );
+impl Seek for IOWrapperFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        if let Some(ref mut f) = self.f_std {
+            f.seek(pos)
+        } else {
+            let mut f = self.f_hdrs.as_ref().unwrap();
+            f.seek(pos)
+        }
     }
-    let extension = match iowrapper_get_extension(file_to_block) {
-        Ok(ext) => Some(ext),
-        Err(_) => None
-    };
-
-    let line_count = BufReader::new(IOWrapperFile::open_read(file_to_block)?)
-                                                    .lines().count();
-    let lines = BufReader::new(IOWrapperFile::open_read(file_to_block)?)
-                                                    .lines();
-    let floor = line_count / n_blocks as usize;
-    let reminder = line_count % n_blocks as usize;
-    let mut fws = Vec::new();
-    for i in 0..n_blocks as usize {
-        let block_f_name = match extension {
-            Some(ref ext_name) => {   // extension只有1个.如果把其内容所有权移入for循环，会在第一次结束后drop.
-                format!("{}.{}",i,ext_name)
-            }
-            None => {
-                format!("{}",i)
-            }
-        };
-        let output_block_path = path_join(target_dir, &block_f_name);
-        // 必须先创建....
-        iowrapper_create_file(&output_block_path)?;
-        let fw = IOWrapperFile::open_empty(&output_block_path)?;
-        fws.push(fw);
+}
+
+impl Write for IOWrapperFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(ref mut f) = self.f_std {
+            f.write(buf)
+        } else {
+            let mut f = self.f_hdrs.as_ref().unwrap();
+            f.write(buf)
+        }
     }
-    let mut saved_lines = 0;
-    let mut to_write = 0;
-    for line in lines {
-        match line {
-            Ok(mut line) => {
-                line.push('\n');   //加个换行符号...
-                fws[to_write].write(line.as_bytes())?;
-                saved_lines += 1;
-                if saved_lines >= (if to_write < reminder {floor+1} else {floor}) 
-                        && to_write < n_blocks as usize-1{
-                    saved_lines = 0;
-                    to_write += 1;
-                }
-            },
-            Err(_) => {continue;}
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(ref mut f) = self.f_std {
+            f.flush()
+        } else {
+            let mut f = self.f_hdrs.as_ref().unwrap();
+            f.flush()
+        }
+    }
+}
+
+/// 读文件中 [start, end) 这一段字节, 它必须是完整的 utf-8 文本(mapper 的输入分段的边界都在行首).
+pub fn iowrapper_read_range(path:&String, start:u64, end:u64) -> IOResult<String> {
+    let mut f = IOWrapperFile::open_read(path)?;
+    f.seek(SeekFrom::Start(start))?;
+    let mut buf = Vec::with_capacity(end.saturating_sub(start) as usize);
+    f.take(end.saturating_sub(start)).read_to_end(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| MapReduceError::FileIOError(
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} [{}, {}) is not utf-8 : {}", path, start, end, e))))
+}
+
+/// 在一个文件中找行首的位置. 文件只打开一次, 找多个位置时不用每次都重新打开(在hdfs上打开文件要一次远程调用).
+pub struct LineStartFinder {
+    reader : BufReader<IOWrapperFile>,
+}
+
+impl LineStartFinder {
+    pub fn open(path:&String) -> IOResult<LineStartFinder> {
+        Ok(LineStartFinder { reader : BufReader::new(IOWrapperFile::open_read(path)?) })
+    }
+
+    /// offset 处或者之后第一行的开头: offset 本身在行首(是 0 或者前一个字节是换行)就是 offset,
+    /// 否则是下一个换行之后的位置; 后面没有换行了就是文件的大小.
+    pub fn next_line_start(&mut self, offset:u64) -> IOResult<u64> {
+        if offset == 0 {
+            return Ok(0);
         }
+        self.reader.seek(SeekFrom::Start(offset - 1))?;
+        let mut skipped = Vec::new();
+        let read = self.reader.read_until(b'\n', &mut skipped)?;
+        Ok(offset - 1 + read as u64)
     }
-    Ok(())    
 }
\ No newline at end of file
@@ -0,0 +1,568 @@
+/// 带类型的用户任务接口.
+/// 用户不再需要把所有东西都编码成 String 和 Vec<String>，而是实现 Job trait，指定自己的 Key 和 Value 类型.
+/// 框架在插件边界以及中间文件中用 json 序列化这些键值对:
+/// mapper 返回的是 MapOutput 的 json 文本, reducer 收到的是 key 的 json 文本和 values 的 json 数组文本. \
+/// 除了主输出以外, mapper/reducer 还可以通过 Outputs 把记录写到命名输出中, 比如把不合格的记录单独输出;
+/// 通过 TaskContext 可以取得随任务一起提交的附属文件(查找表、停用词表等). \
+/// 输出很多的 mapper 可以实现 map_emit, 通过 MapContext 一个一个地交出键值对,
+/// 框架收到之后直接分区、缓存, 缓存满了就写到磁盘上, 内存的使用不再随 mapper 的输出增长;
+/// 同样, 一个 key 的 value 很多时可以实现 reduce_stream, 从迭代器中一个一个地读 value, 通过 ReduceContext 交出输出.
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::plugin::abi::{HOST_EMIT, HOST_NAMED, HOST_OUTPUT, HOST_PROGRESS};
+
+/// 用户实现的 MapReduce 任务.
+/// Key 和 Value 是 mapper 输出、reducer 输入的键值类型; Output 是 reducer 对每个 key 输出的记录类型. \
+/// **map, map_with_outputs, map_emit 三者至少要实现一个, reduce, reduce_with_outputs, reduce_stream 也一样**,
+/// 它们的默认实现互相调用: 一个都没有实现的话, 第一次调用时 panic(子任务报告为 Panicked), 而不是无限递归. \
+/// 中间数据按 key 的 json 文本排序, 每个 reducer 按这个顺序收到 key, 这不是 Key 类型自己的顺序:
+/// 数字 key 按字符串比较(10 排在 9 前面, -1 排在 -2 前面). 需要数值顺序时可以把 key 写成定长补零的字符串.
+pub trait Job : Default {
+    type Key : Serialize + DeserializeOwned;
+    type Value : Serialize + DeserializeOwned;
+    type Output : Serialize;
+
+    /// 处理一个输入分块的全部内容, 返回若干键值对. 默认收集 map_emit 交出的键值对.
+    fn map(&self, content : &str) -> Vec<(Self::Key, Self::Value)> {
+        let _guard = DefaultCycleGuard::enter(&IN_DEFAULT_MAP, "map, map_with_outputs or map_emit");
+        let mut pairs = Vec::new();
+        let mut sink = |event| {
+            if let MapEvent::Emit(k, v) = event {
+                pairs.push((k, v));
+            }
+            true
+        };
+        self.map_emit(content, &mut MapContext::new(&mut sink));
+        pairs
+    }
+
+    /// 处理一个 key 以及它所有的 value. 默认收集 reduce_stream 交出的输出.
+    fn reduce(&self, key : Self::Key, values : Vec<Self::Value>) -> Vec<Self::Output> {
+        let _guard = DefaultCycleGuard::enter(&IN_DEFAULT_REDUCE, "reduce, reduce_with_outputs or reduce_stream");
+        let mut records = Vec::new();
+        let mut sink = |event| {
+            if let ReduceEvent::Output(record) = event {
+                records.push(record);
+            }
+            true
+        };
+        self.reduce_stream(key, &mut values.into_iter(), &mut ReduceContext::new(&mut sink));
+        records
+    }
+
+    /// 与 map 相同, 但是可以通过 outputs 向命名输出写记录. 默认直接调用 map.
+    fn map_with_outputs(&self, content : &str, _outputs : &mut Outputs) -> Vec<(Self::Key, Self::Value)> {
+        self.map(content)
+    }
+
+    /// emit 风格的 map: 通过 ctx.emit 一个一个地交出键值对, 不必把全部输出放在内存里.
+    /// 框架调用的是这个函数, 默认调用 map_with_outputs 再把结果逐个 emit.
+    fn map_emit(&self, content : &str, ctx : &mut MapContext<Self::Key, Self::Value>) {
+        let pairs = self.map_with_outputs(content, ctx.outputs());
+        for (k, v) in pairs {
+            ctx.emit(k, v);
+        }
+    }
+
+    /// 与 reduce 相同, 但是可以通过 outputs 向命名输出写记录. 默认直接调用 reduce.
+    fn reduce_with_outputs(&self, key : Self::Key, values : Vec<Self::Value>, _outputs : &mut Outputs) -> Vec<Self::Output> {
+        self.reduce(key, values)
+    }
+
+    /// 流式的 reduce: values 是从归并后的中间文件中一个一个读出来的, 输出记录通过 ctx.output 一条一条地交出,
+    /// 一个 key 有再多的 value 或输出也不必全部放在内存里. 没有读完的 value 会被跳过.
+    /// 框架调用的是这个函数, 默认把 values 收集起来调用 reduce_with_outputs.
+    fn reduce_stream(&self, key : Self::Key, values : &mut dyn Iterator<Item = Self::Value>,
+        ctx : &mut ReduceContext<Self::Output>) {
+        let values = values.collect();
+        let records = self.reduce_with_outputs(key, values, ctx.outputs());
+        for record in records {
+            ctx.output(record);
+        }
+    }
+
+    /// combiner: 在 mapper 端先把同一个 key 的 values 合并, 减少中间数据. 默认原样返回.
+    /// 只有 export_job!(..., combiner) 时才会被框架调用.
+    fn combine(&self, _key : &Self::Key, values : Vec<Self::Value>) -> Vec<Self::Value> {
+        values
+    }
+
+    /// partitioner: 决定 key 交给第几个 reducer(0..reducer_num). 返回 None 时使用框架默认的哈希分区.
+    /// 只有 export_job!(..., partitioner) 时才会被框架调用.
+    fn partition(&self, _key : &Self::Key, _reducer_num : u32) -> Option<u32> {
+        None
+    }
+}
+
+thread_local! {
+    // 当前线程是否正在执行 Job::map / Job::reduce 的默认实现.
+    static IN_DEFAULT_MAP : Cell<bool> = const { Cell::new(false) };
+    static IN_DEFAULT_REDUCE : Cell<bool> = const { Cell::new(false) };
+}
+
+/// 检查默认实现之间的循环: 默认的 map 还没返回就又进入了默认的 map, 说明三个函数一个都没有实现.
+struct DefaultCycleGuard(&'static std::thread::LocalKey<Cell<bool>>);
+
+impl DefaultCycleGuard {
+    fn enter(flag : &'static std::thread::LocalKey<Cell<bool>>, names : &str) -> DefaultCycleGuard {
+        if flag.with(|flag| flag.replace(true)) {
+            panic!("the job implements none of {}", names);
+        }
+        DefaultCycleGuard(flag)
+    }
+}
+
+impl Drop for DefaultCycleGuard {
+    fn drop(&mut self) {
+        self.0.with(|flag| flag.set(false));
+    }
+}
+
+/// 接收命名输出的记录: 参数是输出名与记录的 json 文本, 返回 false 表示不再接收.
+type NamedRecordSink<'a> = dyn FnMut(&str, &str) -> bool + 'a;
+
+/// 命名输出的收集器. 每个命名输出在每个 mapper/reducer 中各有一个结果文件,
+/// client 把它们取回到结果文件夹下与输出同名的子文件夹中. 输出名只能由字母、数字、_ 和 - 组成. \
+/// 框架给出了接收记录的 sink 时(emit 风格的 mapper 与流式的 reducer), 每条记录立刻交给框架写进结果文件,
+/// 否则收集起来在调用结束之后一起交出.
+#[derive(Default)]
+pub struct Outputs<'a> {
+    named : BTreeMap<String, Vec<Value>>,
+    sink : Option<&'a mut NamedRecordSink<'a>>,
+    stopped : bool,
+    error : Option<String>,   // 第一次序列化失败的错误, 调用结束之后作为用户代码的错误返回.
+}
+
+impl<'a> Outputs<'a> {
+    pub fn new() -> Outputs<'a> {
+        Outputs::default()
+    }
+
+    /// 记录写进来之后立刻交给 sink, 不再收集.
+    pub fn with_sink(sink : &'a mut NamedRecordSink<'a>) -> Outputs<'a> {
+        Outputs { sink : Some(sink), ..Outputs::default() }
+    }
+
+    /// 向名为 name 的命名输出写一条记录.
+    pub fn write<T : Serialize>(&mut self, name : &str, record : T) {
+        let written = match &mut self.sink {
+            Some(sink) => serde_json::to_string(&record).map(|record| {
+                if !self.stopped && !sink(name, &record) {
+                    self.stopped = true;
+                }
+            }),
+            None => serde_json::to_value(record).map(|record| self.named.entry(name.to_string()).or_default().push(record)),
+        };
+        if let Err(e) = written {
+            self.error.get_or_insert_with(|| format!("cannot serialize a record of output {}: {}", name, e));
+        }
+    }
+
+    /// 取出所有收集起来的记录.
+    pub fn into_named(self) -> Result<BTreeMap<String, Vec<Value>>, String> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.named),
+        }
+    }
+}
+
+/// MapContext 交给框架一侧的事件.
+pub enum MapEvent<K, V> {
+    Emit(K, V),
+    Progress,
+}
+
+/// emit 风格的 mapper 的上下文. emit 的键值对立刻交给框架, 计数器在 map_emit 返回之后一起交出,
+/// 命名输出见 Outputs. \
+/// 框架不再接收输出时(比如写中间文件出错), 之后的 emit 都被忽略, 用户代码可以用 progress 的返回值提前结束.
+pub struct MapContext<'a, K, V> {
+    sink : &'a mut dyn FnMut(MapEvent<K, V>) -> bool,
+    outputs : Outputs<'a>,
+    counters : BTreeMap<String, i64>,
+    stopped : bool,
+    _types : PhantomData<fn(K, V)>,
+}
+
+impl<'a, K, V> MapContext<'a, K, V> {
+    /// sink 收到每一个事件, 返回 false 表示不再接收.
+    pub fn new(sink : &'a mut dyn FnMut(MapEvent<K, V>) -> bool) -> MapContext<'a, K, V> {
+        MapContext::with_outputs(sink, Outputs::new())
+    }
+
+    /// 同 new, 命名输出写进 outputs.
+    pub fn with_outputs(sink : &'a mut dyn FnMut(MapEvent<K, V>) -> bool, outputs : Outputs<'a>) -> MapContext<'a, K, V> {
+        MapContext {
+            sink,
+            outputs,
+            counters : BTreeMap::new(),
+            stopped : false,
+            _types : PhantomData,
+        }
+    }
+
+    /// 输出一个键值对.
+    pub fn emit(&mut self, key : K, value : V) {
+        if !self.stopped && !(self.sink)(MapEvent::Emit(key, value)) {
+            self.stopped = true;
+        }
+    }
+
+    /// 给名为 name 的计数器加上 amount.
+    pub fn counter(&mut self, name : &str, amount : i64) {
+        *self.counters.entry(name.to_string()).or_default() += amount;
+    }
+
+    /// 报告 mapper 仍在工作. 返回 false 时框架已经不再接收输出(比如之前的输出写入失败), mapper 应该尽快返回. \
+    /// 框架目前只用它告诉用户代码要不要停下来, 它不会延长墙钟时间等限制, 也不会被记录.
+    pub fn progress(&mut self) -> bool {
+        if !self.stopped && !(self.sink)(MapEvent::Progress) {
+            self.stopped = true;
+        }
+        !self.stopped
+    }
+
+    /// 命名输出的收集器.
+    pub fn outputs(&mut self) -> &mut Outputs<'a> {
+        &mut self.outputs
+    }
+
+    /// 计数器的当前值.
+    pub fn counters(&self) -> &BTreeMap<String, i64> {
+        &self.counters
+    }
+
+    /// 结束之后交给框架的内容: 命名输出和计数器, 键值对已经 emit 过了.
+    fn finish(self) -> Result<MapOutput, String> {
+        Ok(MapOutput {
+            pairs : Vec::new(),
+            named : self.outputs.into_named()?,
+            counters : self.counters,
+        })
+    }
+}
+
+/// ReduceContext 交给框架一侧的事件.
+pub enum ReduceEvent<O> {
+    Output(O),
+    Progress,
+}
+
+/// 流式的 reducer 的上下文. 输出记录立刻交给框架, 计数器在 reduce_stream 返回之后一起交出, 命名输出见 Outputs.
+/// 与 MapContext 一样, 框架不再接收输出之后 output 都被忽略, progress 返回 false.
+pub struct ReduceContext<'a, O> {
+    sink : &'a mut dyn FnMut(ReduceEvent<O>) -> bool,
+    outputs : Outputs<'a>,
+    counters : BTreeMap<String, i64>,
+    stopped : bool,
+}
+
+impl<'a, O> ReduceContext<'a, O> {
+    /// sink 收到每一个事件, 返回 false 表示不再接收.
+    pub fn new(sink : &'a mut dyn FnMut(ReduceEvent<O>) -> bool) -> ReduceContext<'a, O> {
+        ReduceContext::with_outputs(sink, Outputs::new())
+    }
+
+    /// 同 new, 命名输出写进 outputs.
+    pub fn with_outputs(sink : &'a mut dyn FnMut(ReduceEvent<O>) -> bool, outputs : Outputs<'a>) -> ReduceContext<'a, O> {
+        ReduceContext {
+            sink,
+            outputs,
+            counters : BTreeMap::new(),
+            stopped : false,
+        }
+    }
+
+    /// 输出一条记录.
+    pub fn output(&mut self, record : O) {
+        if !self.stopped && !(self.sink)(ReduceEvent::Output(record)) {
+            self.stopped = true;
+        }
+    }
+
+    /// 给名为 name 的计数器加上 amount.
+    pub fn counter(&mut self, name : &str, amount : i64) {
+        *self.counters.entry(name.to_string()).or_default() += amount;
+    }
+
+    /// 报告 reducer 仍在工作. 返回 false 时框架已经不再接收输出, reducer 应该尽快返回. 与 MapContext::progress 相同.
+    pub fn progress(&mut self) -> bool {
+        if !self.stopped && !(self.sink)(ReduceEvent::Progress) {
+            self.stopped = true;
+        }
+        !self.stopped
+    }
+
+    /// 命名输出的收集器.
+    pub fn outputs(&mut self) -> &mut Outputs<'a> {
+        &mut self.outputs
+    }
+
+    /// 计数器的当前值.
+    pub fn counters(&self) -> &BTreeMap<String, i64> {
+        &self.counters
+    }
+
+    /// 结束之后交给框架的内容: 命名输出和计数器, 输出记录已经交出过了.
+    fn finish(self) -> Result<ReduceOutput, String> {
+        Ok(ReduceOutput {
+            outputs : Vec::new(),
+            named : self.outputs.into_named()?,
+            counters : self.counters,
+        })
+    }
+}
+
+/// 任务的上下文, 框架加载插件之后通过 mapreduce_set_context 交给插件
+/// (wasm 插件的每个实例各有一份, 都会收到).
+/// config 是 client 提交任务时给出的配置参数.
+/// cache_files 是 client 用 add_cache_file 附加的文件: 文件名 -> worker 本地的只读路径;
+/// wasm 插件碰不到文件系统, 它们只能使用配置参数.
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct TaskContext {
+    pub task_id : u32,
+    #[serde(default)]
+    pub config : BTreeMap<String, String>,
+    #[serde(default)]
+    pub cache_dir : Option<String>,
+    #[serde(default)]
+    pub cache_files : BTreeMap<String, String>,
+}
+
+// 同一个库可能先后被加载给不同的任务(比如 client 在同一个进程里提交两次、用不同的配置检查插件),
+// 所以上下文可以被替换, 每次加载都给出新的上下文.
+static TASK_CONTEXT : RwLock<Option<Arc<TaskContext>>> = RwLock::new(None);
+
+impl TaskContext {
+    /// 插件中当前任务的上下文. 框架还没有给出时(比如 client 提交之前的检查)是空的上下文.
+    pub fn current() -> Arc<TaskContext> {
+        TASK_CONTEXT.read().unwrap().clone().unwrap_or_default()
+    }
+
+    /// 配置参数 key 的值.
+    pub fn config(&self, key : &str) -> Option<&str> {
+        self.config.get(key).map(String::as_str)
+    }
+
+    /// 配置参数 key 解析成 T 之后的值; 没有这个参数时是 None, 解析失败时是 Some(Err).
+    pub fn config_parsed<T : std::str::FromStr>(&self, key : &str) -> Option<Result<T, T::Err>> {
+        self.config(key).map(str::parse)
+    }
+
+    /// 名为 name 的附属文件的本地路径, 不要修改这个文件.
+    pub fn cache_file(&self, name : &str) -> Option<&Path> {
+        self.cache_files.get(name).map(Path::new)
+    }
+}
+
+/// 检查命名输出的名字, 它会被用作文件夹名.
+pub fn check_output_name(name : &str) -> Result<(), String> {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        Ok(())
+    } else {
+        Err(format!("invalid output name {:?}, only letters, digits, _ and - are allowed", name))
+    }
+}
+
+/// mapper 在插件边界上返回的内容. 键值都已经是 json 值; named 是写到命名输出中的记录,
+/// counters 是用户计数器的值. emit 风格的 mapper 返回的 pairs 是空的.
+#[derive(Deserialize, Serialize, Default)]
+pub struct MapOutput {
+    pub pairs : Vec<(Value, Value)>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub named : BTreeMap<String, Vec<Value>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub counters : BTreeMap<String, i64>,
+}
+
+/// reducer 对一个 key 的输出. 在插件边界上, 没有命名输出和计数器时只是 outputs 的 json 数组,
+/// 否则是这个结构的 json 对象, 这样旧的插件仍然可以使用. 流式的 reducer 返回的 outputs 是空的.
+#[derive(Deserialize, Serialize, Default, Debug)]
+pub struct ReduceOutput {
+    pub outputs : Vec<Value>,
+    #[serde(default)]
+    pub named : BTreeMap<String, Vec<Value>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub counters : BTreeMap<String, i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReduceOutputRepr {
+    Outputs(Vec<Value>),
+    Full(ReduceOutput),
+}
+
+impl ReduceOutput {
+    /// 解析插件中 reducer 返回的 json 值.
+    pub fn from_value(value : Value) -> Result<ReduceOutput, serde_json::Error> {
+        Ok(match serde_json::from_value(value)? {
+            ReduceOutputRepr::Outputs(outputs) => ReduceOutput { outputs, ..ReduceOutput::default() },
+            ReduceOutputRepr::Full(output) => output,
+        })
+    }
+
+    /// 序列化成插件边界上的 json 文本.
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        if self.named.is_empty() && self.counters.is_empty() {
+            serde_json::to_string(&self.outputs)
+        } else {
+            serde_json::to_string(self)
+        }
+    }
+}
+
+/// 插件中 mapper 入口的通用实现: 调用用户的 map_emit, 把全部结果序列化为 MapOutput 的 json 文本.
+pub fn job_map_entry<J : Job>(content : &str) -> Result<String, String> {
+    let job = J::default();
+    let mut pairs = Vec::new();
+    let mut error = None;
+    let mut sink = |event| {
+        if let MapEvent::Emit(k, v) = event {
+            match serde_json::to_value(k).and_then(|k| serde_json::to_value(v).map(|v| (k, v))) {
+                Ok(pair) => pairs.push(pair),
+                Err(e) => {
+                    error = Some(e.to_string());
+                    return false;
+                }
+            }
+        }
+        true
+    };
+    let mut ctx = MapContext::new(&mut sink);
+    job.map_emit(content, &mut ctx);
+    let mut output = ctx.finish()?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+    output.pairs = pairs;
+    serde_json::to_string(&output).map_err(|e| e.to_string())
+}
+
+/// 插件中 emit 风格的 mapper 入口的通用实现: 每个键值对与命名输出的记录序列化成 json 文本之后立刻交给 host,
+/// host 的参数是 (HOST_EMIT, key, value), (HOST_NAMED, 输出名, 记录) 或 (HOST_PROGRESS, "", ""), 返回 false 表示框架不再接收.
+/// 返回只有计数器的 MapOutput 的 json 文本.
+pub fn job_map_emit_entry<J : Job>(content : &str, host : &mut dyn FnMut(u32, &str, &str) -> bool) -> Result<String, String> {
+    let job = J::default();
+    let mut error = None;
+    // 键值对与命名输出的记录都交给 host, 两边不会同时调用它.
+    let host = RefCell::new(host);
+    let mut sink = |event| match event {
+        MapEvent::Emit(k, v) => {
+            match serde_json::to_string(&k).and_then(|k| serde_json::to_string(&v).map(|v| (k, v))) {
+                Ok((k, v)) => (*host.borrow_mut())(HOST_EMIT, &k, &v),
+                Err(e) => {
+                    error = Some(e.to_string());
+                    false
+                }
+            }
+        }
+        MapEvent::Progress => (*host.borrow_mut())(HOST_PROGRESS, "", ""),
+    };
+    let mut named = |name : &str, record : &str| (*host.borrow_mut())(HOST_NAMED, name, record);
+    let mut ctx = MapContext::with_outputs(&mut sink, Outputs::with_sink(&mut named));
+    job.map_emit(content, &mut ctx);
+    let output = ctx.finish()?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+    serde_json::to_string(&output).map_err(|e| e.to_string())
+}
+
+/// 插件中 reducer 入口的通用实现: key 是 json 文本, values 是 json 数组文本, 返回 ReduceOutput 的 json 文本.
+pub fn job_reduce_entry<J : Job>(key : &str, values : &str) -> Result<String, String> {
+    let job = J::default();
+    let key : J::Key = serde_json::from_str(key).map_err(|e| e.to_string())?;
+    let values : Vec<J::Value> = serde_json::from_str(values).map_err(|e| e.to_string())?;
+    let mut records = Vec::new();
+    let mut error = None;
+    let mut sink = |event| {
+        if let ReduceEvent::Output(record) = event {
+            match serde_json::to_value(record) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    error = Some(e.to_string());
+                    return false;
+                }
+            }
+        }
+        true
+    };
+    let mut ctx = ReduceContext::new(&mut sink);
+    job.reduce_stream(key, &mut values.into_iter(), &mut ctx);
+    let mut output = ctx.finish()?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+    output.outputs = records;
+    output.to_json().map_err(|e| e.to_string())
+}
+
+/// 插件中流式的 reducer 入口的通用实现: key 是 json 文本, next_value 从框架取下一个 value 的 json 文本,
+/// 没有更多的 value 时返回 None. 每条输出记录序列化之后立刻通过 host(HOST_OUTPUT, record, "") 交给框架,
+/// 命名输出的记录同样立刻通过 host(HOST_NAMED, 输出名, 记录) 交出. 返回只有计数器的 ReduceOutput 的 json 文本.
+pub fn job_reduce_stream_entry<J : Job>(key : &str,
+    host : &mut dyn FnMut(u32, &str, &str) -> bool,
+    next_value : &mut dyn FnMut() -> Result<Option<String>, String>) -> Result<String, String> {
+    let job = J::default();
+    let key : J::Key = serde_json::from_str(key).map_err(|e| e.to_string())?;
+    let mut error = None;
+    let mut value_error = None;
+    let mut values = std::iter::from_fn(|| {
+        let value = next_value().and_then(|value| match value {
+            Some(value) => serde_json::from_str(&value).map(Some).map_err(|e| e.to_string()),
+            None => Ok(None),
+        });
+        value.unwrap_or_else(|e| {
+            value_error.get_or_insert(e);
+            None
+        })
+    });
+    let host = RefCell::new(host);
+    let mut sink = |event| match event {
+        ReduceEvent::Output(record) => match serde_json::to_string(&record) {
+            Ok(record) => (*host.borrow_mut())(HOST_OUTPUT, &record, ""),
+            Err(e) => {
+                error = Some(e.to_string());
+                false
+            }
+        },
+        ReduceEvent::Progress => (*host.borrow_mut())(HOST_PROGRESS, "", ""),
+    };
+    let mut named = |name : &str, record : &str| (*host.borrow_mut())(HOST_NAMED, name, record);
+    let mut ctx = ReduceContext::with_outputs(&mut sink, Outputs::with_sink(&mut named));
+    job.reduce_stream(key, &mut values, &mut ctx);
+    let output = ctx.finish()?;
+    if let Some(e) = value_error.or(error) {
+        return Err(e);
+    }
+    output.to_json().map_err(|e| e.to_string())
+}
+
+/// 插件中 combiner 入口的通用实现: 输入输出都与 reducer 一样是 json 文本, 输出的是合并后的 values.
+pub fn job_combine_entry<J : Job>(key : &str, values : &str) -> Result<String, String> {
+    let job = J::default();
+    let key : J::Key = serde_json::from_str(key).map_err(|e| e.to_string())?;
+    let values : Vec<J::Value> = serde_json::from_str(values).map_err(|e| e.to_string())?;
+    serde_json::to_string(&job.combine(&key, values)).map_err(|e| e.to_string())
+}
+
+/// 插件中 partitioner 入口的通用实现: key 是 json 文本.
+pub fn job_partition_entry<J : Job>(key : &str, reducer_num : u32) -> Result<Option<u32>, String> {
+    let job = J::default();
+    let key : J::Key = serde_json::from_str(key).map_err(|e| e.to_string())?;
+    Ok(job.partition(&key, reducer_num))
+}
+
+/// 插件中设置任务上下文的通用实现: context 是 TaskContext 的 json 文本.
+pub fn job_set_context_entry(context : &str) -> Result<String, String> {
+    let context : TaskContext = serde_json::from_str(context).map_err(|e| e.to_string())?;
+    *TASK_CONTEXT.write().unwrap() = Some(Arc::new(context));
+    Ok(String::new())
+}
@@ -1,15 +1,30 @@
 #![allow(unused)]
+#![allow(non_snake_case)]
 
+// 编译成 wasm32 时(用户插件)只需要 job 与 plugin::abi, 框架一侧的模块都不参与编译.
+#[cfg(not(target_arch = "wasm32"))]
 pub mod map_reduce_server;
 pub mod map_reduce;
+#[cfg(not(target_arch = "wasm32"))]
 mod thread_poll;
+#[cfg(not(target_arch = "wasm32"))]
 mod io_wrapper;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod map_reduce_client;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod local_cluster;
 pub mod error;
+pub mod job;
+pub mod plugin;
 
+#[cfg(not(target_arch = "wasm32"))]
 use map_reduce_server::MapReduceServer;
+/// worker子进程使用的全局分配器, 见 mapreduce_worker.
+#[cfg(not(target_arch = "wasm32"))]
+pub use map_reduce_server::process_worker::WorkerAllocator;
 
 /// 运行一个mapreduce server host 为地址号.
+#[cfg(not(target_arch = "wasm32"))]
 pub fn run_server(host : &str, master_num : usize, worker_num : usize) {
     println!("Establish and run a server for mapreduce at {}", host);
     let mut server = MapReduceServer::new(
@@ -19,3 +34,31 @@ pub fn run_server(host : &str, master_num : usize, worker_num : usize) {
     );
     server.run();
 }
+
+/// 运行一个模拟模式的mapreduce server: worker 线程分成 node_num 个虚拟节点, 每个节点 worker_num 个线程,
+/// 中间文件留在节点的本地目录中. 节点可以用 map_reduce_client::kill_simulated_node 杀掉.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run_simulated_server(host : &str, master_num : usize, node_num : usize, worker_num : usize) {
+    println!("Establish and run a simulated server for mapreduce at {}", host);
+    let mut server = MapReduceServer::new_simulated(
+        host,
+        master_num,
+        node_num,
+        worker_num,
+    );
+    server.run();
+}
+
+/// 运行一个 worker daemon: 向 server_host 的server注册, 然后执行server分配来的子任务,
+/// 最多同时执行 capacity 个. 见 mapreduce_daemon.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run_worker_daemon(server_host : &str, capacity : usize) -> Result<(), Box<dyn std::error::Error>> {
+    map_reduce_server::remote_worker::worker_daemon_main(server_host, capacity)
+}
+
+/// 运行一个worker子进程: 从stdin读入一个子任务, 执行之后把结果写到stdout.
+/// 由server以 Process 方式执行子任务时启动, 见 mapreduce_worker.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run_worker_process() -> Result<(), Box<dyn std::error::Error>> {
+    map_reduce_server::process_worker::worker_process_main()
+}
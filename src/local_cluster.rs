@@ -0,0 +1,164 @@
+// 开发用的本机集群: 在 localhost 上启动一个 server 进程(mapreduce_server)和若干个 worker daemon 进程(mapreduce_daemon),
+// 每个进程有自己的工作目录, 等它们都准备好之后交给调用者, 用完了一起关掉. 命令行入口见 mapreduce_cluster.
+use std::{
+    env, fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc::{channel, Sender},
+    thread,
+    time::Duration,
+};
+
+use crate::map_reduce_server::READY_MARKER;
+
+/// 等待一个进程准备好的最长时间.
+const READY_TIMEOUT : Duration = Duration::from_secs(30);
+
+/// 本机集群中的一个 worker daemon.
+pub struct LocalWorker {
+    pub worker_id : u32,        // server 分给它的编号.
+    pub shuffle_host : String,  // 它的 shuffle 服务的地址.
+    pub dir : PathBuf,          // 它的工作目录.
+}
+
+/// 在本机上运行的一个 server 和若干个 worker daemon. drop 时关掉所有进程.
+pub struct LocalCluster {
+    server_host : String,
+    server_dir : PathBuf,
+    workers : Vec<LocalWorker>,
+    processes : Vec<Child>,   // server 在最前面.
+}
+
+/// 可执行文件 name 的位置: 优先使用环境变量 env_var, 否则在当前可执行文件所在的目录中找;
+/// 集成测试的可执行文件在 target/debug/deps 中, 所以再到上一层目录找.
+fn sibling_binary(env_var : &str, name : &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Ok(path) = env::var(env_var) {
+        return Ok(PathBuf::from(path));
+    }
+    let exe = env::current_exe()?;
+    let mut dir = exe.parent();
+    while let Some(current) = dir {
+        let path = current.join(name);
+        if path.exists() {
+            return Ok(path);
+        }
+        dir = current.parent().filter(|_| current.ends_with("deps"));
+    }
+    Err(format!("cannot find {}, build it first or set {}", name, env_var).into())
+}
+
+/// 把子进程的 stdout 一行一行地加上 [name] 前缀转发到自己的 stdout;
+/// 第一次遇到 READY_MARKER 开头的行时把它后面的各项通过 ready 发出去.
+fn forward_output(child : &mut Child, name : String, ready : Sender<Vec<String>>) {
+    let stdout = child.stdout.take().expect("stdout of the child process is piped");
+    thread::spawn(move || {
+        let mut ready = Some(ready);
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            println!("[{}] {}", name, line);
+            let mut fields = line.split_whitespace();
+            if fields.next() == Some(READY_MARKER) {
+                if let Some(ready) = ready.take() {
+                    let _ = ready.send(fields.map(String::from).collect());
+                }
+            }
+        }
+    });
+}
+
+impl LocalCluster {
+    /// 在 base_dir 下启动本机集群: server 的工作目录是 base_dir/server, 第 i 个 worker daemon 的是 base_dir/worker{i}.
+    /// server 自己的 worker 线程池只有一个线程, 大部分子任务由 worker_num 个 worker daemon 执行, 每个最多同时执行 capacity 个.
+    /// hdfs 是 hdfs 客户端的 host 与用户名, 给出时 server 与 worker daemon 都会初始化 hdfs 客户端, client 提交的任务才能执行
+    /// (mapper 直接读hdfs上的输入文件).
+    /// 所有进程都准备好(server 开始监听, worker daemon 都注册完)才返回.
+    pub fn start(base_dir : &str, worker_num : usize, capacity : usize, hdfs : Option<(&str, &str)>)
+        -> Result<LocalCluster, Box<dyn std::error::Error>> {
+        let server_bin = sibling_binary("MAPREDUCE_SERVER_BIN", "mapreduce_server")?;
+        let daemon_bin = sibling_binary("MAPREDUCE_DAEMON_BIN", "mapreduce_daemon")?;
+        let base_dir = Path::new(base_dir);
+        let server_dir = base_dir.join("server");
+        fs::create_dir_all(&server_dir)?;
+        let server_dir = fs::canonicalize(server_dir)?;
+
+        // 端口由系统分配, server 准备好时打印出实际的地址.
+        let mut command = Command::new(server_bin);
+        command.arg("127.0.0.1:0").arg("2").arg("1");
+        if let Some((client_host, user)) = hdfs {
+            command.arg(client_host).arg(user);
+        }
+        let mut server = command.current_dir(&server_dir).stdout(Stdio::piped()).spawn()?;
+        let (ready, server_ready) = channel();
+        forward_output(&mut server, String::from("server"), ready);
+        // 从这里开始出错的话, drop 会关掉已经启动的进程.
+        let mut cluster = LocalCluster { server_host : String::new(), server_dir, workers : Vec::new(), processes : vec![server] };
+        let fields = server_ready.recv_timeout(READY_TIMEOUT).map_err(|_| "the server is not ready in time")?;
+        cluster.server_host = match fields.as_slice() {
+            [server_host] => server_host.clone(),
+            _ => return Err(format!("unexpected ready message from the server : {:?}", fields).into()),
+        };
+
+        // 所有 worker daemon 同时启动, 再一个一个地等它们注册完.
+        let mut starting = Vec::with_capacity(worker_num);
+        for i in 0..worker_num {
+            let dir = base_dir.join(format!("worker{}", i));
+            fs::create_dir_all(&dir)?;
+            let dir = fs::canonicalize(dir)?;
+            let mut command = Command::new(&daemon_bin);
+            command.arg(&cluster.server_host).arg(capacity.to_string());
+            if let Some((client_host, user)) = hdfs {
+                command.arg(client_host).arg(user);
+            }
+            let mut daemon = command.current_dir(&dir).stdout(Stdio::piped()).spawn()?;
+            let (ready, registered) = channel();
+            forward_output(&mut daemon, format!("worker{}", i), ready);
+            cluster.processes.push(daemon);
+            starting.push((dir, registered));
+        }
+        for (i, (dir, registered)) in starting.into_iter().enumerate() {
+            let fields = registered.recv_timeout(READY_TIMEOUT)
+                .map_err(|_| format!("worker daemon {} is not registered in time", i))?;
+            let (worker_id, shuffle_host) = match fields.as_slice() {
+                [worker_id, shuffle_host] => (worker_id.parse()?, shuffle_host.clone()),
+                _ => return Err(format!("unexpected ready message from worker daemon {} : {:?}", i, fields).into()),
+            };
+            cluster.workers.push(LocalWorker { worker_id, shuffle_host, dir });
+        }
+        cluster.workers.sort_by_key(|worker| worker.worker_id);
+        Ok(cluster)
+    }
+
+    /// server 的地址, client 连接它提交任务.
+    pub fn server_host(&self) -> &str {
+        &self.server_host
+    }
+
+    /// server 的工作目录, 任务的中间文件与结果文件在它下面.
+    pub fn server_dir(&self) -> &Path {
+        &self.server_dir
+    }
+
+    /// 所有 worker daemon, 按编号排列.
+    pub fn workers(&self) -> &[LocalWorker] {
+        &self.workers
+    }
+
+    /// 关掉所有进程: 先关 worker daemon, 最后关 server.
+    pub fn shutdown(mut self) {
+        self.kill_all();
+    }
+
+    fn kill_all(&mut self) {
+        while let Some(mut process) = self.processes.pop() {
+            // 已经退出了的进程 kill 会失败, 不用管.
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+    }
+}
+
+impl Drop for LocalCluster {
+    fn drop(&mut self) {
+        self.kill_all();
+    }
+}
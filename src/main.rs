@@ -7,6 +7,8 @@ mod thread_poll;
 mod io_wrapper;
 mod map_reduce_client;
 mod error;
+mod job;
+mod plugin;
 
 use std::env;
 
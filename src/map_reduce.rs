@@ -1,20 +1,32 @@
+use std::collections::BTreeMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::Read;
 use serde::{Deserialize, Serialize};
 
+#[cfg(not(target_arch = "wasm32"))]
 use crate::io_wrapper::HdfsSetting;
 use crate::error::MapReduceError;
 
 /// 初始化HDFS客户端的全局设置；这个函数只应该调用一次.
+#[cfg(not(target_arch = "wasm32"))]
 pub fn SETUP_GLOBAL_HDFS_CLIENT(client_host : &str, user : &str) -> Result<(),MapReduceError> {
     let setting = HdfsSetting::new(client_host, user)?;
     HdfsSetting::init_global(setting)?;
     Ok(())
 }
 
+/// client 上传的附属文件在hdfs上的后缀, server 据此把它们和输入文件区分开.
+pub const CACHE_FILE_SUFFIX : &str = ".cache";
+/// server 把附属文件放在任务目录下的这个子目录中, 每个任务只放一次.
+pub const CACHE_DIR : &str = "cache/";
+/// client 把输入文件原样上传到hdfs上任务目录下的这个子目录中(已经在hdfs上的输入文件不用上传).
+pub const INPUT_DIR : &str = "input/";
+
 /// 定义通信类型(message_type)：\
 /// message_type:   意义\
 /// Client:  \
 /// 1:              client申请任务号, 开启一个任务 \
-/// 2:              client将输入文件与dll准备到指定位置，可以开始任务 \
+/// 2:              client将输入文件与dll准备到指定位置，可以开始任务. data_file 是输入文件(上传到了 INPUT_DIR 中, 或者本来就在hdfs上) \
 /// 3:              client将结果文件复制到本地，任务完毕 \
 /// Server:  \
 /// 4:              向client发送任务号，存放输入文件与dll的位置  \
@@ -24,7 +36,16 @@ pub fn SETUP_GLOBAL_HDFS_CLIENT(client_host : &str, user : &str) -> Result<(),Ma
 /// 7:              向server发送任务处理完毕通知，包括任务id以及结果文件位置 \
 /// 8:              向server发送任务失败的通知，这个不是机器的问题，所以server也向client发送失败信息.
 ///                 暂时把data_file设置为空字符串表示失败....
-#[derive(Deserialize, Serialize, Debug)]
+/// Worker daemon:  \
+/// 9:              worker daemon 向server注册, capacity 是它能同时执行的子任务数, data_file 是它的 shuffle 服务的地址 \
+/// 10:             server 回复注册成功, from 是分给这个worker的编号. 之后这个连接用来分配子任务, 见 remote_worker. \
+/// 模拟模式:  \
+/// 11:             杀掉编号为 from 的模拟节点 \
+/// 12:             server 回复 11, 失败时 dll_file 是错误信息. \
+/// Client(重新连接):  \
+/// 13:             client 重新连上来等任务 task_id 的结果(比如server重启之后), server 同样用 5 回复.
+/// 没有给出的字段在 Default 中是 0 和空字符串(注意反序列化时缺少的字段另有默认值).
+#[derive(Deserialize, Serialize, Debug, Default)]
 pub struct MessagePacket{
     pub message_type:u8,
     #[serde(default="default_packet_int")]
@@ -39,6 +60,135 @@ pub struct MessagePacket{
     pub mapper_num:u32,
     #[serde(default="default_packet_int")]
     pub reducer_num:u32,
+    #[serde(default)]
+    pub options:JobOptions,   // 任务的选项, client申请任务(type 1)时给出.
+    #[serde(default)]
+    pub named_outputs:BTreeMap<String, String>,   // 命名输出: 输出名 -> |分隔的结果文件, 随 type 7 与 type 5 一起发送.
+    #[serde(default)]
+    pub config:JobConfig,   // 任务的配置参数, client申请任务(type 1)时给出.
+    #[serde(default)]
+    pub counters:Counters,   // 整个任务的计数器, 随 type 7 与 type 5 一起发送.
+    #[serde(default)]
+    pub capacity:u32,   // worker daemon 注册(type 9)时给出.
+    #[serde(default)]
+    pub splits:Vec<InputSplit>,   // 输入切成的各段, 每段一个mapper, 随 type 7 与 type 5 一起发送.
+}
+
+/// 从 stream 中读一个 MessagePacket. 只读到这个 json 对象结束为止, 不需要对方关闭连接,
+/// 也不受固定长度的缓冲区限制(带着计数器、命名输出的消息可能很长).
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_packet<R : Read>(stream : R) -> Result<MessagePacket, Box<dyn std::error::Error>> {
+    let mut packets = serde_json::Deserializer::from_reader(stream).into_iter::<MessagePacket>();
+    match packets.next() {
+        Some(packet) => Ok(packet?),
+        None => Err(Box::new(MapReduceError::WrongMessageType)),
+    }
+}
+
+/// mapper/reducer 子任务的执行方式.
+/// Thread: 在server进程中的worker线程里直接加载插件执行;
+/// Process: worker线程启动一个单独的worker子进程(mapreduce_worker)加载插件执行, 子进程崩溃只会让这一次子任务失败.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionMode {
+    #[default]
+    Thread,
+    Process,
+}
+
+/// 每次子任务尝试的资源限制, None 表示不限制.
+/// 前三项只有以 Process 方式执行子任务时才会生效: cpu时间和地址空间通过子进程的 rlimit 限制,
+/// 墙钟时间由worker线程计时, 超时就杀掉子进程. 注意地址空间包括worker进程自己加载的动态库. \
+/// wasm_ 开头的两项只对 wasm 插件有效, 由 wasm 运行时执行, 两种执行方式下都生效:
+/// wasm_fuel 是每次调用插件可以消耗的燃料(大致是执行的指令数), wasm_memory_bytes 是插件线性内存的上限.
+/// wasm_fuel 同时也是 rhai 脚本每次调用可以执行的操作数.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SubtaskLimits {
+    #[serde(default)]
+    pub wall_clock_secs : Option<u64>,
+    #[serde(default)]
+    pub cpu_time_secs : Option<u64>,
+    #[serde(default)]
+    pub memory_bytes : Option<u64>,
+    #[serde(default)]
+    pub wasm_fuel : Option<u64>,
+    #[serde(default)]
+    pub wasm_memory_bytes : Option<u64>,
+}
+
+impl SubtaskLimits {
+    /// 是否设置了只在 Process 方式下生效的限制.
+    pub fn has_process_limits(&self) -> bool {
+        self.wall_clock_secs.is_some() || self.cpu_time_secs.is_some() || self.memory_bytes.is_some()
+    }
+}
+
+/// 任务的配置参数: 同一个插件可以用不同的参数(阈值、正则表达式等)执行, 用户代码通过 job::TaskContext 读取.
+pub type JobConfig = BTreeMap<String, String>;
+
+/// 计数器: 计数器名 -> 值. 用户代码通过 MapContext/ReduceContext 的 counter 增加自己起名的计数器,
+/// 框架也维护一些内置的计数器, 它们的名字以 "mapreduce." 开头, 用户计数器不要使用这个前缀. \
+/// 每个成功的子任务把它的计数器报告给master, master 把所有子任务的加起来随结果一起交给client.
+pub type Counters = BTreeMap<String, i64>;
+
+/// mapper 读入的记录数(输入分块的行数).
+pub const MAP_INPUT_RECORDS : &str = "mapreduce.map.input.records";
+/// mapper 读入的字节数.
+pub const MAP_INPUT_BYTES : &str = "mapreduce.map.input.bytes";
+/// mapper 输出的键值对数.
+pub const MAP_OUTPUT_RECORDS : &str = "mapreduce.map.output.records";
+/// mapper 输出的键值对的字节数(key 与 value 的 json 文本).
+pub const MAP_OUTPUT_BYTES : &str = "mapreduce.map.output.bytes";
+/// reducer 处理的 key 数.
+pub const REDUCE_INPUT_GROUPS : &str = "mapreduce.reduce.input.groups";
+/// reducer 读入的键值对数.
+pub const REDUCE_INPUT_RECORDS : &str = "mapreduce.reduce.input.records";
+/// reducer 从中间文件中读入的字节数.
+pub const REDUCE_INPUT_BYTES : &str = "mapreduce.reduce.input.bytes";
+/// reducer 输出的记录数.
+pub const REDUCE_OUTPUT_RECORDS : &str = "mapreduce.reduce.output.records";
+/// reducer 写进结果文件的字节数.
+pub const REDUCE_OUTPUT_BYTES : &str = "mapreduce.reduce.output.bytes";
+
+/// 把 counters 加到 total 上.
+pub fn add_counters(total : &mut Counters, counters : &Counters) {
+    for (name, amount) in counters {
+        *total.entry(name.clone()).or_default() += amount;
+    }
+}
+
+/// 每个任务自己的选项, 随申请任务的消息一起发给server.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct JobOptions {
+    #[serde(default)]
+    pub execution_mode : ExecutionMode,
+    #[serde(default)]
+    pub limits : SubtaskLimits,
+    #[serde(default)]
+    pub split_size : SplitSize,
+}
+
+/// 输入怎样切分给 mapper(见 map_reduce_server::splits), 单位都是字节, None 表示不设置. \
+/// target 是每段的目标大小, 设置了的话 mapper 的数量由输入的大小决定; 否则每段大约是 输入的总大小 / 申请的mapper数. \
+/// min 与 max 是每段大小的上下限, 用来限制上面得到的大小. 一段不跨过文件, 边界还要对齐到行首,
+/// 所以小文件的段会比 min 小, 有很长的行时也会比 max 大. 每段至少是 输入的总大小 / 10000(见 splits::MAX_SPLITS),
+/// max 比这还小时任务失败.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SplitSize {
+    #[serde(default)]
+    pub target : Option<u64>,
+    #[serde(default)]
+    pub min : Option<u64>,
+    #[serde(default)]
+    pub max : Option<u64>,
+}
+
+/// 一个 mapper 的输入: 输入文件 path 中 [start, end) 这一段字节. 两端都在行首, mapper 就地读这一段, 不用复制.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InputSplit {
+    pub path : String,
+    pub start : u64,
+    pub end : u64,
 }
 
 
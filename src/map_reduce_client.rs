@@ -1,40 +1,86 @@
-use libloading::{Symbol, Library, Error as LibloadingError};
-use thiserror::Error;
-use std::fmt::format;
 use std::io::{Write, Read};
-use std::{
-    net::TcpStream,
-    collections::HashMap,
-};
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
 
-use crate::map_reduce::MessagePacket;
+use crate::map_reduce::{JobOptions, JobConfig, Counters, ExecutionMode, SubtaskLimits, SplitSize, InputSplit, CACHE_FILE_SUFFIX, INPUT_DIR, read_packet};
+use crate::job::TaskContext;
+use crate::plugin::{Plugin, ScriptPlugin};
+use crate::plugin::script::ScriptManifest;
+use crate::plugin::streaming::{StreamingManifest, STREAMING_MAPPER_FILE, STREAMING_REDUCER_FILE};
 use crate::io_wrapper::*;
 use crate::error::MapReduceError;
 
+/// 任务的用户代码.
+enum UserCode {
+    Plugin(String),                 // .dll(或 .wasm)的绝对路径.
+    Streaming(StreamingManifest),   // streaming 任务的 mapper/reducer 可执行文件的绝对路径.
+    Script(String),                 // rhai 脚本的源码.
+}
+
 pub struct Client{
-    origin_input_file : String,  // 原始的输入文件，将要被分块成多个(mapper_num)个.
+    origin_input_file : String,  // 原始的输入文件, 原样上传, 由master切成若干段.
     result_dir : String,    // 用户指定的要把结果文件放在这个文件夹.
-    dll_path : String,   // .dll的绝对路径.
+    code : UserCode,   // 用户代码, 会被放到server给出的dll位置.
     server_host : String,   // server的地址
     input_dir : Option<String>,   // server返回的放置输入文件的文件夹.
     result_files : Option<String>,  // 用|分隔的多个结果文件的绝对路径.
     task_id : u32,    // server分配的task_id
     m : u32,
     n : u32,
+    options : JobOptions,   // 任务选项, 随申请任务的消息发给server.
+    cache_files : Vec<String>,   // 附属文件的绝对路径, 随输入文件一起上传.
+    config : JobConfig,   // 任务的配置参数, 随申请任务的消息发给server.
+    counters : Counters,   // 任务完成之后整个任务的计数器.
+    splits : Vec<InputSplit>,   // 任务完成之后, 输入被切成的各段.
 }
 
 
 impl Client{
     /// 新建一个Client.  \
-    /// origin_input_file : 原始输入文件的路径，单个文件；它将被分块成. \
-    /// dll_path: dll的路径，一般和用户crate名字和toml里的设置有关 \
-    /// result_dir : 制定一个输出文件夹，所有n个输出文件都会被放到result_dir中, 它可以没被创建. \
-    /// m: mapper数量 \
-    /// n: reducer数量 \
+    /// origin_input_file : 原始输入文件的路径，单个文件；它原样上传(已经在hdfs上, 即以 hdfs:// 开头的话直接就地使用),
+    /// 由master按字节切成若干段(见 set_split_size), 每段的边界在行首. \
+    /// dll_path: dll的路径，一般和用户crate名字和toml里的设置有关; 也可以是编译成 wasm32 的 .wasm 模块 \
+    /// result_dir : 制定一个输出文件夹，所有n个输出文件都会被放到result_dir中, 它可以没被创建.
+    /// 用户代码通过 job::Outputs 写的命名输出放在 result_dir 下与输出同名的子文件夹中. \
+    /// m: 申请的mapper数量, 每段输入大约是 输入的大小 / m; 实际的数量是输入切成的段数(见 set_split_size 与 splits). \
+    /// n: reducer数量, 为 0 时是只有 map 的任务: 没有 shuffle 和 reduce, 每个mapper的输出直接就是一个结果文件. \
+    /// config: 任务的配置参数(键值对), 用户代码通过 job::TaskContext::current().config(key) 读取,
+    /// 这样同一个插件可以用不同的参数执行. \
     pub fn new(
         origin_input_file : &str, 
         dll_path : &str, server_host : &str, result_dir : &str, 
-        m:u32, n:u32)-> Result<Client, MapReduceError>{
+        m:u32, n:u32, config : &[(&str, &str)])-> Result<Client, MapReduceError>{
+        let code = UserCode::Plugin(iowrapper_get_absolute_path(&dll_path.to_string())?);
+        Client::with_code(origin_input_file, code, server_host, result_dir, m, n, config)
+    }
+
+    /// 新建一个 streaming 任务的Client: mapper 与 reducer 是两个可执行文件, 通过 stdin/stdout 读写 key\tvalue 行,
+    /// 详见 plugin::streaming. 其余参数与 new 相同.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_streaming(
+        origin_input_file : &str,
+        mapper : &str, reducer : &str, server_host : &str, result_dir : &str,
+        m:u32, n:u32, config : &[(&str, &str)])-> Result<Client, MapReduceError>{
+        let code = UserCode::Streaming(StreamingManifest {
+            mapper : iowrapper_get_absolute_path(&mapper.to_string())?,
+            reducer : iowrapper_get_absolute_path(&reducer.to_string())?,
+        });
+        Client::with_code(origin_input_file, code, server_host, result_dir, m, n, config)
+    }
+
+    /// 新建一个用脚本写 mapper/reducer 的Client: script 是 rhai 脚本的源码, 详见 plugin::script.
+    /// 其余参数与 new 相同.
+    pub fn new_script(
+        origin_input_file : &str,
+        script : &str, server_host : &str, result_dir : &str,
+        m:u32, n:u32, config : &[(&str, &str)])-> Result<Client, MapReduceError>{
+        Client::with_code(origin_input_file, UserCode::Script(script.to_string()), server_host, result_dir, m, n, config)
+    }
+
+    fn with_code(
+        origin_input_file : &str,
+        code : UserCode, server_host : &str, result_dir : &str,
+        m:u32, n:u32, config : &[(&str, &str)])-> Result<Client, MapReduceError>{
         let ret_dir = result_dir.to_string();
         if !iowrapper_exist(&ret_dir) {
             iowrapper_create_dir(&ret_dir)?;
@@ -42,12 +88,60 @@ impl Client{
         Ok(Client {
             origin_input_file: iowrapper_get_absolute_path(&origin_input_file.to_string())?,
             result_dir: iowrapper_get_absolute_path(&ret_dir)?, 
-            dll_path: iowrapper_get_absolute_path(&dll_path.to_string())?,
+            code,
             server_host : server_host.to_string(),
             input_dir: None, 
             result_files: None, 
             task_id: 0, 
-            m, n})
+            m, n,
+            options: JobOptions::default(),
+            cache_files: Vec::new(),
+            config: config.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            counters: Counters::new(),
+            splits: Vec::new()})
+    }
+
+    /// 设置mapper/reducer子任务的执行方式, 默认是在server的worker线程中执行(Thread).
+    pub fn set_execution_mode(&mut self, mode : ExecutionMode) {
+        self.options.execution_mode = mode;
+    }
+
+    /// 设置每个子任务的资源限制, 除了 wasm 插件的限制以外只在 ExecutionMode::Process 下生效.
+    pub fn set_limits(&mut self, limits : SubtaskLimits) {
+        self.options.limits = limits;
+    }
+
+    /// 设置输入怎样切分给 mapper: 每段的目标大小以及上下限(字节), 见 SplitSize.
+    /// 设置了目标大小的话 mapper 的数量由输入的大小决定, 不再是 new 中给出的 m.
+    pub fn set_split_size(&mut self, split_size : SplitSize) {
+        self.options.split_size = split_size;
+    }
+
+    /// 给任务附加一个附属文件(查找表、停用词表等), 它和输入文件一起上传, 每个任务只在server上放置一次.
+    /// 用户代码通过 job::TaskContext::current().cache_file(文件名) 得到它在worker上的只读路径;
+    /// streaming 任务从环境变量 MAPREDUCE_CACHE_DIR 得到所在的文件夹, 脚本用 cache_file(文件名). \
+    /// 同一个任务的附属文件不能重名.
+    pub fn add_cache_file(&mut self, path : &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = iowrapper_get_absolute_path(&path.to_string())?;
+        let name = iowrapper_get_filename(&path)?;
+        for added in &self.cache_files {
+            if iowrapper_get_filename(added)? == name {
+                return Err(format!("cache file {} has the same name as {}", path, added).into());
+            }
+        }
+        self.cache_files.push(path);
+        Ok(())
+    }
+
+    /// 任务完成之后输入被切成的各段, 每段一个 mapper. execute 之前是空的.
+    pub fn splits(&self) -> &[InputSplit] {
+        &self.splits
+    }
+
+    /// 任务完成之后整个任务的计数器: 用户代码的计数器以及框架内置的计数器(见 map_reduce::Counters),
+    /// 都是所有成功的子任务的和. execute 之前是空的.
+    pub fn counters(&self) -> &Counters {
+        &self.counters
     }
 
     /// 执行这个mapreduce任务
@@ -61,8 +155,10 @@ impl Client{
             r#"{{
                 "message_type":1,
                 "mapper_num":{},
-                "reducer_num":{}
-            }}"#, self.m, self.n
+                "reducer_num":{},
+                "options":{},
+                "config":{}
+            }}"#, self.m, self.n, serde_json::to_string(&self.options)?, serde_json::to_string(&self.config)?
         );
         println!("Connecting to MapReduce server...");
         let mut stream = TcpStream::connect(&self.server_host)?;
@@ -70,9 +166,7 @@ impl Client{
         stream.write_all(apply_for_task.as_bytes())?;
         
         
-        let mut task_info = [0;1024];
-        let length = stream.read(&mut task_info)?;
-        let mut task_info : MessagePacket = serde_json::from_slice(&task_info[0..length])?;
+        let task_info = read_packet(&mut stream)?;
         //如果返回的message_type不对，就结束.
         if task_info.message_type != 4 {
             return Err(Box::new(MapReduceError::WrongMessageType));
@@ -83,25 +177,71 @@ impl Client{
         println!("The task id is: {}", task_info.task_id);
 
         // 接下来，把文件复制到指定的地方，并且结束回复消息称自己已经完成.
-        println!("Copying dynamic linked library...");
-        iowrapper_copy_file(&self.dll_path, &task_info.dll_file)?;
-        println!("Clipping and copying input file...");
-        iowrapper_file_blocking(
-            &self.origin_input_file, 
-            &task_info.data_file, self.m
-        )?;
-        let prepared_message = String::from(
-            format!("{{\"message_type\":2,\"task_id\":{}}}",self.task_id)
-        );
+        match &self.code {
+            UserCode::Plugin(dll_path) => {
+                println!("Copying dynamic linked library...");
+                iowrapper_copy_file(dll_path, &task_info.dll_file)?;
+            }
+            UserCode::Streaming(streaming) => {
+                // 可执行文件放在任务目录里, dll的位置放一个指向它们的 manifest.
+                println!("Copying mapper and reducer executables...");
+                iowrapper_copy_file(&streaming.mapper, &path_join(&task_info.data_file, &STREAMING_MAPPER_FILE.to_string()))?;
+                iowrapper_copy_file(&streaming.reducer, &path_join(&task_info.data_file, &STREAMING_REDUCER_FILE.to_string()))?;
+                let manifest = StreamingManifest {
+                    mapper : STREAMING_MAPPER_FILE.to_string(),
+                    reducer : STREAMING_REDUCER_FILE.to_string(),
+                };
+                iowrapper_write_file_all(&task_info.dll_file, &serde_json::to_string(&manifest)?)?;
+            }
+            UserCode::Script(script) => {
+                // 脚本源码放在 manifest 中, 代替dll.
+                println!("Copying script...");
+                let manifest = ScriptManifest { script : script.clone() };
+                iowrapper_write_file_all(&task_info.dll_file, &serde_json::to_string(&manifest)?)?;
+            }
+        }
+        if !self.cache_files.is_empty() {
+            // 附属文件加上后缀放在输入文件旁边, server 据此把它们和输入文件区分开.
+            println!("Copying cache files...");
+            for path in &self.cache_files {
+                let name = iowrapper_get_filename(path)? + CACHE_FILE_SUFFIX;
+                iowrapper_copy_file(path, &path_join(&task_info.data_file, &name))?;
+            }
+        }
+        // 输入文件原样交给server, master 把它切成若干段, mapper 直接读自己的那一段. 已经在hdfs上的不用上传.
+        let input_file = if self.origin_input_file.starts_with(HdfsSetting::path_head()) {
+            self.origin_input_file.clone()
+        } else {
+            println!("Uploading input file...");
+            let input_dir = path_join(&task_info.data_file, &INPUT_DIR.to_string());
+            iowrapper_create_dir(&input_dir)?;
+            let to = path_join(&input_dir, &iowrapper_get_filename(&self.origin_input_file)?);
+            iowrapper_copy_file(&self.origin_input_file, &to)?;
+            to
+        };
+        // 准备好的消息中 data_file 是输入文件的位置.
+        let prepared_message = format!("{{\"message_type\":2,\"task_id\":{},\"data_file\":{}}}", self.task_id, serde_json::to_string(&input_file)?);
         // server中，刚Apply用的(message_type==1)tcpstream会drop掉，所以应该重新连接.
         let mut stream = TcpStream::connect(&self.server_host)?;
         stream.write_all(prepared_message.as_bytes())?;
+        self.fetch_results(stream)
+    }
+
+    /// 重新连上server等之前提交的任务 task_id 的结果, 用在 execute 等结果时断开了的情况(比如server重启了,
+    /// 它会从检查点继续没有完成的任务). 结果同样复制到 result_dir; 其余参数(输入文件, 插件, m, n)不再用到.
+    pub fn reattach(&mut self, task_id : u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.task_id = task_id;
+        println!("Reattaching to task {}...", task_id);
+        let mut stream = TcpStream::connect(&self.server_host)?;
+        stream.write_all(format!("{{\"message_type\":13,\"task_id\":{}}}", task_id).as_bytes())?;
+        self.fetch_results(stream)
+    }
 
+    /// 在 stream 上等候server发来结果通知(type 5), 把结果复制到 result_dir, 再通知server可以清理了.
+    fn fetch_results(&mut self, mut stream : TcpStream) -> Result<(), Box<dyn std::error::Error>> {
         // 等候server发来结果通知.
         println!("Waitting for results...");
-        let mut result_packet = [0;1024];
-        let length = stream.read(&mut result_packet)?;
-        let result_packet:MessagePacket = serde_json::from_slice(&result_packet[0..length])?;
+        let result_packet = read_packet(&mut stream)?;
 
         if result_packet.message_type != 5 {
             return Err(Box::new(MapReduceError::WrongMessageType));
@@ -113,6 +253,8 @@ impl Client{
         }
 
         println!("Task done. Fetching result files.");
+        self.counters = result_packet.counters;
+        self.splits = result_packet.splits;
 
         let ret_files:Vec<&str> = result_packet.data_file.split('|').collect();
         // 把结果复制到目标文件夹.
@@ -122,11 +264,21 @@ impl Client{
             let target_path = path_join(&self.result_dir, &filename);
             iowrapper_copy_file(&file_whole_path, &target_path)?;
         }
+        // 命名输出放到结果文件夹下与输出同名的子文件夹中.
+        for (name, files) in &result_packet.named_outputs {
+            let target_dir = path_join(&self.result_dir, name);
+            if !iowrapper_exist(&target_dir) {
+                iowrapper_create_dir(&target_dir)?;
+            }
+            for file_whole_path in files.split('|') {
+                let file_whole_path = file_whole_path.to_string();
+                let filename = iowrapper_get_filename(&file_whole_path)?;
+                iowrapper_copy_file(&file_whole_path, &path_join(&target_dir, &filename))?;
+            }
+        }
 
         // 复制完毕，通知server任务结束，可以清除任务, 通信类型是3.
-        let copied_message = String::from(
-            format!("{{\"message_type\":3,\"task_id\":{}}}", self.task_id)
-        );
+        let copied_message = format!("{{\"message_type\":3,\"task_id\":{}}}", self.task_id);
 
         // 同样，那边通知完之后直接drop了之前的stream，所以需要重新连接
         let mut stream = TcpStream::connect(&self.server_host)?;
@@ -134,34 +286,66 @@ impl Client{
         // 这里写了之后如果立即退出, 
 
         println!("All MapReduce task completed.");
+        println!("Input splits:");
+        for split in &self.splits {
+            println!("\t{} [{}, {})", split.path, split.start, split.end);
+        }
+        println!("Counters:");
+        for (name, amount) in &self.counters {
+            println!("\t{}={}", name, amount);
+        }
 
         Ok(())
     }
 
+    /// 检查插件能否加载: 根据文件内容判断是原生动态库还是 wasm 模块, 再检查 ABI 版本以及所有需要的导出.
+    /// streaming 任务只检查两个可执行文件是否存在并且可以执行; 脚本则先在本地编译一遍.
     fn test_mapper_reducer_loadable(&self) -> Result<(),Box<dyn std::error::Error>> {
-        unsafe{
-            let lib = Library::new(&self.dll_path)?;
-            let mapper : Result<Symbol<fn(&String)->HashMap<String,Vec<String>>>, LibloadingError>
-                = lib.get(b"mapper");
-            match mapper{
-                Err(_) => {
-                    return Err(Box::new(MapReduceError::DllLoadingError {
-                        fntype : String::from("mapper")
-                    }));
+        match &self.code {
+            UserCode::Plugin(dll_path) => {
+                // 在本地检查时, 附属文件就是client上的原文件.
+                let mut context = TaskContext { config : self.config.clone(), ..TaskContext::default() };
+                for path in &self.cache_files {
+                    context.cache_files.insert(iowrapper_get_filename(path)?, path.clone());
                 }
-                _ => {}
-            };
-            let reducer: Result<Symbol<fn(&String, &Vec<String>)->Vec<String>>, LibloadingError>
-                = lib.get(b"reducer");
-            match reducer{
-                Err(_) => {
-                    return Err(Box::new(MapReduceError::DllLoadingError {
-                        fntype : String::from("reducer")
-                    }));
+                let plugin = Plugin::load(dll_path, &self.options.limits, &context)?;
+                println!("{:?} plugin {} (version {}) uses ABI version {}.",
+                    plugin.kind(), plugin.info().name, plugin.info().version, plugin.info().abi_version);
+            }
+            UserCode::Streaming(streaming) => {
+                for (fntype, path) in [("mapper", &streaming.mapper), ("reducer", &streaming.reducer)] {
+                    let mode = std::fs::metadata(path).map_err(|e| MapReduceError::DllLoadingError {
+                        fntype : fntype.to_string(),
+                        reason : format!("{}: {}", path, e),
+                    })?.permissions().mode();
+                    if mode & 0o111 == 0 {
+                        return Err(Box::new(MapReduceError::DllLoadingError {
+                            fntype : fntype.to_string(),
+                            reason : format!("{} is not executable", path),
+                        }));
+                    }
                 }
-                _ => {}
-            };
+                println!("Streaming job: mapper {}, reducer {}.", streaming.mapper, streaming.reducer);
+            }
+            UserCode::Script(script) => {
+                let plugin = ScriptPlugin::compile(script)?;
+                println!("Script job: combiner {}, partitioner {}.", plugin.info().combiner, plugin.info().partitioner);
+            }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// 让模拟模式的server(见 MapReduceServer::new_simulated)杀掉节点 node_id: 它的本地文件消失, 正在执行的子任务重新分配.
+pub fn kill_simulated_node(server_host : &str, node_id : u32) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect(server_host)?;
+    stream.write_all(format!("{{\"message_type\":11,\"from\":{}}}", node_id).as_bytes())?;
+    let reply = read_packet(&mut stream)?;
+    if reply.message_type != 12 {
+        return Err(Box::new(MapReduceError::WrongMessageType));
+    }
+    if !reply.dll_file.is_empty() {
+        return Err(reply.dll_file.into());
+    }
+    Ok(())
+}
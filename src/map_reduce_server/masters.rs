@@ -1,82 +1,209 @@
 use serde::{Deserialize, Serialize};
 
 use crate::map_reduce_server::Status;
-use crate::map_reduce::MessagePacket;
-use crate::thread_poll::ThreadPoll;
+use crate::map_reduce::{MessagePacket, JobOptions, JobConfig, Counters, ExecutionMode, InputSplit, add_counters};
 use crate::io_wrapper::*;
-use crate::map_reduce_server::workers::{mapper,reducer};
+use crate::map_reduce_server::workers::{SubtaskKind, SubtaskSpec};
+use crate::map_reduce_server::scheduler::Scheduler;
+use crate::map_reduce_server::shuffle::parse_location;
+use crate::map_reduce_server::splits::plan_splits;
 use crate::error::MapReduceError;
+use crate::plugin::cache::evict_task;
 use std::{
+    collections::BTreeMap,
     sync::mpsc::channel,
     sync::Arc,
     sync::Mutex,
+    fs::OpenOptions,
     io::prelude::*,
     net::TcpStream, 
 };
 
 /// Master和Worker之间通信(Worker向Master发送包)的格式.
+/// 失败时 result_path 中是错误信息, failure 是失败的原因.
+/// named_outputs 是这个子任务写出的命名输出: 输出名 -> 结果文件的路径.
+/// counters 是这个子任务的计数器, 包括用户计数器和框架内置的计数器.
 #[derive(Deserialize, Serialize)]
 pub struct MasterWorkerInfo{
     pub subtask_id : u32,
     pub successed : bool,
     pub result_path : String,
+    #[serde(default)]
+    pub failure : Option<FailureReason>,
+    #[serde(default)]
+    pub named_outputs : BTreeMap<String, String>,
+    #[serde(default)]
+    pub counters : Counters,
 }
 
+/// 子任务失败的原因.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    Error,                  // 返回了错误(用户代码或者框架的读写等)
+    Panicked,               // 用户代码panic
+    Crashed,                // worker子进程异常退出
+    WallClockLimitExceeded, // 超过了墙钟时间限制
+    CpuTimeLimitExceeded,   // 超过了cpu时间限制
+    MemoryLimitExceeded,    // 超过了内存(地址空间或 wasm 线性内存)限制
+    FuelLimitExceeded,      // wasm 插件的燃料耗尽
+    WorkerLost,             // 执行它的 worker daemon 断开了连接
+    FetchFailed{ mapper : u32 },   // reducer 一直取不到这个 mapper 的输出
+}
+
+/// reducer 因为取不到 mapper 的输出而重新执行的最多次数(包括第一次).
+const MAX_REDUCER_ATTEMPTS : u32 = 4;
+/// master 的检查点文件, 在任务的 base_dir 下.
+pub const CHECKPOINT_FILE : &str = "checkpoint.json";
+/// 检查点之后每个子任务的结果, 在任务的 base_dir 下, 每行一条, 见 Master::log_completion.
+pub const CHECKPOINT_LOG : &str = "checkpoint.log";
+
+/// 一个任务的 master. 切分好输入之后把自己整个写到检查点(base_dir/checkpoint.json)中,
+/// 之后每完成一个子任务只在 base_dir/checkpoint.log 后面追加一行它的表项.
+/// master 线程或者 server 死掉之后, 新的 master 从检查点继续, 已经完成的子任务不再执行. 见 Master::load_checkpoint.
+#[derive(Deserialize, Serialize)]
 pub struct Master{
     task_id : u32,
     mapper_num : u32,
     reducer_num : u32,
-    mapper_completed : u32,
-    reducer_completed: u32,
     base_dir : String,   // 暂时的该任务的基本目录.
-    inputpath : String,  // 总的input的路径
+    inputpath : String,  // 所有输入文件, 用|分隔. 可能在hdfs上, mapper 直接读其中的一段.
     dllpath : String,
+    options : JobOptions,
+    config : JobConfig,   // 任务的配置参数, 随子任务交给用户代码.
     mapper_tracking_list : Vec<SubTaskEntry>,
     reducer_tracking_list: Vec<SubTaskEntry>,
 }
 
 // 子任务追踪中的表项
+#[derive(Deserialize, Serialize)]
 struct SubTaskEntry{
     subtask_id : u32,
     status : Status,
     inputpath: String,
+    #[serde(default)]
+    split : Option<InputSplit>,   // mapper 读的那一段输入.
     resultpath:String,
+    named_outputs : BTreeMap<String, String>,   // 这个子任务写出的命名输出.
+    counters : Counters,   // 这个子任务的计数器, 任务的计数器是所有成功的子任务的和.
+    attempts : u32,   // 分配出去的次数.
 }
 
 impl SubTaskEntry{
     pub fn new(subtask_id:u32, status:Status, inputpath:String)->SubTaskEntry{
-        SubTaskEntry { subtask_id, status, inputpath, resultpath: String::from("") }
+        SubTaskEntry { subtask_id, status, inputpath, split: None, resultpath: String::from(""), named_outputs: BTreeMap::new(), counters: Counters::new(), attempts: 0 }
     }
 }
 
 // Master的关联函数, 将这个任务分配给thread.  
 impl Master{
-    pub fn new(task_id:u32, m:u32, n:u32, base_dir:String, inputpath:String, dllpath:String) -> Master{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(task_id:u32, m:u32, n:u32, base_dir:String, inputpath:String, dllpath:String, options:JobOptions, config:JobConfig) -> Master{
         Master{
             task_id,
             mapper_num : m,
             reducer_num: n,
-            mapper_completed: 0,
-            reducer_completed: 0,
             base_dir,
             inputpath,
             dllpath,
+            options,
+            config,
             mapper_tracking_list: Vec::new(),
             reducer_tracking_list: Vec::new(),
         }
     }
 
-    /// master函数的入口
+    /// 第 index 个 mapper 子任务.
+    fn mapper_spec(&self, index : usize) -> SubtaskSpec {
+        SubtaskSpec {
+            kind : SubtaskKind::Mapper,
+            task_id : self.task_id,
+            subtask_id : self.mapper_tracking_list[index].subtask_id,
+            base_dir : self.base_dir.clone(),
+            inputpath : self.mapper_tracking_list[index].inputpath.clone(),
+            split : self.mapper_tracking_list[index].split.clone(),
+            dllpath : self.dllpath.clone(),
+            reducer_num : self.reducer_num,
+            limits : self.options.limits.clone(),
+            config : self.config.clone(),
+            shuffle_host : String::new(),
+            scratch_dir : String::new(),
+        }
+    }
+
+    /// 第 index 个 reducer 子任务.
+    fn reducer_spec(&self, index : usize) -> SubtaskSpec {
+        SubtaskSpec {
+            kind : SubtaskKind::Reducer,
+            task_id : self.task_id,
+            subtask_id : index as u32,
+            base_dir : self.base_dir.clone(),
+            inputpath : self.reducer_tracking_list[index].inputpath.clone(),
+            split : None,
+            dllpath : self.dllpath.clone(),
+            reducer_num : self.reducer_num,
+            limits : self.options.limits.clone(),
+            config : self.config.clone(),
+            shuffle_host : String::new(),
+            scratch_dir : String::new(),
+        }
+    }
+
+    /// 第 i 个reducer的输入文件: 所有成功的mapper的第i个输出文件, 用|分隔. 没有 mapper(输入是空的)时是空字符串.
+    fn reducer_inputs(&self, i : u32) -> String {
+        let mut inputfiles = String::new();
+        for mapper_task in &self.mapper_tracking_list{
+            // 只记录那些成功的.
+            match mapper_task.status {
+                Status::Completed => {
+                    let inputfile = path_join(
+                        &mapper_task.resultpath, 
+                        &format!("{i}.json")
+                    );
+                    inputfiles.push_str(&inputfile);
+                    inputfiles.push('|');
+                }
+                _ => {
+                    continue;
+                }
+            }
+        }
+        inputfiles.trim_end_matches('|').to_string()  // 去掉末尾的 |
+    }
+
+    /// 重新执行第 mapper 个 mapper(它的输出取不到了), 等它完成.
+    /// 又失败了的话这个 mapper 就算失败了, 之后的 reducer 不再读它的输出.
+    fn rerun_mapper(&mut self, mapper : usize, scheduler : &Arc<Scheduler>) -> Result<(), Box<dyn std::error::Error>> {
+        println!("Task {}: rerunning mapper {} whose output cannot be fetched", self.task_id, mapper);
+        let (sender, receiver) = channel::<String>();
+        scheduler.dispatch(self.mapper_spec(mapper), self.options.clone(), sender);
+        let packet:MasterWorkerInfo = serde_json::from_str(&receiver.recv()?)?;
+        let mapper_task = &mut self.mapper_tracking_list[mapper];
+        mapper_task.attempts += 1;
+        if packet.successed {
+            // 计数器与命名输出在第一次完成时已经记下了.
+            mapper_task.resultpath = packet.result_path;
+        } else {
+            eprintln!("Task {}: mapper {} failed again : {}", self.task_id, mapper, packet.result_path);
+            mapper_task.status = Status::Error;
+        }
+        Ok(())
+    }
+
+    /// master函数的入口. 进度随时写在检查点中, server 重启之后新的 master 从检查点继续, 见 load_checkpoint.
+    #[allow(clippy::too_many_arguments)]
     pub fn master_thread(
         task_id:u32,m:u32, n:u32, base_dir:String,
-        inputpath:String, dllpath:String,
+        inputpath:String, dllpath:String, options:JobOptions, config:JobConfig,
         server_host:String,
-        worker_poll: Arc<Mutex<ThreadPoll>>   // 共享所有权并且互斥.
+        scheduler: Arc<Scheduler>   // 所有master共用的子任务调度器.
     ) {
         // TODO: 在这里申请Master，这样之后在失败之后就可以在这里进行清理，否在在do_master中清理.
         if let Err(e) = Master::do_master(
-            task_id, m, n, base_dir, inputpath, dllpath, server_host.clone(), worker_poll) {
+            task_id, m, n, base_dir, inputpath, dllpath, options, config, server_host.clone(), scheduler.clone()) {
             eprintln!("Master (task id: {}) failed. {}",task_id, e);
+            // 失败的任务同样要卸载它缓存的插件.
+            evict_task(task_id);
+            scheduler.evict_task(task_id);
             let mut stream = TcpStream::connect(server_host).expect(
                 "Master cannot connect to Server!"
             );
@@ -92,64 +219,143 @@ impl Master{
         }
     }
 
+    /// 把当前的进度整个写到检查点, 清空检查点之后的日志. 先写到临时文件再改名, 写到一半死掉也不会留下不完整的检查点;
+    /// 改名之后、清空日志之前死掉的话, 日志中的表项都是已经写进检查点的, 回放一遍也没有关系.
+    fn save_checkpoint(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let checkpoint = path_join(&self.base_dir, &CHECKPOINT_FILE.to_string());
+        let temp = format!("{}.tmp", checkpoint);
+        iowrapper_write_file_all(&temp, &serde_json::to_string(self)?)?;
+        std::fs::rename(&temp, &checkpoint)?;
+        let log = path_join(&self.base_dir, &CHECKPOINT_LOG.to_string());
+        if iowrapper_exist(&log) {
+            iowrapper_remove_file(&log)?;
+        }
+        Ok(())
+    }
+
+    /// 在检查点的日志后面追加第 index 个 mapper 或 reducer 现在的表项, 每行一个 [kind, index, 表项].
+    fn log_completion(&self, kind : SubtaskKind, index : usize) -> Result<(), Box<dyn std::error::Error>> {
+        let task = match kind {
+            SubtaskKind::Mapper => &self.mapper_tracking_list[index],
+            SubtaskKind::Reducer => &self.reducer_tracking_list[index],
+        };
+        let line = serde_json::to_string(&(kind, index, task))? + "\n";
+        let log = path_join(&self.base_dir, &CHECKPOINT_LOG.to_string());
+        OpenOptions::new().create(true).append(true).open(log)?.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// 按顺序把日志中的表项放回 mapper_tracking_list/reducer_tracking_list. 最后一行可能只写了一半(写的时候死掉了), 不要了.
+    fn replay_log(&mut self, log : &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut lines = log.lines().peekable();
+        while let Some(line) = lines.next() {
+            let (kind, index, task) : (SubtaskKind, usize, SubTaskEntry) = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(_) if lines.peek().is_none() => break,
+                Err(e) => return Err(e.into()),
+            };
+            let list = match kind {
+                SubtaskKind::Mapper => &mut self.mapper_tracking_list,
+                SubtaskKind::Reducer => &mut self.reducer_tracking_list,
+            };
+            match list.get_mut(index) {
+                Some(entry) => *entry = task,
+                None => return Err(format!("the checkpoint log refers to a missing {:?} {}", kind, index).into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// 读入 base_dir 中的检查点并回放它之后的日志, 没有检查点的话返回 None. 读入的 master 从第一个没有完成的阶段继续:
+    /// 完成了的子任务, 输出还在本机上的话不再执行, 否则(比如在已经断开的 worker daemon 上)重新执行;
+    /// mapper 的输出直接读本机上的文件, 原来的 shuffle 服务可能已经随着旧的 server 一起没了.
+    /// 执行中的子任务的结果已经收不到了, 同样重新执行. 失败了的子任务仍然算失败.
+    pub fn load_checkpoint(base_dir : &str) -> Result<Option<Master>, Box<dyn std::error::Error>> {
+        let checkpoint = path_join(&base_dir.to_string(), &CHECKPOINT_FILE.to_string());
+        if !iowrapper_exist(&checkpoint) {
+            return Ok(None);
+        }
+        let mut master : Master = serde_json::from_str(&iowrapper_read_to_string(&checkpoint)?)?;
+        let log = path_join(&base_dir.to_string(), &CHECKPOINT_LOG.to_string());
+        if iowrapper_exist(&log) {
+            master.replay_log(&iowrapper_read_to_string(&log)?)?;
+        }
+        for task in master.mapper_tracking_list.iter_mut().chain(master.reducer_tracking_list.iter_mut()) {
+            match task.status {
+                Status::Completed => {
+                    let local = parse_location(&task.resultpath).map_or(task.resultpath.as_str(), |(_, path)| path).to_string();
+                    if iowrapper_exist(&local) {
+                        task.resultpath = local;
+                    } else {
+                        task.status = Status::Waiting;
+                    }
+                }
+                Status::Executing => task.status = Status::Waiting,
+                _ => {}
+            }
+        }
+        Ok(Some(master))
+    }
+
     /// 创建一个master所用的线程函数!
+    #[allow(clippy::too_many_arguments)]
     fn do_master(
         task_id:u32,m:u32, n:u32, base_dir:String,
-        inputpath:String, dllpath:String,
+        inputpath:String, dllpath:String, options:JobOptions, config:JobConfig,
         server_host:String,
-        worker_poll: Arc<Mutex<ThreadPoll>>   // 共享所有权并且互斥.
+        scheduler: Arc<Scheduler>   // 所有master共用的子任务调度器.
     ) -> Result<(), Box<dyn std::error::Error>>{
-        let mut master:Master = Master::new(task_id, m, n, base_dir, inputpath.clone(), dllpath.clone());
+        let mut master:Master = match Master::load_checkpoint(&base_dir)? {
+            Some(master) => {
+                println!("Task {}: resuming from its checkpoint.", task_id);
+                // 回放过的日志并进检查点.
+                master.save_checkpoint()?;
+                master
+            }
+            None => {
+                let mut master = Master::new(task_id, m, n, base_dir, inputpath.clone(), dllpath.clone(), options, config);
+                // 先把输入切成若干段(见 JobOptions.split_size), 每段一个mapper任务.
+                let inputs : Vec<String> = master.inputpath.split('|').map(String::from).collect();
+                let splits = plan_splits(&inputs, master.mapper_num, &master.options.split_size)?;
+                for (mapper_id, split) in (0..).zip(splits){
+                    let mut mapper_task = SubTaskEntry::new(
+                        mapper_id,
+                        Status::Waiting,
+                        split.path.clone()
+                    );
+                    mapper_task.split = Some(split);
+                    master.mapper_tracking_list.push(mapper_task);
+                }
+                // 纠正m, 让m变成mapper_tracking_list中的值: 段数由输入的大小与 split_size 决定, 和申请的m不一定一样.
+                // 切分的结果随完成的消息发给client.
+                let real_m = master.mapper_tracking_list.len() as u32;
+                println!("Task {}: the input is split into {} parts, {} mappers were asked for.", task_id, real_m, master.mapper_num);
+                master.mapper_num = real_m;
+                master.save_checkpoint()?;
+                master
+            }
+        };
         let (sender, receiver) = channel::<String>();
-
-        // 先创建所有mapper任务
-        let mut mapper_id : u32 = 0;
-        for filepath in iowrapper_read_dir_into_strings(&master.inputpath)?{
-            // filepath直接是文件夹子文件的路径.
-            let mapper_task = SubTaskEntry::new(
-                mapper_id,
-                Status::Waiting,
-                String::from(filepath)
-            );
-            master.mapper_tracking_list.push(mapper_task);
-            mapper_id+=1;
-        }
-        // 纠正可能的m的错误，让m变成mapper_tracking_list中的值, mapper_tracking_list是inputpath中文件数量.
-        let real_m = master.mapper_tracking_list.len() as u32;
-        if real_m != master.mapper_num {
-            //---------------------------------
-            // TODO: 打一条后续发给client 的 log?
-            //---------------------------------
-            master.mapper_num = real_m;
+        if master.options.execution_mode == ExecutionMode::Thread && master.options.limits.has_process_limits() {
+            println!("Task {}: wall clock, cpu time and memory limits only take effect in process execution mode, ignored.", task_id);
         }
 
-        // 接着把所有mapper任务分配出去.
-        for mapper_task_item in &mut master.mapper_tracking_list {
-            let task_id = master.task_id;
-            let subtaskid = mapper_task_item.subtask_id;
-            let base_dir = master.base_dir.clone();
-            let inputp = mapper_task_item.inputpath.clone();
-            let dllp = master.dllpath.clone();
-            let n = master.reducer_num;
-            let worker_sender = sender.clone();
-            // 绕考所有权机制，让下面的闭包获取所有权..
-            // 别的线程在拿着worker_poll的时候死掉了，会返回一个error(但同样获取了mutex). ——暂时不管.
-            worker_poll.lock().unwrap().execute(move || {
-                mapper(
-                    task_id,
-                    subtaskid, 
-                    base_dir,
-                    inputp, 
-                    dllp, 
-                    n, 
-                    worker_sender);
-            });
+        // 接着把所有还没有完成的mapper任务分配出去.
+        let mut executing = 0;
+        for index in 0..master.mapper_tracking_list.len() {
+            if master.mapper_tracking_list[index].status != Status::Waiting {
+                continue;
+            }
+            // 交给本地的worker线程或者某个 worker daemon.
+            scheduler.dispatch(master.mapper_spec(index), master.options.clone(), sender.clone());
+            let mapper_task_item = &mut master.mapper_tracking_list[index];
             mapper_task_item.status = Status::Executing;  // 修改状态.
+            mapper_task_item.attempts += 1;
+            executing += 1;
         }
 
         // 接着读取回复结果. 有一个sender在自己这里，一定不会因没有发送端而终止.
-        let mut max_mapper_num: u32 = master.mapper_num;
-        loop{
+        while executing > 0 {
             let mapper_result = receiver.recv()?;
             let packet:MasterWorkerInfo = serde_json::from_str(&mapper_result)?;
             let index = packet.subtask_id as usize;
@@ -157,140 +363,308 @@ impl Master{
             if packet.successed {
                 mapper_task.status = Status::Completed;
                 mapper_task.resultpath = packet.result_path;  // 一个mapper会准备n个输出文件，在一个文件夹下.
-                master.mapper_completed += 1;
+                mapper_task.named_outputs = packet.named_outputs;
+                mapper_task.counters = packet.counters;
             } else {
                 //------------TODO--------------
                 // 错误处理, 保存一条log..?
                 //------------------------------
                 mapper_task.status = Status::Error;
-                max_mapper_num -= 1;
-                
-            }
-            if master.mapper_completed == max_mapper_num {
-                // 所有任务都完成了.
-                break;
             }
+            executing -= 1;
+            master.log_completion(SubtaskKind::Mapper, index)?;
         }
 
-        // 准备reducer任务. 第 i 个reducer的输入文件是所有mapper的第i个输出文件.
-        for i in 0..master.reducer_num {
-            // 编号为i的reducer, 输入文件是所有mapper的输出文件i.mid  
-            let mut inputfiles = String::new();
-            for mapper_task in &master.mapper_tracking_list{
-                // 只记录那些成功的.
-                match mapper_task.status {
-                    Status::Completed => {
-                        let inputfile = path_join(
-                            &mapper_task.resultpath, 
-                            &String::from(format!("{i}.json"))
-                        );
-                        inputfiles.push_str(&inputfile);
-                        inputfiles.push('|');
-                    }
-                    _ => {
-                        continue;
-                    }
-                }
+        // 有 mapper 却都失败了的话, reducer 的输入都是空的, 结果没有意义. 输入是空的(一个 mapper 也没有)不算失败.
+        if !master.mapper_tracking_list.is_empty()
+            && master.mapper_tracking_list.iter().all(|task| task.status != Status::Completed) {
+            return Err(format!("all {} mappers failed", master.mapper_tracking_list.len()).into());
+        }
+
+        // 只有 map 的任务(reducer_num 为 0)没有 shuffle 和 reduce, 下面准备、等待 reducer 的步骤什么也不做,
+        // mapper 的输出文件就是结果.
+        let map_only = master.reducer_num == 0;
+
+        // 准备reducer任务(从检查点继续时已经准备过了). 第 i 个reducer的输入文件是所有mapper的第i个输出文件,
+        // 分配的时候才确定: 从检查点继续时mapper的输出可能换了位置.
+        // 准备好之后写一次检查点, 之后日志中的 reducer 表项才有地方放.
+        if master.reducer_tracking_list.is_empty() && !map_only {
+            for i in 0..master.reducer_num {
+                let reducer_task = SubTaskEntry::new(
+                    i+master.mapper_num,
+                    Status::Waiting,
+                    String::new()
+                );
+                master.reducer_tracking_list.push(reducer_task);
             }
-            let inputfiles = inputfiles.trim_end_matches('|').to_string();  // 去掉末尾的 |
-            let reducer_task = SubTaskEntry::new(
-                i+mapper_id, 
-                Status::Waiting,
-                inputfiles
-            );
-            master.reducer_tracking_list.push(reducer_task);
+            master.save_checkpoint()?;
         }
         
-        // 向 workerpoll 中丢入所有 reducer 任务.
-        for reducer_task_item in &mut master.reducer_tracking_list{
-            // 向Workerpoll 丢入一个 reducer任务. 注意所有权向闭包的转移..
-            let task_id = master.task_id;
-            let subtask_id = reducer_task_item.subtask_id;
-            let base_dir = master.base_dir.clone();
-            let inputfilepath = reducer_task_item.inputpath.clone();
-            let dllpath = master.dllpath.clone();
-            let worker_sender = sender.clone();
-            worker_poll.lock().unwrap().execute(move || {
-                reducer(
-                    task_id, subtask_id - mapper_id, 
-                    base_dir, inputfilepath, dllpath, worker_sender);
-            });
-
+        // 向 workerpoll 中丢入所有还没有完成的 reducer 任务.
+        for index in 0..master.reducer_tracking_list.len() {
+            if master.reducer_tracking_list[index].status != Status::Waiting {
+                continue;
+            }
+            // 编号为i的reducer, 输入文件是所有mapper的输出文件i.json
+            master.reducer_tracking_list[index].inputpath = master.reducer_inputs(index as u32);
+            scheduler.dispatch(master.reducer_spec(index), master.options.clone(), sender.clone());
+            let reducer_task_item = &mut master.reducer_tracking_list[index];
             reducer_task_item.status = Status::Executing;
+            reducer_task_item.attempts += 1;
+            executing += 1;
         }
 
         // 接下来等worker回复完成reducer的消息.
-        let mut max_reducer_num = master.reducer_num;
-        loop{
+        while executing > 0 {
             let reducer_result = receiver.recv()?;
             let packet:MasterWorkerInfo = serde_json::from_str(&reducer_result)?;
             let index = packet.subtask_id as usize;
+            // 取不到某个 mapper 的输出: 重新执行那个 mapper(别的 reducer 已经让它重新执行过的话就不用了), 然后重新执行这个 reducer.
+            if let Some(FailureReason::FetchFailed { mapper }) = packet.failure {
+                let mapper = mapper as usize;
+                if mapper < master.mapper_tracking_list.len() && master.reducer_tracking_list[index].attempts < MAX_REDUCER_ATTEMPTS {
+                    eprintln!("Reducer {} of task {} : {}", index, master.task_id, packet.result_path);
+                    let stale = &master.mapper_tracking_list[mapper].resultpath;
+                    if master.reducer_tracking_list[index].inputpath.split('|').any(|inputfile| inputfile.starts_with(stale.as_str())) {
+                        master.rerun_mapper(mapper, &scheduler)?;
+                        master.log_completion(SubtaskKind::Mapper, mapper)?;
+                    }
+                    master.reducer_tracking_list[index].inputpath = master.reducer_inputs(index as u32);
+                    scheduler.dispatch(master.reducer_spec(index), master.options.clone(), sender.clone());
+                    master.reducer_tracking_list[index].attempts += 1;
+                    continue;
+                }
+            }
             let reducer_task:&mut SubTaskEntry = &mut master.reducer_tracking_list[index];
             if(packet.successed){
                 reducer_task.status = Status::Completed;
                 reducer_task.resultpath = packet.result_path;
-                master.reducer_completed += 1;
+                reducer_task.named_outputs = packet.named_outputs;
+                reducer_task.counters = packet.counters;
             } else {
                 // --------TODO----------------
                 // 错误处理，打个Log之类的?.
                 // ----------------------------
                 reducer_task.status = Status::Error;
-                max_reducer_num -= 1;
-            }
-
-            if master.reducer_completed == max_reducer_num {
-                break;
             }
+            executing -= 1;
+            master.log_completion(SubtaskKind::Reducer, index)?;
+        }
+        // 完成，收集结果文件位置. 只收集成功的子任务的结果: 失败的子任务的 resultpath 是错误信息, 或者还是输入.
+        let result_tracking_list = if map_only {
+            &master.mapper_tracking_list
+        } else {
+            &master.reducer_tracking_list
+        };
+        let completed = result_tracking_list.iter().filter(|task| task.status == Status::Completed).count();
+        if completed == 0 && !result_tracking_list.is_empty() {
+            // 一个结果都没有, 报告任务失败(type 8).
+            return Err(format!("all {} subtasks producing results failed", result_tracking_list.len()).into());
+        }
+        if completed < result_tracking_list.len() {
+            eprintln!("Task {}: {} of {} subtasks producing results failed, their results are missing.",
+                master.task_id, result_tracking_list.len() - completed, result_tracking_list.len());
         }
-
-        // 完成，收集结果文件位置.
         let mut resultfiles = String::new();
-        for reducer_task in &master.reducer_tracking_list {
+        for reducer_task in result_tracking_list.iter().filter(|task| task.status == Status::Completed) {
             resultfiles.push_str(&reducer_task.resultpath);
             resultfiles.push('|');
         }
+        // 输入是空的、只有 map 的任务一个结果文件也没有, 而没有结果文件表示任务失败(见 type 5), 给出一个空的结果文件.
+        if result_tracking_list.is_empty() {
+            let empty = path_join(&master.base_dir, &String::from("ret0.json"));
+            iowrapper_create_file(&empty)?;
+            resultfiles.push_str(&empty);
+        }
         // 注意，自己整除json字符串，\需要显式地有两个：\\ ! 否则非法.
         let resultfiles = resultfiles.trim_end_matches('|').replace('\\', "/");   //去掉末尾的 |
 
+        // 命名输出: mapper 与 reducer 都可能写, 按输出名把所有结果文件用|连起来.
+        // 整个任务的计数器是所有成功的子任务的计数器之和.
+        let mut named_outputs : BTreeMap<String, String> = BTreeMap::new();
+        let mut counters = Counters::new();
+        for task in master.mapper_tracking_list.iter().chain(master.reducer_tracking_list.iter()) {
+            if let Status::Completed = task.status {
+                add_counters(&mut counters, &task.counters);
+                for (name, path) in &task.named_outputs {
+                    let files = named_outputs.entry(name.clone()).or_default();
+                    if !files.is_empty() {
+                        files.push('|');
+                    }
+                    files.push_str(path);
+                }
+            }
+        }
+
         // 接下来向Server发送消息：完成.
         let mut tcpstream = TcpStream::connect(server_host)?;
-        let message_json_str = format!(
-            "{{\"message_type\":7,\"task_id\":{},\"data_file\":\"{}\"}}",
-            master.task_id, resultfiles
-        );  // 一个json格式字符串. 也许有更好的方式统一地组织它...
+        let message = MessagePacket {
+            message_type : 7,
+            task_id : master.task_id,
+            data_file : resultfiles,
+            named_outputs,
+            counters,
+            splits : master.mapper_tracking_list.iter().filter_map(|task| task.split.clone()).collect(),
+            ..MessagePacket::default()
+        };
 
-        tcpstream.write_all(message_json_str.as_bytes())?;
+        tcpstream.write_all(serde_json::to_string(&message)?.as_bytes())?;
         
         // 之后等待回复, 回复的一定是clear信号(type:6)，所以不用管内容，只是阻塞到等来信号.
         let mut unused = [0; 1024];
-        tcpstream.read(&mut unused)?;   // 这里会阻塞. 这个信息用不上，不管.
+        let _ = tcpstream.read(&mut unused)?;   // 这里会阻塞. 这个信息用不上，不管.
 
         // 执行清理：清理原始inputfiles, 清理mapper产生的所有中间文件，清理reducer产生的结果文件.
-        // 1. 清除最开始的输入文件位置与dllpath.
-        iowrapper_remove_dir_all(&inputpath)?;
+        // 1. 清除dllpath. 输入文件是client给的, 不在这里清理(上传到hdfs上的由server随任务目录一起删除).
         iowrapper_remove_file(&dllpath)?;
-        // 2. 清除所有成功的mapper_task的resultpath(是一个文件夹)
+        // 2. 清除所有成功的mapper_task的resultpath(是一个文件夹; 只有 map 的任务中是结果文件)
         for mapper_task in &master.mapper_tracking_list {
-            match mapper_task.status {
-                Status::Completed => {
-                    iowrapper_remove_dir_all(&mapper_task.resultpath)?;
+            if let Status::Completed = mapper_task.status {
+                if map_only {
+                    iowrapper_remove_file(&mapper_task.resultpath)?;
+                } else {
+                    // shuffle 位置的话, 只清理本机上的同一个文件夹(在别的机器上执行的 mapper 在本机上没有这个文件夹).
+                    let mid_dir = parse_location(&mapper_task.resultpath).map_or(mapper_task.resultpath.as_str(), |(_, path)| path).to_string();
+                    if iowrapper_exist(&mid_dir) {
+                        iowrapper_remove_dir_all(&mid_dir)?;
+                    }
                 }
-                _ => {}
-            }            
+            }
         }
         // 3. 清除所有成功的reducer_task的resultpath(是一个文件)
         for reducer_task in &master.reducer_tracking_list {
-            match reducer_task.status {
-                Status::Completed => {
-                    iowrapper_remove_file(&reducer_task.resultpath)?;
-                }
-                _ => {}
+            if let Status::Completed = reducer_task.status {
+                iowrapper_remove_file(&reducer_task.resultpath)?;
             }
         }
         // 4. 清理掉这个任务的base_dir.
         iowrapper_remove_dir_all(&master.base_dir)?;
+        // 5. 卸载这个任务缓存的插件, 包括各个 worker daemon 中的.
+        evict_task(master.task_id);
+        scheduler.evict_task(master.task_id);
 
         println!("Master of task {} completed and quited.", master.task_id);
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use crate::map_reduce::read_packet;
+    use crate::map_reduce_server::nodes::LocalNode;
+    use crate::map_reduce_server::test_dir::TestDir;
+    use crate::thread_poll::ThreadPoll;
+
+    /// base_dir 在 dir 下、有 m 个等待中的 mapper 的 master.
+    fn new_master(dir : &TestDir, m : u32, n : u32) -> Master {
+        let base_dir = dir.path().to_string_lossy().into_owned();
+        let mut master = Master::new(0, m, n, base_dir, String::new(), String::new(), JobOptions::default(), JobConfig::default());
+        for i in 0..m {
+            master.mapper_tracking_list.push(SubTaskEntry::new(i, Status::Waiting, format!("input{}", i)));
+        }
+        master
+    }
+
+    #[test]
+    fn checkpoint_log_is_replayed() {
+        let dir = TestDir::new("masters", "replay");
+        let mut master = new_master(&dir, 3, 0);
+        master.save_checkpoint().unwrap();
+        let output = dir.path().join("m0").to_string_lossy().into_owned();
+        std::fs::write(&output, "").unwrap();
+        master.mapper_tracking_list[0].status = Status::Completed;
+        master.mapper_tracking_list[0].resultpath = output.clone();
+        master.mapper_tracking_list[0].counters.insert(String::from("c"), 1);
+        master.log_completion(SubtaskKind::Mapper, 0).unwrap();
+        master.mapper_tracking_list[1].status = Status::Error;
+        master.log_completion(SubtaskKind::Mapper, 1).unwrap();
+        // 检查点本身没有变.
+        let checkpoint = dir.path().join(CHECKPOINT_FILE);
+        let saved : Master = serde_json::from_str(&std::fs::read_to_string(&checkpoint).unwrap()).unwrap();
+        assert!(saved.mapper_tracking_list.iter().all(|task| task.status == Status::Waiting));
+        // 写到一半的最后一行不要了.
+        let log = dir.path().join(CHECKPOINT_LOG);
+        OpenOptions::new().append(true).open(&log).unwrap().write_all(b"[\"Mapper\",2,{\"subt").unwrap();
+
+        let loaded = Master::load_checkpoint(&master.base_dir).unwrap().unwrap();
+        let statuses : Vec<Status> = loaded.mapper_tracking_list.iter().map(|task| task.status).collect();
+        assert!(statuses == [Status::Completed, Status::Error, Status::Waiting]);
+        assert_eq!(loaded.mapper_tracking_list[0].resultpath, output);
+        assert_eq!(loaded.mapper_tracking_list[0].counters.get("c"), Some(&1));
+        // 并进检查点之后日志清空.
+        loaded.save_checkpoint().unwrap();
+        assert!(!log.exists());
+        let reloaded = Master::load_checkpoint(&master.base_dir).unwrap().unwrap();
+        assert!(reloaded.mapper_tracking_list[1].status == Status::Error);
+    }
+
+    #[test]
+    fn log_entries_must_refer_to_planned_subtasks() {
+        let dir = TestDir::new("masters", "missing");
+        let mut master = new_master(&dir, 1, 2);
+        master.save_checkpoint().unwrap();
+        master.reducer_tracking_list.push(SubTaskEntry::new(1, Status::Error, String::new()));
+        master.log_completion(SubtaskKind::Reducer, 0).unwrap();
+        master.log_completion(SubtaskKind::Mapper, 0).unwrap();
+        assert!(Master::load_checkpoint(&master.base_dir).is_err());
+    }
+
+    /// 在 dir 中用 cat 作为 mapper 和 reducer 的 streaming 任务执行一个 master, 输入是 inputs 中的内容.
+    /// 返回 master 发给 server 的消息, 以及其中每个结果文件的内容(回复 server 之后 master 就把它们删了).
+    fn run_master(dir : &TestDir, task_id : u32, inputs : &[&str], n : u32) -> (MessagePacket, Vec<String>) {
+        let root = dir.path().to_string_lossy().into_owned();
+        let base_dir = format!("{}/base/", root);
+        std::fs::create_dir_all(&base_dir).unwrap();
+        for script in ["mapper.sh", "reducer.sh"] {
+            std::fs::write(dir.path().join(script), "#!/bin/sh\ncat\n").unwrap();
+        }
+        let dllpath = format!("{}/plugin.json", root);
+        std::fs::write(&dllpath, r#"{"mapper":"mapper.sh","reducer":"reducer.sh"}"#).unwrap();
+        let inputpath : Vec<String> = inputs.iter().enumerate().map(|(i, content)| {
+            let path = format!("{}/input{}.txt", root, i);
+            std::fs::write(&path, content).unwrap();
+            path
+        }).collect();
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_host = server.local_addr().unwrap().to_string();
+        let scheduler = Scheduler::new(vec![LocalNode::server(ThreadPoll::new(2), String::new())]);
+        let inputpath = inputpath.join("|");
+        let master = std::thread::spawn(move || Master::master_thread(task_id, 1, n, base_dir, inputpath, dllpath,
+            JobOptions::default(), JobConfig::default(), server_host, scheduler));
+        let (mut stream, _) = server.accept().unwrap();
+        let packet = read_packet(&mut stream).unwrap();
+        let contents = packet.data_file.split('|').filter(|path| !path.is_empty())
+            .map(|path| std::fs::read_to_string(path).unwrap()).collect();
+        // 失败的 master 不等回复.
+        let _ = stream.write_all(b"{\"message_type\":6}");
+        master.join().unwrap();
+        (packet, contents)
+    }
+
+    #[test]
+    fn empty_input_gives_empty_results() {
+        let dir = TestDir::new("masters", "empty");
+        let (packet, contents) = run_master(&dir, 900, &["", ""], 2);
+        assert_eq!(packet.message_type, 7);
+        assert!(packet.splits.is_empty());
+        assert_eq!(contents, ["", ""]);
+    }
+
+    #[test]
+    fn empty_map_only_input_gives_an_empty_result() {
+        let dir = TestDir::new("masters", "empty_map_only");
+        let (packet, contents) = run_master(&dir, 901, &[""], 0);
+        assert_eq!(packet.message_type, 7);
+        assert_eq!(contents, [""]);
+    }
+
+    #[test]
+    fn results_of_a_small_input() {
+        let dir = TestDir::new("masters", "small");
+        let (packet, contents) = run_master(&dir, 902, &["a\tx\n", "b\ty\n"], 1);
+        assert_eq!(packet.message_type, 7);
+        assert_eq!(contents, ["[\"a\",[\"x\"]]\n[\"b\",[\"y\"]]\n"]);
+    }
+}
@@ -0,0 +1,333 @@
+// server 一侧的集群成员表: 记录所有注册过的 worker daemon, 它们最近的心跳、空闲的槽位、最近的失败次数.
+// 超过 HEARTBEAT_TIMEOUT 没有心跳的 worker 被认为已经死了, 由调度器把它正在执行的子任务重新分配;
+// FAILURE_WINDOW 内失败了 FAILURES_TO_BLACKLIST 次的 worker 被拉黑 BLACKLIST_COOLDOWN, 期间不分配新的子任务.
+// 失败记录按 worker 的机器(它的 shuffle 服务的地址, 没有的话是它的 ip)记, 死掉之后重新注册的 daemon 拿到新的编号, 但是失败记录还在.
+// 只有说明机器本身有问题的失败(worker 子进程崩溃、worker 丢失)才算数, 用户代码的错误和 panic 在哪里执行都一样, 不算.
+// 给 worker 写命令时持有整个成员表的锁, 所以写命令有 COMMAND_WRITE_TIMEOUT, 卡住的 worker 不会拖住调度.
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    net::{Shutdown, TcpStream},
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use crate::map_reduce::JobOptions;
+use crate::map_reduce_server::masters::{MasterWorkerInfo, FailureReason};
+use crate::map_reduce_server::remote_worker::{DaemonCommand, SubtaskAssignment};
+use crate::map_reduce_server::workers::SubtaskSpec;
+
+/// worker daemon 发送心跳的间隔.
+pub const HEARTBEAT_INTERVAL : Duration = Duration::from_secs(2);
+/// 超过这么久没有收到心跳(或者任何消息)就认为 worker 已经死了.
+pub const HEARTBEAT_TIMEOUT : Duration = Duration::from_secs(10);
+/// 统计失败次数的时间窗口.
+pub const FAILURE_WINDOW : Duration = Duration::from_secs(300);
+/// 时间窗口内失败这么多次就拉黑.
+pub const FAILURES_TO_BLACKLIST : usize = 3;
+/// 拉黑的时长, 过了之后失败次数清零, 重新开始分配子任务.
+pub const BLACKLIST_COOLDOWN : Duration = Duration::from_secs(60);
+/// 一个子任务因为 worker 死掉而被重新分配的最多次数, 再死就报告失败.
+pub const MAX_SUBTASK_ATTEMPTS : u32 = 4;
+/// 给 worker 写一条命令最多等待的时间, 超时的 worker 当作已经死了.
+pub const COMMAND_WRITE_TIMEOUT : Duration = Duration::from_secs(5);
+
+/// 交给 worker daemon、还没有结果的子任务. worker 死掉时据此重新分配.
+pub struct PendingSubtask {
+    pub spec : SubtaskSpec,
+    pub options : JobOptions,
+    pub sender : Sender<String>,
+    pub attempt : u32,   // 这是第几次尝试, 从 1 开始.
+}
+
+/// 一个 worker daemon 的状态.
+struct Member {
+    capacity : u32,
+    shuffle_host : String,   // 它的 shuffle 服务的地址.
+    stream : TcpStream,   // 写命令用, 读消息的是另一个线程中的 try_clone.
+    alive : bool,         // 写命令失败之后就不再分配, 等读消息的线程发现连接断开再去掉.
+    last_heartbeat : Instant,
+    identity : String,   // 它的失败记录的键, 见 worker_identity.
+    pending : HashMap<u64, PendingSubtask>,   // 分配的 id -> 子任务.
+}
+
+impl Member {
+    fn free_slots(&self) -> u32 {
+        self.capacity.saturating_sub(self.pending.len() as u32)
+    }
+
+    /// 给 worker 写一条命令. 写失败(包括超时, 这时可能只写了半行)之后这个连接不能再用, 关掉它.
+    fn send(&mut self, worker_id : u32, line : &str) -> std::io::Result<()> {
+        let ret = self.stream.write_all(line.as_bytes());
+        if let Err(e) = &ret {
+            eprintln!("Cannot send a command to worker daemon {} : {}", worker_id, e);
+            self.alive = false;
+            let _ = self.stream.shutdown(Shutdown::Both);
+        }
+        ret
+    }
+}
+
+/// 一个 worker 最近的失败与拉黑的状态. 时间都由调用者给出.
+#[derive(Default)]
+struct FailureRecord {
+    failures : VecDeque<Instant>,   // FAILURE_WINDOW 内每次失败的时间.
+    blacklisted_until : Option<Instant>,
+}
+
+impl FailureRecord {
+    /// 子任务失败的原因是否说明这台机器有问题.
+    fn counts(failure : Option<FailureReason>) -> bool {
+        matches!(failure, Some(FailureReason::Crashed | FailureReason::WorkerLost))
+    }
+
+    /// 记下 now 时的一次失败, 返回是否因此被拉黑.
+    fn record(&mut self, now : Instant) -> bool {
+        self.failures.push_back(now);
+        while self.failures.front().is_some_and(|time| now.duration_since(*time) > FAILURE_WINDOW) {
+            self.failures.pop_front();
+        }
+        if self.failures.len() >= FAILURES_TO_BLACKLIST && !self.blacklisted(now) {
+            self.blacklisted_until = Some(now + BLACKLIST_COOLDOWN);
+            return true;
+        }
+        false
+    }
+
+    fn blacklisted(&self, now : Instant) -> bool {
+        self.blacklisted_until.is_some_and(|until| now < until)
+    }
+
+    /// 拉黑到期的话解除拉黑并清空失败次数, 返回是否解除了.
+    fn release(&mut self, now : Instant) -> bool {
+        if self.blacklisted_until.is_some() && !self.blacklisted(now) {
+            self.blacklisted_until = None;
+            self.failures.clear();
+            return true;
+        }
+        false
+    }
+}
+
+/// 一个 worker daemon 跨越重新注册不变的标识: 它的 shuffle 服务的地址, 没有的话是它的 ip.
+fn worker_identity(stream : &TcpStream, shuffle_host : &str) -> String {
+    if !shuffle_host.is_empty() {
+        return shuffle_host.to_string();
+    }
+    stream.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+}
+
+/// 集群成员表.
+#[derive(Default)]
+pub struct Membership {
+    members : HashMap<u32, Member>,
+    failures : HashMap<String, FailureRecord>,   // worker 的标识 -> 失败记录, worker 去掉之后也留着.
+    next_worker_id : u32,
+    next_assignment_id : u64,
+}
+
+impl Membership {
+    /// 加入一个新注册的 worker daemon, 返回分给它的编号. 同一台机器之前的失败记录仍然有效.
+    pub fn join(&mut self, stream : TcpStream, capacity : u32, shuffle_host : String) -> u32 {
+        // 设置失败的话只是没有超时, 照样可以用.
+        let _ = stream.set_write_timeout(Some(COMMAND_WRITE_TIMEOUT));
+        let worker_id = self.next_worker_id;
+        self.next_worker_id += 1;
+        let identity = worker_identity(&stream, &shuffle_host);
+        if self.failures.entry(identity.clone()).or_default().blacklisted(Instant::now()) {
+            println!("Worker daemon {} at {} is still blacklisted", worker_id, identity);
+        }
+        self.members.insert(worker_id, Member {
+            capacity,
+            shuffle_host,
+            stream,
+            alive : true,
+            last_heartbeat : Instant::now(),
+            identity,
+            pending : HashMap::new(),
+        });
+        worker_id
+    }
+
+    /// 给 worker_id 所在的机器记下一次失败, 次数够了就拉黑它.
+    fn record_failure(&mut self, worker_id : u32, identity : &str) {
+        let record = self.failures.entry(identity.to_string()).or_default();
+        if record.record(Instant::now()) {
+            println!("Worker daemon {} at {} failed {} subtasks recently, blacklisted for {} s",
+                worker_id, identity, record.failures.len(), BLACKLIST_COOLDOWN.as_secs());
+        }
+    }
+
+    /// 收到了 worker 的心跳. 它发来的任何消息都算心跳.
+    pub fn heartbeat(&mut self, worker_id : u32) {
+        if let Some(member) = self.members.get_mut(&worker_id) {
+            member.last_heartbeat = Instant::now();
+        }
+    }
+
+    /// worker 报告了子任务 id 的结果 info(MasterWorkerInfo 的 json), 返回结果要发给谁.
+    /// 因为机器的问题而失败的结果记进这个 worker 的失败次数, 次数够了就拉黑它.
+    pub fn complete(&mut self, worker_id : u32, id : u64, info : &str) -> Option<Sender<String>> {
+        let member = self.members.get_mut(&worker_id)?;
+        let pending = member.pending.remove(&id)?;
+        let failure = serde_json::from_str::<MasterWorkerInfo>(info).ok().and_then(|info| info.failure);
+        if FailureRecord::counts(failure) {
+            let identity = member.identity.clone();
+            self.record_failure(worker_id, &identity);
+        }
+        Some(pending.sender)
+    }
+
+    /// 可以分配子任务(活着、没有被拉黑)的 worker 中空闲槽位最多的那个: (worker 编号, 空闲的槽位数).
+    /// 拉黑到期的 worker 在这里恢复.
+    pub fn most_free(&mut self) -> Option<(u32, u32)> {
+        let now = Instant::now();
+        for (identity, record) in self.failures.iter_mut() {
+            if record.release(now) {
+                println!("Worker daemon at {} is no longer blacklisted", identity);
+            }
+        }
+        let failures = &self.failures;
+        self.members.iter()
+            .filter(|(_, member)| member.alive && !failures.get(&member.identity).is_some_and(|record| record.blacklisted(now)))
+            .map(|(id, member)| (*id, member.free_slots()))
+            .filter(|(_, free)| *free > 0)
+            .max_by_key(|(_, free)| *free)
+    }
+
+    /// 把子任务交给 worker_id. 发送失败时这个 worker 不再参与分配, 把 sender 还回去.
+    pub fn assign(&mut self, worker_id : u32, spec : &SubtaskSpec, options : &JobOptions, sender : Sender<String>, attempt : u32)
+        -> Result<(), Sender<String>> {
+        let id = self.next_assignment_id;
+        self.next_assignment_id += 1;
+        let Some(member) = self.members.get_mut(&worker_id) else {
+            return Err(sender);
+        };
+        let mut spec = spec.clone();
+        spec.shuffle_host = member.shuffle_host.clone();
+        spec.scratch_dir = String::new();  // daemon 换成它自己的本地目录, 见 remote_worker.
+        let command = DaemonCommand::Run(Box::new(SubtaskAssignment { id, spec : spec.clone(), options : options.clone() }));
+        let line = serde_json::to_string(&command).unwrap() + "\n";
+        if member.send(worker_id, &line).is_err() {
+            return Err(sender);
+        }
+        member.pending.insert(id, PendingSubtask { spec, options : options.clone(), sender, attempt });
+        Ok(())
+    }
+
+    /// 关掉超过 HEARTBEAT_TIMEOUT 没有心跳的 worker 的连接, 读消息的线程随之结束, 由它去掉这个 worker.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        for (worker_id, member) in self.members.iter_mut() {
+            if member.alive && now.duration_since(member.last_heartbeat) > HEARTBEAT_TIMEOUT {
+                eprintln!("Worker daemon {} missed heartbeats for {} s, disconnecting", worker_id,
+                    now.duration_since(member.last_heartbeat).as_secs());
+                member.alive = false;
+                let _ = member.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// 去掉一个 worker daemon, 返回它还没有完成的子任务. 已经去掉了的话返回空的.
+    /// 有子任务因此失败(FailureReason::WorkerLost)的话, 先给它的机器记下一次失败.
+    pub fn leave(&mut self, worker_id : u32) -> Vec<PendingSubtask> {
+        let Some(member) = self.members.remove(&worker_id) else {
+            return Vec::new();
+        };
+        if !member.pending.is_empty() {
+            self.record_failure(worker_id, &member.identity);
+        }
+        member.pending.into_values().collect()
+    }
+
+    /// 通知所有 worker daemon 卸载任务 task_id 的插件.
+    pub fn evict_task(&mut self, task_id : u32) {
+        let line = serde_json::to_string(&DaemonCommand::Evict(task_id)).unwrap() + "\n";
+        for (worker_id, member) in self.members.iter_mut() {
+            // 写不进去的 worker 的连接已经关掉, 读消息的线程会发现并去掉它.
+            if member.alive {
+                let _ = member.send(*worker_id, &line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_infrastructure_failures_count() {
+        assert!(FailureRecord::counts(Some(FailureReason::Crashed)));
+        assert!(FailureRecord::counts(Some(FailureReason::WorkerLost)));
+        assert!(!FailureRecord::counts(Some(FailureReason::Error)));
+        assert!(!FailureRecord::counts(Some(FailureReason::Panicked)));
+        assert!(!FailureRecord::counts(None));
+    }
+
+    #[test]
+    fn blacklists_after_enough_failures_in_the_window() {
+        let start = Instant::now();
+        let mut record = FailureRecord::default();
+        for i in 1..FAILURES_TO_BLACKLIST {
+            assert!(!record.record(start + Duration::from_secs(i as u64)));
+        }
+        let now = start + Duration::from_secs(FAILURES_TO_BLACKLIST as u64);
+        assert!(record.record(now));
+        assert!(record.blacklisted(now));
+        // 已经拉黑的时候再失败不会延长拉黑.
+        assert!(!record.record(now + Duration::from_secs(1)));
+        assert_eq!(record.blacklisted_until, Some(now + BLACKLIST_COOLDOWN));
+    }
+
+    #[test]
+    fn old_failures_leave_the_window() {
+        let start = Instant::now();
+        let mut record = FailureRecord::default();
+        for i in 1..FAILURES_TO_BLACKLIST {
+            record.record(start + Duration::from_secs(i as u64));
+        }
+        let later = start + FAILURE_WINDOW + Duration::from_secs(FAILURES_TO_BLACKLIST as u64 + 1);
+        assert!(!record.record(later));
+        assert_eq!(record.failures.len(), 1);
+    }
+
+    #[test]
+    fn blacklist_expires_after_the_cooldown() {
+        let start = Instant::now();
+        let mut record = FailureRecord::default();
+        for _ in 0..FAILURES_TO_BLACKLIST {
+            record.record(start);
+        }
+        assert!(!record.release(start + BLACKLIST_COOLDOWN - Duration::from_secs(1)));
+        assert!(record.blacklisted(start + BLACKLIST_COOLDOWN - Duration::from_secs(1)));
+        assert!(record.release(start + BLACKLIST_COOLDOWN));
+        assert!(!record.blacklisted(start + BLACKLIST_COOLDOWN));
+        assert!(record.failures.is_empty());
+        // 解除之后重新计数.
+        assert!(!record.record(start + BLACKLIST_COOLDOWN));
+    }
+
+    #[test]
+    fn failures_survive_rejoining() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let spec : SubtaskSpec = serde_json::from_str(r#"{"kind":"Mapper","task_id":0,"subtask_id":0,
+            "base_dir":"","inputpath":"","dllpath":"","reducer_num":0}"#).unwrap();
+        let (sender, _receiver) = std::sync::mpsc::channel();
+        let mut membership = Membership::default();
+        // 同一台机器上的 daemon 每次带着子任务死掉, 重新注册之后拿到新的编号.
+        for _ in 0..FAILURES_TO_BLACKLIST {
+            let worker_id = membership.join(TcpStream::connect(addr).unwrap(), 1, String::from("host:1"));
+            assert_eq!(membership.most_free(), Some((worker_id, 1)));
+            assert!(membership.assign(worker_id, &spec, &JobOptions::default(), sender.clone(), 1).is_ok());
+            assert_eq!(membership.leave(worker_id).len(), 1);
+        }
+        let worker_id = membership.join(TcpStream::connect(addr).unwrap(), 1, String::from("host:1"));
+        assert_eq!(membership.most_free(), None);
+        // 别的机器不受影响.
+        let other = membership.join(TcpStream::connect(addr).unwrap(), 1, String::from("host:2"));
+        assert_eq!(membership.most_free(), Some((other, 1)));
+        assert!(membership.leave(worker_id).is_empty());
+    }
+}
@@ -1,6 +1,17 @@
 mod masters;
 mod workers;
+mod spill;
+mod scheduler;
+mod membership;
+mod nodes;
+mod shuffle;
+mod splits;
+pub(crate) mod process_worker;
+pub(crate) mod remote_worker;
+#[cfg(test)]
+mod test_dir;
 
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 enum Status{
     Waiting,
     Executing,
@@ -9,63 +20,116 @@ enum Status{
 }
 
 use std::{
+    ffi::OsStr,
     fs,
     io::{prelude::*, BufReader, read_to_string},
     net::{TcpListener, TcpStream},
+    path::Path,
     thread::{self, Thread},
     sync::{Arc, Mutex},
     time::Duration,
-    collections::HashMap, hash::Hash,
+    collections::{BTreeMap, HashMap}, hash::Hash,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::map::Entry;
 
-use crate::{thread_poll::ThreadPoll, io_wrapper::{iowrapper_create_dir, iowrapper_get_absolute_path, path_join, iowrapper_exist, iowrapper_remove_dir_all, HdfsSetting, iowrapper_read_dir_into_strings, iowrapper_copy_file, iowrapper_get_filename}};
-use crate::map_reduce::MessagePacket;
-use crate::map_reduce_server::masters::Master;
+use crate::{thread_poll::ThreadPoll, io_wrapper::{iowrapper_create_dir, iowrapper_get_absolute_path, path_join, iowrapper_exist, iowrapper_remove_dir_all, HdfsSetting, iowrapper_read_dir_into_strings, iowrapper_copy_file, iowrapper_get_filename, iowrapper_read_to_string, iowrapper_write_file_all}};
+use crate::map_reduce::{MessagePacket, JobOptions, JobConfig, Counters, CACHE_DIR, CACHE_FILE_SUFFIX, read_packet};
+use crate::map_reduce_server::masters::{Master, CHECKPOINT_FILE};
+use crate::map_reduce_server::scheduler::Scheduler;
+use crate::map_reduce_server::nodes::LocalNode;
+use crate::map_reduce_server::shuffle::start_shuffle_service;
 use crate::error::MapReduceError;
+use crate::plugin::PluginKind;
+use crate::plugin::streaming::StreamingManifest;
 
+/// server 开始接受连接、worker daemon 注册完之后各打印一行以它开头的消息, 给启动它们的程序(见 local_cluster)看:
+/// server 打印 `MAPREDUCE_READY {地址}`, worker daemon 打印 `MAPREDUCE_READY {编号} {shuffle 服务的地址}`.
+pub const READY_MARKER : &str = "MAPREDUCE_READY";
 
 pub struct MapReduceServer{
     host : String,
     listener : TcpListener,
     master_poll : ThreadPoll,
-    worker_poll : Arc<Mutex<ThreadPoll>>,  // 共享所有权..
+    scheduler : Arc<Scheduler>,  // 本地的worker线程池与注册过的 worker daemon, 所有master共享.
     task_id_count : u32,     // 累增计数，用来分配task_id.
     task_map : HashMap<u32, TaskEntry>,  // 用Hashmap实现id到task的O(1)访问.
 }
 
+/// 任务表项的记录文件, 在任务的 base_dir 下. client 准备好之后写下, server 重启之后据此恢复没有完成的任务.
+const TASK_RECORD_FILE : &str = "task.json";
+
+#[derive(Deserialize, Serialize)]
 struct TaskEntry{
     pub task_id : u32,
     pub hdfs_base_dir : String,
     pub task_base_dir : String,  // 该任务数据文件所在的基本目录
-    pub input_dir : String,    // 输入文件所在的dir
-    pub dll_path : String,     // 该任务的dllpath所在的路径，这三个都是server分配的.
+    pub input_files : String,  // 输入文件, 用|分隔. client 在准备好的消息中给出, 可能在hdfs上, mapper 就地读.
+    pub dll_path : String,     // 该任务的dllpath所在的路径，这两个都是server分配的.
     pub result_path : Option<String>,  // 所有结果文件的路径, 用|分隔. 最开始可能没有.
     pub mapper_num : u32,
     pub reducer_num : u32,
+    pub options : JobOptions,   // client给出的任务选项, 比如子任务的执行方式.
+    pub config : JobConfig,     // client给出的任务配置参数, 交给用户代码.
     pub status : Status,
+    #[serde(skip)]
     pub stream : Option<TcpStream>,  // 用来保存与Client对话用的tcpstream的,可能变更.
     // 在收到master报告任务完毕之后，也会暂存master的stream直到这里.
+    #[serde(skip)]
+    pub client_reply : Option<String>,  // 任务完成后发给client的type 5消息, client 重新连上来(type 13)时再发一次.
 }
 
 impl MapReduceServer {
+    /// host 的端口可以是 0, 由系统分配, 实际的地址见打印出来的 READY_MARKER 一行.
     pub fn new(host:&str, master_num:usize, worker_num:usize) -> MapReduceServer{
         let listener = TcpListener::bind(host).unwrap(); // 不处理错误.
+        let host = listener.local_addr().unwrap().to_string();
         let worker_poll = ThreadPoll::new(worker_num);
+        // 本机的 shuffle 服务和server用同一个ip. 任务的文件夹都在当前目录下(./{task_id}/), mapper 的输出也在其中.
+        let ip = listener.local_addr().unwrap().ip();
+        let shuffle_host = start_shuffle_service(ip, "./").unwrap();  // 同样不处理错误.
         let master_poll = ThreadPoll::new(master_num);
-        println!("MapReduce server with {} masters and {} workers at {}",
-                    master_num, worker_num, host);
+        println!("MapReduce server with {} masters and {} workers at {}, shuffle service at {}",
+                    master_num, worker_num, host, shuffle_host);
         MapReduceServer { 
-            host : String::from(host),
+            host,
             listener: (listener),
             master_poll,
-            worker_poll : Arc::new(Mutex::new(worker_poll)),
+            scheduler : Scheduler::new(vec![LocalNode::server(worker_poll, shuffle_host)]),
+            task_id_count : 0,
+            task_map : HashMap::new(),
+        }
+    }
+
+    /// 模拟模式的server: worker线程分成 node_num 个虚拟节点, 每个节点 worker_num 个线程,
+    /// 有自己的本地目录(./nodes/node{id}/)和 shuffle 服务. 节点可以用 type 11 的消息杀掉, 见 nodes.
+    pub fn new_simulated(host:&str, master_num:usize, node_num:usize, worker_num:usize) -> MapReduceServer{
+        let listener = TcpListener::bind(host).unwrap(); // 不处理错误.
+        let host = listener.local_addr().unwrap().to_string();
+        let ip = listener.local_addr().unwrap().ip();
+        let nodes = LocalNode::simulated_nodes(node_num, worker_num, "./nodes/", ip).unwrap();  // 同样不处理错误.
+        let master_poll = ThreadPoll::new(master_num);
+        println!("MapReduce server with {} masters and {} simulated nodes of {} workers at {}",
+                    master_num, node_num, worker_num, host);
+        for node in &nodes {
+            println!("Node {} : shuffle service at {}, local files in {}", node.id(), node.shuffle_host(), node.scratch_dir());
+        }
+        MapReduceServer {
+            host,
+            listener,
+            master_poll,
+            scheduler : Scheduler::new(nodes),
             task_id_count : 0,
             task_map : HashMap::new(),
         }
     }
 
     pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>>{
+        // 先接着执行上一次没有完成的任务.
+        if let Err(e) = self.resume_tasks() {
+            eprintln!("Cannot resume unfinished tasks : {}", e);
+        }
+        println!("{} {}", READY_MARKER, self.host);
         // 注意，下面这个for .. in self.listener借用了self中的listener字段;
         // 而后面的handle_... 借用了整个self，从签名来看，包括里面的listener(虽然实际上没有)
         // 而编译器单独编译各个函数，只看函数签名，所以“发现”了这个重复引用.
@@ -79,15 +143,9 @@ impl MapReduceServer {
                 }
             };
             if let Some(mut stream) = stream{
-                // read_to_string 要一直读到eof... 不知道怎么发送EOF，所以用read+定长buf吧...
-                let mut buf = [0;1024];
-                let length = stream.read(&mut buf);
-                if length.is_err(){
-                    eprintln!("{}", length.err().unwrap());
-                    continue;
-                }
-                let length = length.unwrap();
-                let packet = serde_json::from_slice::<MessagePacket>(&buf[0..length]);
+                // read_to_string 要一直读到eof, 而对方发完消息之后还要等回复, 不会关闭连接,
+                // 所以只读到一个完整的json对象为止.
+                let packet = read_packet(&mut stream);
                 match packet {
                     Ok(packet) => {
                         // 判断packet类型 用数组的方式存函数可能更聪明..
@@ -116,6 +174,21 @@ impl MapReduceServer {
                                 eprintln!("{}", e);
                             }
                         }
+                        else if packet.message_type == 9 {
+                            if let Err(e) = self.scheduler.register_remote(stream, packet.capacity, packet.data_file){
+                                eprintln!("{}", e);
+                            }
+                        }
+                        else if packet.message_type == 11 {
+                            if let Err(e) = self.handle_type_11_kill_node(stream, packet){
+                                eprintln!("{}", e);
+                            }
+                        }
+                        else if packet.message_type == 13 {
+                            if let Err(e) = self.handle_type_13_client_reattach(stream, packet){
+                                eprintln!("{}", e);
+                            }
+                        }
                     }
                     Err(e) => {
                         eprintln!("{}",e);
@@ -149,32 +222,30 @@ impl MapReduceServer {
         }
         iowrapper_create_dir(&base_dir)?;
         let base_dir = iowrapper_get_absolute_path(&base_dir)?;  // 变成绝对路径.
-        let input_dir = path_join(&base_dir, &String::from("rawinput/"));
-        // 记得创建这个rawinput目录..
-        iowrapper_create_dir(&input_dir)?;
         let dll_path = path_join(&base_dir, &String::from("uesr_mapreduce.dll"));
         // 创建一个TaskEntry
         let taskentry = TaskEntry{
             task_id,
             hdfs_base_dir,
             task_base_dir : base_dir,
-            input_dir : input_dir.clone(),
+            input_files : String::new(),
             dll_path : dll_path.clone(),
             result_path : None,
             mapper_num : packet.mapper_num,
             reducer_num : packet.reducer_num,
+            options : packet.options.clone(),
+            config : packet.config.clone(),
             status : Status::Waiting,
             stream : None,
+            client_reply : None,
         };
         // 形成发回的数据包.
         let message = MessagePacket{
             message_type : 4,   // 通知，已经分配任务, 编号为4.
-            from : 0,   // 无用.
-            task_id : task_id,
+            task_id,
             data_file : taskentry.hdfs_base_dir.clone(),   // client 把数据文件放在这里, 这是个文件夹.
             dll_file : path_join(&taskentry.hdfs_base_dir, &String::from("uesr_mapreduce.dll")),  // 把dll放在这里, 这是个文件名，直接复制到这个文件名即可.
-            mapper_num : packet.mapper_num,  // 无用
-            reducer_num : packet.reducer_num,  // 无用.
+            ..MessagePacket::default()
         };
         // 存储任务表项
         self.task_map.insert(task_id, taskentry);
@@ -197,38 +268,104 @@ impl MapReduceServer {
         }
 
         let entry = entry.unwrap();
+        // 输入文件不复制, master 把它们切成若干段, mapper 直接读.
+        for input_file in packet.data_file.split('|') {
+            if !iowrapper_exist(&input_file.to_string()) {
+                return Err(format!("input file {} of task {} does not exist", input_file, task_id).into());
+            }
+        }
+        entry.input_files = packet.data_file;
         entry.stream = Some(stream);
 
-        // 将hdfs中的文件复制到“本地”，entry.input_dir中.
+        // 将hdfs中的dll等文件复制到“本地”. 输入文件在hdfs上的 INPUT_DIR 中, 不用复制.
+        let cache_dir = path_join(&entry.task_base_dir, &CACHE_DIR.to_string());
         for f_hdfspath in iowrapper_read_dir_into_strings(&entry.hdfs_base_dir)? {
-            if f_hdfspath.ends_with(".dll") {
+            if f_hdfspath.ends_with(CACHE_FILE_SUFFIX) {
+                // 附属文件, 去掉后缀放到任务目录下的 cache 文件夹, 设为只读. 整个任务共用这一份.
+                if !iowrapper_exist(&cache_dir) {
+                    iowrapper_create_dir(&cache_dir)?;
+                }
+                let fname = iowrapper_get_filename(&f_hdfspath)?;
+                let to = path_join(&cache_dir, &fname.strip_suffix(CACHE_FILE_SUFFIX).unwrap_or(&fname).to_string());
+                iowrapper_copy_file(&f_hdfspath, &to)?;
+                let mut permissions = fs::metadata(&to)?.permissions();
+                permissions.set_readonly(true);
+                fs::set_permissions(&to, permissions)?;
+            }
+            else if f_hdfspath.ends_with(".dll") {
                 // 是那个dll, 复制到 entry.dllpath
                 iowrapper_copy_file(&f_hdfspath, &entry.dll_path)?;
             }
-            else {
-                let fname = iowrapper_get_filename(&f_hdfspath)?;
-                let to = path_join(&entry.input_dir, &fname);
-                iowrapper_copy_file(&f_hdfspath, &to)?;
+        }
+        // streaming 任务的 dll 是一个 manifest, 把其中写的 mapper/reducer 可执行文件复制到和它相同的目录.
+        if matches!(PluginKind::detect(&entry.dll_path)?, PluginKind::Streaming) {
+            let manifest : StreamingManifest = serde_json::from_str(&iowrapper_read_to_string(&entry.dll_path)?)?;
+            for (fntype, fname) in [("mapper", &manifest.mapper), ("reducer", &manifest.reducer)] {
+                // 只能是 hdfs_base_dir 下的文件名, 不能指到别的地方去.
+                if Path::new(fname).file_name() != Some(OsStr::new(fname)) {
+                    return Err(Box::new(MapReduceError::DllLoadingError {
+                        fntype : fntype.to_string(),
+                        reason : format!("{} is not a file name in the task directory", fname),
+                    }));
+                }
+                iowrapper_copy_file(&path_join(&entry.hdfs_base_dir, fname), &path_join(&entry.task_base_dir, fname))?;
             }
         }
-        // 扔给 master_poll 一个master线程
+        // 记下任务表项, server 重启之后从这里恢复.
+        let record = path_join(&entry.task_base_dir, &TASK_RECORD_FILE.to_string());
+        iowrapper_write_file_all(&record, &serde_json::to_string(entry)?)?;
+        entry.status = Status::Executing;
+        self.start_master(task_id);
+        Ok(())
+    }
+
+    /// 扔给 master_poll 一个执行任务 task_id 的master线程.
+    fn start_master(&self, task_id : u32) {
+        let entry = &self.task_map[&task_id];
         let task_id = entry.task_id;
         let m = entry.mapper_num;
         let n = entry.reducer_num;
         let base_dir = entry.task_base_dir.clone();
-        let inputpath = entry.input_dir.clone();
+        let inputpath = entry.input_files.clone();
         let dllpath = entry.dll_path.clone();
+        let options = entry.options.clone();
+        let config = entry.config.clone();
         let server_host = self.host.clone();
-        let worker_poll = Arc::clone(&self.worker_poll);
-        // 上面这条代码增加一个互斥的共享引用，Arc::clone克隆的是那个引用!
+        let scheduler = Arc::clone(&self.scheduler);
+        // 上面这条代码增加一个共享引用，Arc::clone克隆的是那个引用!
         self.master_poll.execute(move || {
             Master::master_thread(
-                task_id, m, n, base_dir, inputpath, dllpath, 
-                server_host, worker_poll
+                task_id, m, n, base_dir, inputpath, dllpath, options, config,
+                server_host, scheduler
             );
         });
+    }
 
-        entry.status = Status::Executing;
+    /// 恢复上一次运行时没有完成的任务: 当前目录下每个有任务记录的任务目录都是一个,
+    /// master 从检查点继续(还没有检查点的话从头开始). 新的任务编号从已有的之后开始.
+    /// client 可以用 type 13 的消息重新连上来等结果, 见 Client::reattach.
+    fn resume_tasks(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for dir in fs::read_dir(".")? {
+            let record = dir?.path().join(TASK_RECORD_FILE);
+            if !record.is_file() {
+                continue;
+            }
+            let record = record.to_string_lossy().to_string();
+            let entry : TaskEntry = match iowrapper_read_to_string(&record).map_err(|e| e.to_string())
+                .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string())) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    eprintln!("Skipping the broken task record {} : {}", record, e);
+                    continue;
+                }
+            };
+            let task_id = entry.task_id;
+            let checkpointed = iowrapper_exist(&path_join(&entry.task_base_dir, &CHECKPOINT_FILE.to_string()));
+            println!("Resuming task {} {}", task_id, if checkpointed { "from its checkpoint" } else { "from the beginning" });
+            self.task_id_count = self.task_id_count.max(task_id + 1);
+            self.task_map.insert(task_id, entry);
+            self.start_master(task_id);
+        }
         Ok(())
     }
 
@@ -280,20 +417,38 @@ impl MapReduceServer {
         }
         let hdfs_ret_filepaths = hdfs_ret_filepaths.trim_end_matches('|').to_string();
 
+        // 命名输出放在hdfs_base_dir/outputs/{name}/下, 同样以|分隔.
+        let mut hdfs_named_outputs = BTreeMap::new();
+        for (name, local_ret_paths) in &packet.named_outputs {
+            let hdfs_dir = path_join(&entry.hdfs_base_dir, &format!("outputs/{}/", name));
+            if !iowrapper_exist(&hdfs_dir) {
+                iowrapper_create_dir(&hdfs_dir)?;
+            }
+            let mut hdfs_paths = Vec::new();
+            for local_ret_path in local_ret_paths.split('|') {
+                let local_ret_fname = iowrapper_get_filename(&local_ret_path.to_string())?;
+                let hdfs_ret_path = path_join(&hdfs_dir, &local_ret_fname);
+                iowrapper_copy_file(&local_ret_path.to_string(), &hdfs_ret_path)?;
+                hdfs_paths.push(hdfs_ret_path);
+            }
+            hdfs_named_outputs.insert(name.clone(), hdfs_paths.join("|"));
+        }
+
         let message = MessagePacket {
             message_type : 5,   // 告知client任务已经完成，结果文件已经准备好.
-            from : 0,
             task_id : packet.task_id,
             data_file : hdfs_ret_filepaths,  // 这个就是|分隔的所有结果文件. 不过在hdfs上
-            dll_file : String::new(),   // 无用
-            mapper_num : 0,  // 无用.
-            reducer_num : 0,  // 无用
+            named_outputs : hdfs_named_outputs,  // 命名输出的结果文件, 同样在hdfs上.
+            counters : packet.counters,  // 整个任务的计数器.
+            splits : packet.splits,  // 输入切成的各段.
+            ..MessagePacket::default()
         };
         let json_str = serde_json::to_string(&message)?;
+        // server 重启过的话 client 还没有重新连上来, 没有它的stream. 回复留着, client 重新连上来(type 13)时再发.
         if let Some(mut client_stream) = entry.stream.take() {
-            // 一定有，不可能没有.
             client_stream.write_all(json_str.as_bytes())?;
         }
+        entry.client_reply = Some(json_str);
         entry.stream = Some(stream);
         Ok(())
     }
@@ -312,12 +467,10 @@ impl MapReduceServer {
         let entry = entry.unwrap();
         let message = MessagePacket{
             message_type : 5,
-            from :0,
             task_id : packet.task_id,
-            data_file : String::new(),
+            data_file : String::new(),   // 没有结果文件, client 据此知道任务失败了.
             dll_file : packet.dll_file,
-            mapper_num : 0,
-            reducer_num : 0
+            ..MessagePacket::default()
         };
         let json_str = serde_json::to_string(&message)?;
         // 通知client出错了.
@@ -331,6 +484,48 @@ impl MapReduceServer {
         Ok(())
     }
 
+    /// 处理信号11, 杀掉模拟的节点 packet.from. 回复 type 12, 失败时 dll_file 是错误信息, 成功时为空.
+    fn handle_type_11_kill_node(&mut self, mut stream:TcpStream, packet:MessagePacket)
+        -> Result<(), Box<dyn std::error::Error>>
+    {
+        let error = match self.scheduler.kill_node(packet.from) {
+            Ok(()) => String::new(),
+            Err(e) => e.to_string(),
+        };
+        let message = MessagePacket{
+            message_type : 12,
+            from : packet.from,
+            dll_file : error,
+            ..MessagePacket::default()
+        };
+        stream.write_all(serde_json::to_string(&message)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// 处理信号13, client 重新连上来等任务 packet.task_id 的结果(比如server重启之后). 任务已经完成的话直接回复 type 5,
+    /// 否则和 type 2 一样留下这个连接, 任务完成时回复. server 不知道这个任务时回复失败的 type 5.
+    fn handle_type_13_client_reattach(&mut self, mut stream:TcpStream, packet:MessagePacket)
+        -> Result<(), Box<dyn std::error::Error>>
+    {
+        let Some(entry) = self.task_map.get_mut(&packet.task_id) else {
+            let message = MessagePacket{
+                message_type : 5,
+                task_id : packet.task_id,
+                data_file : String::new(),   // 没有结果文件, client 据此知道出错了.
+                dll_file : format!("task {} is unknown to the server", packet.task_id),
+                ..MessagePacket::default()
+            };
+            stream.write_all(serde_json::to_string(&message)?.as_bytes())?;
+            return Err(Box::new(MapReduceError::WrongTaskId));
+        };
+        // 任务完成之后 entry.stream 是master的, 不能换掉.
+        match &entry.client_reply {
+            Some(json_str) => stream.write_all(json_str.as_bytes())?,
+            None => entry.stream = Some(stream),
+        }
+        Ok(())
+    }
+
     pub fn hdfs_root_dir() -> &'static str {
         "/DS2023"
     }
@@ -0,0 +1,127 @@
+// server 进程中执行子任务的本地节点. 一般只有一个节点, 就是 server 自己的 worker 线程池.
+// 模拟模式下 worker 线程被分成若干个虚拟节点, 每个节点有自己的线程池、本地目录(scratch)和 shuffle 服务:
+// 在节点上执行的 mapper 把中间文件写在节点的本地目录中, reducer 通过 shuffle 服务取.
+// 节点可以被"杀掉"(见 Scheduler::kill_node): 本地目录被删除, shuffle 服务不再回复, 正在执行的子任务的结果作废.
+// 这样在一台机器上就能测试 mapper 输出丢失、子任务重新执行等容错行为.
+use std::{
+    net::IpAddr,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex},
+};
+
+use crate::io_wrapper::*;
+use crate::map_reduce_server::shuffle::start_gated_shuffle_service;
+use crate::thread_poll::ThreadPoll;
+
+/// 一个本地节点.
+pub struct LocalNode {
+    id : u32,
+    pool : Mutex<ThreadPoll>,
+    running : Arc<AtomicUsize>,   // 已经分配、还没有结束的子任务数.
+    scratch_dir : String,         // 节点的本地目录; 为空时不是模拟的节点, 中间文件放在任务的 base_dir 下.
+    shuffle_host : String,        // 节点的 shuffle 服务的地址.
+    alive : Arc<AtomicBool>,
+}
+
+impl LocalNode {
+    /// server 自己的 worker 线程池作为唯一的节点, shuffle_host 是 server 的 shuffle 服务.
+    pub fn server(pool : ThreadPoll, shuffle_host : String) -> LocalNode {
+        LocalNode {
+            id : 0,
+            pool : Mutex::new(pool),
+            running : Arc::new(AtomicUsize::new(0)),
+            scratch_dir : String::new(),
+            shuffle_host,
+            alive : Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// 模拟的节点 id: 有 worker_num 个 worker 线程, 本地目录是 scratch_dir(不存在就创建), 在 ip 上开自己的 shuffle 服务.
+    pub fn simulated(id : u32, worker_num : usize, scratch_dir : String, ip : IpAddr) -> Result<LocalNode, Box<dyn std::error::Error>> {
+        if !iowrapper_exist(&scratch_dir) {
+            iowrapper_create_dir(&scratch_dir)?;
+        }
+        let scratch_dir = iowrapper_get_absolute_path(&scratch_dir)?;
+        let alive = Arc::new(AtomicBool::new(true));
+        let shuffle_host = start_gated_shuffle_service(ip, &scratch_dir, Arc::clone(&alive))?;
+        Ok(LocalNode {
+            id,
+            pool : Mutex::new(ThreadPoll::new(worker_num)),
+            running : Arc::new(AtomicUsize::new(0)),
+            scratch_dir,
+            shuffle_host,
+            alive,
+        })
+    }
+
+    /// 模拟模式: node_num 个节点, 每个有 worker_num 个 worker 线程, 本地目录是 scratch_root/node{id}/.
+    pub fn simulated_nodes(node_num : usize, worker_num : usize, scratch_root : &str, ip : IpAddr)
+        -> Result<Vec<LocalNode>, Box<dyn std::error::Error>> {
+        let scratch_root = scratch_root.to_string();
+        if !iowrapper_exist(&scratch_root) {
+            iowrapper_create_dir(&scratch_root)?;
+        }
+        (0..node_num as u32)
+            .map(|id| LocalNode::simulated(id, worker_num, path_join(&scratch_root, &format!("node{}/", id)), ip))
+            .collect()
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn scratch_dir(&self) -> &str {
+        &self.scratch_dir
+    }
+
+    pub fn shuffle_host(&self) -> &str {
+        &self.shuffle_host
+    }
+
+    pub fn alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    /// 空闲的线程数, 排队的子任务比线程多时是负数.
+    pub fn free_slots(&self) -> i64 {
+        self.pool.lock().unwrap().capacity() as i64 - self.running.load(Ordering::SeqCst) as i64
+    }
+
+    /// 在节点的线程池中执行 f.
+    pub fn execute<F>(&self, f : F) where F : FnOnce() + Send + 'static {
+        let running = Arc::clone(&self.running);
+        running.fetch_add(1, Ordering::SeqCst);
+        // 别的线程在拿着线程池的时候死掉了，会返回一个error(但同样获取了mutex). ——暂时不管.
+        self.pool.lock().unwrap().execute(move || {
+            f();
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    /// 杀掉模拟的节点: 不再分配子任务, shuffle 服务不再回复, 删除本地目录中的所有文件.
+    pub fn kill(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.scratch_dir.is_empty() {
+            return Err(format!("node {} is not a simulated node", self.id).into());
+        }
+        if !self.alive.swap(false, Ordering::SeqCst) {
+            return Err(format!("node {} is already dead", self.id).into());
+        }
+        if iowrapper_exist(&self.scratch_dir) {
+            iowrapper_remove_dir_all(&self.scratch_dir)?;
+        }
+        println!("Node {} is killed, its local files are gone", self.id);
+        Ok(())
+    }
+
+    /// 删除节点本地目录中任务 task_id 的文件.
+    pub fn remove_task_files(&self, task_id : u32) {
+        if self.scratch_dir.is_empty() || !self.alive() {
+            return;
+        }
+        let task_dir = path_join(&self.scratch_dir, &format!("{}/", task_id));
+        if iowrapper_exist(&task_dir) {
+            if let Err(e) = iowrapper_remove_dir_all(&task_dir) {
+                eprintln!("Cannot remove files of task {} on node {} : {}", task_id, self.id, e);
+            }
+        }
+    }
+}
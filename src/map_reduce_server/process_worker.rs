@@ -0,0 +1,241 @@
+// 在单独的worker子进程中执行mapper/reducer子任务.
+// worker线程启动子进程(mapreduce_worker), 通过stdin把SubtaskSpec的json发过去,
+// 子进程执行完之后在stdout上输出一行 RESULT_PREFIX + MasterWorkerInfo的json.
+// 子进程崩溃(比如插件破坏了内存、abort)的话只会让这一次子任务失败, server进程不受影响.
+// 任务设置了SubtaskLimits的话, 子进程受到cpu时间、地址空间、墙钟时间的限制, 超过限制会报告相应的FailureReason.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::BTreeMap,
+    env,
+    io::{prelude::*, BufReader},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::PathBuf,
+    process::{Child, Command, Stdio, ExitStatus},
+    sync::mpsc::{channel, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::map_reduce::{SubtaskLimits, Counters};
+use crate::plugin::cache::evict_task;
+use crate::map_reduce_server::masters::{MasterWorkerInfo, FailureReason};
+use crate::map_reduce_server::workers::{SubtaskSpec, run_subtask};
+
+/// 子进程输出结果的那一行的前缀, 用来和用户代码自己打印的内容区分开.
+const RESULT_PREFIX : &str = "MAPREDUCE_RESULT:";
+/// worker子进程中内存分配失败(超过了地址空间的限制)时的退出码, 见 WorkerAllocator.
+const ALLOCATION_FAILED_EXIT_CODE : i32 = 86;
+
+/// mapreduce_worker 的全局分配器: 与 System 相同, 只是分配失败时直接以 ALLOCATION_FAILED_EXIT_CODE 退出,
+/// server 根据退出码判断子任务超过了内存限制, 不必去猜 stderr 上的内容. \
+/// 插件自己的标准库(cdylib)中的分配失败不经过这里, 它们仍然是 abort, 报告为 Crashed.
+pub struct WorkerAllocator;
+
+impl WorkerAllocator {
+    fn check(ptr : *mut u8) -> *mut u8 {
+        if ptr.is_null() {
+            // 这里不能再分配内存, 也不能 panic, 直接退出.
+            unsafe { libc::_exit(ALLOCATION_FAILED_EXIT_CODE) }
+        }
+        ptr
+    }
+}
+
+unsafe impl GlobalAlloc for WorkerAllocator {
+    unsafe fn alloc(&self, layout : Layout) -> *mut u8 {
+        Self::check(System.alloc(layout))
+    }
+
+    unsafe fn alloc_zeroed(&self, layout : Layout) -> *mut u8 {
+        Self::check(System.alloc_zeroed(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr : *mut u8, layout : Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr : *mut u8, layout : Layout, new_size : usize) -> *mut u8 {
+        Self::check(System.realloc(ptr, layout, new_size))
+    }
+}
+
+/// worker子进程可执行文件的位置: 优先使用环境变量 MAPREDUCE_WORKER_BIN,
+/// 否则是和当前可执行文件在同一个目录下的 mapreduce_worker.
+fn worker_binary() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Ok(path) = env::var("MAPREDUCE_WORKER_BIN") {
+        return Ok(PathBuf::from(path));
+    }
+    let exe = env::current_exe()?;
+    let dir = exe.parent().ok_or("cannot find the directory of current executable")?;
+    Ok(dir.join("mapreduce_worker"))
+}
+
+/// 在worker子进程中执行一个子任务, 结果(MasterWorkerInfo的json)通过sender发回.
+/// 子进程没能给出结果时(崩溃、超过资源限制被杀掉等), 由这里生成一个失败的MasterWorkerInfo.
+pub fn run_in_process(spec : SubtaskSpec, limits : &SubtaskLimits, sender : Sender<String>) {
+    let info = match do_run_in_process(&spec, limits) {
+        Ok(info) => info,
+        Err((reason, e)) => {
+            eprintln!("Worker process for subtask {} of task {} failed : {}", spec.subtask_id, spec.task_id, e);
+            let err_info = MasterWorkerInfo {
+                subtask_id : spec.subtask_id,
+                successed : false,
+                result_path : e,
+                failure : Some(reason),
+                named_outputs : BTreeMap::new(),
+                counters : Counters::new(),
+            };
+            serde_json::to_string(&err_info).unwrap()
+        }
+    };
+    sender.send(info).unwrap();
+}
+
+/// 子进程跑起来之前出的错, 都算普通错误.
+fn error<E : std::fmt::Display>(e : E) -> (FailureReason, String) {
+    (FailureReason::Error, e.to_string())
+}
+
+/// 在子进程中(exec之前)设置cpu时间与地址空间的rlimit.
+/// cpu时间到了软限制会收到SIGXCPU, 硬限制多给一秒, 到了直接SIGKILL.
+fn set_rlimits(cpu_time_secs : Option<u64>, memory_bytes : Option<u64>) -> std::io::Result<()> {
+    unsafe {
+        if let Some(secs) = cpu_time_secs {
+            let limit = libc::rlimit { rlim_cur : secs as libc::rlim_t, rlim_max : secs.saturating_add(1) as libc::rlim_t };
+            if libc::setrlimit(libc::RLIMIT_CPU, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if let Some(bytes) = memory_bytes {
+            let limit = libc::rlimit { rlim_cur : bytes as libc::rlim_t, rlim_max : bytes as libc::rlim_t };
+            if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn do_run_in_process(spec : &SubtaskSpec, limits : &SubtaskLimits) -> Result<String, (FailureReason, String)> {
+    let mut command = Command::new(worker_binary().map_err(error)?);
+    command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let (cpu_time_secs, memory_bytes) = (limits.cpu_time_secs, limits.memory_bytes);
+    if cpu_time_secs.is_some() || memory_bytes.is_some() {
+        // pre_exec 中只调用 setrlimit, 它是 async-signal-safe 的.
+        unsafe {
+            command.pre_exec(move || set_rlimits(cpu_time_secs, memory_bytes));
+        }
+    }
+    let spec_json = serde_json::to_string(spec).map_err(error)?;
+    let mut child = command.spawn().map_err(error)?;
+    // 写完之后drop掉stdin, 子进程那边就能读到EOF. 写不进去的话杀掉子进程并回收, 不留下僵尸进程.
+    if let Err(e) = child.stdin.take().unwrap().write_all(spec_json.as_bytes()) {
+        let _ = child.kill();
+        let _ = wait_child(&child, false);
+        return Err(error(e));
+    }
+
+    // stdout中找结果那一行, 其余的(包括用户代码自己的输出)原样转发.
+    let stdout = child.stdout.take().unwrap();
+    let stdout_reader = thread::spawn(move || {
+        let mut result = None;
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            match line.strip_prefix(RESULT_PREFIX) {
+                Some(info) => result = Some(info.to_string()),
+                None => println!("{}", line),
+            }
+        }
+        result
+    });
+    // stderr同样转发.
+    let stderr = child.stderr.take().unwrap();
+    let stderr_reader = thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            eprintln!("{}", line);
+        }
+    });
+
+    // 等待子进程结束, 有墙钟时间限制的话超时就杀掉它.
+    let mut timed_out = false;
+    let (status, cpu_time) = match limits.wall_clock_secs {
+        None => wait_child(&child, false).map_err(error)?.unwrap(),
+        Some(secs) => {
+            let deadline = Instant::now() + Duration::from_secs(secs);
+            loop {
+                if let Some(exited) = wait_child(&child, true).map_err(error)? {
+                    break exited;
+                }
+                if Instant::now() >= deadline {
+                    let _ = child.kill();
+                    timed_out = true;
+                    break wait_child(&child, false).map_err(error)?.unwrap();
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+    };
+    let result = stdout_reader.join().unwrap_or(None);
+    let _ = stderr_reader.join();
+
+    if timed_out {
+        return Err((FailureReason::WallClockLimitExceeded,
+            format!("subtask exceeded the wall clock limit of {} s", limits.wall_clock_secs.unwrap())));
+    }
+    if let Some(info) = result {
+        return Ok(info);
+    }
+    match status.signal() {
+        Some(libc::SIGXCPU) => Err((FailureReason::CpuTimeLimitExceeded,
+            format!("subtask exceeded the cpu time limit of {} s", limits.cpu_time_secs.unwrap_or_default()))),
+        // 到了硬限制是 SIGKILL, 但 SIGKILL 也可能来自别处(比如 OOM killer), 所以要看子进程实际用掉的cpu时间.
+        Some(libc::SIGKILL) if limits.cpu_time_secs.is_some_and(|secs| cpu_time >= Duration::from_secs(secs)) =>
+            Err((FailureReason::CpuTimeLimitExceeded,
+                format!("subtask exceeded the cpu time limit of {} s", limits.cpu_time_secs.unwrap()))),
+        _ if status.code() == Some(ALLOCATION_FAILED_EXIT_CODE) && limits.memory_bytes.is_some() =>
+            Err((FailureReason::MemoryLimitExceeded,
+                format!("subtask exceeded the memory limit of {} bytes", limits.memory_bytes.unwrap()))),
+        _ => Err((FailureReason::Crashed,
+            format!("worker process exited without a result ({})", describe_exit(status)))),
+    }
+}
+
+/// 用 wait4 等待子进程结束, 同时取得它用掉的cpu时间(用户态加内核态). no_hang 时子进程还没结束就返回 None.
+fn wait_child(child : &Child, no_hang : bool) -> std::io::Result<Option<(ExitStatus, Duration)>> {
+    let mut status : libc::c_int = 0;
+    let mut usage : libc::rusage = unsafe { std::mem::zeroed() };
+    let options = if no_hang { libc::WNOHANG } else { 0 };
+    let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, options, &mut usage) };
+    if pid < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if pid == 0 {
+        return Ok(None);
+    }
+    let time = |t : libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    Ok(Some((ExitStatus::from_raw(status), time(usage.ru_utime) + time(usage.ru_stime))))
+}
+
+fn describe_exit(status : ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exit code {}", code),
+        (None, Some(signal)) => format!("killed by signal {}", signal),
+        _ => String::from("unknown exit status"),
+    }
+}
+
+/// worker子进程的入口: 从stdin读入SubtaskSpec, 执行, 把结果写到stdout.
+pub fn worker_process_main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut spec = String::new();
+    std::io::stdin().read_to_string(&mut spec)?;
+    let spec : SubtaskSpec = serde_json::from_str(&spec)?;
+    let (sender, receiver) = channel::<String>();
+    let task_id = spec.task_id;
+    run_subtask(spec, sender);
+    // 子进程只执行这一个子任务, 插件的私有副本用完就删掉.
+    evict_task(task_id);
+    let info = receiver.recv()?;
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}{}", RESULT_PREFIX, info)?;
+    stdout.flush()?;
+    Ok(())
+}
@@ -0,0 +1,128 @@
+// 独立的 worker daemon 进程, 以及它与 server 之间的消息.
+// daemon 连接 server, 发送 type 9 的消息注册自己能同时执行的子任务数, server 回复 type 10.
+// 之后这个连接不再关闭, 两边都一行写一个 json:
+// server 发给 daemon 的是 DaemonCommand(执行一个子任务, 或者卸载一个任务的插件),
+// daemon 发回的是 DaemonMessage: 每隔 HEARTBEAT_INTERVAL 一次心跳, 以及子任务的结果
+// (就是本地执行时通过 channel 发给 master 的那个 MasterWorkerInfo). server 一侧见 membership 与 scheduler.
+// daemon 还开一个 shuffle 服务, 它执行的 mapper 的输出写在 daemon 自己的本地目录(./daemon{pid}/)中, 由这个服务提供给 reducer.
+// 除此之外子任务中的路径(输入文件、插件)都是 server 上的路径, daemon 要能直接访问它们(同一台机器, 或者共享的文件系统).
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::{mpsc::channel, Arc, Mutex},
+    thread,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::MapReduceError;
+use crate::io_wrapper::{iowrapper_create_dir, iowrapper_exist, iowrapper_get_absolute_path, iowrapper_remove_dir_all, path_join};
+use crate::map_reduce::{JobOptions, read_packet};
+use crate::map_reduce_server::READY_MARKER;
+use crate::map_reduce_server::membership::HEARTBEAT_INTERVAL;
+use crate::map_reduce_server::shuffle::start_shuffle_service;
+use crate::map_reduce_server::workers::{SubtaskSpec, execute_subtask};
+use crate::plugin::cache::evict_task;
+use crate::thread_poll::ThreadPoll;
+
+/// server 发给 worker daemon 的命令.
+#[derive(Deserialize, Serialize)]
+pub enum DaemonCommand {
+    Run(Box<SubtaskAssignment>),
+    Evict(u32),   // 任务结束了, 卸载这个任务缓存的插件.
+}
+
+/// 分配给 worker daemon 的一个子任务. id 由 server 分配, daemon 报告结果时原样带回.
+#[derive(Deserialize, Serialize)]
+pub struct SubtaskAssignment {
+    pub id : u64,
+    pub spec : SubtaskSpec,
+    pub options : JobOptions,
+}
+
+/// worker daemon 发给 server 的消息.
+#[derive(Deserialize, Serialize)]
+pub enum DaemonMessage {
+    Heartbeat,
+    Report(SubtaskReport),
+}
+
+/// worker daemon 报告的子任务结果. info 是 MasterWorkerInfo 的 json.
+#[derive(Deserialize, Serialize)]
+pub struct SubtaskReport {
+    pub id : u64,
+    pub info : String,
+}
+
+/// 把一条消息写成一行发给 server.
+fn send_message(writer : &Mutex<TcpStream>, message : &DaemonMessage) -> std::io::Result<()> {
+    let line = serde_json::to_string(message).unwrap() + "\n";
+    writer.lock().unwrap().write_all(line.as_bytes())
+}
+
+/// worker daemon 的入口: 向 server_host 注册, 然后一直执行 server 分配来的子任务,
+/// 最多同时执行 capacity 个, 直到 server 断开连接.
+pub fn worker_daemon_main(server_host : &str, capacity : usize) -> Result<(), Box<dyn std::error::Error>> {
+    if capacity == 0 {
+        return Err("the capacity of a worker daemon must be positive".into());
+    }
+    let mut stream = TcpStream::connect(server_host)?;
+    // 本地目录: 中间文件放在这里, 就像模拟的节点的 scratch_dir. 用进程号区分同一台机器上的多个 daemon.
+    let scratch_dir = format!("./daemon{}/", std::process::id());
+    if iowrapper_exist(&scratch_dir) {
+        iowrapper_remove_dir_all(&scratch_dir)?;
+    }
+    iowrapper_create_dir(&scratch_dir)?;
+    let scratch_dir = iowrapper_get_absolute_path(&scratch_dir)?;
+    // shuffle 服务开在连接 server 用的那个 ip 上, server 和其他 worker 应该也能连上它.
+    let shuffle_host = start_shuffle_service(stream.local_addr()?.ip(), &scratch_dir)?;
+    stream.write_all(format!("{{\"message_type\":9,\"capacity\":{},\"data_file\":{:?}}}", capacity, shuffle_host).as_bytes())?;
+    let reply = read_packet(&mut stream)?;
+    if reply.message_type != 10 {
+        return Err(Box::new(MapReduceError::WrongMessageType));
+    }
+    println!("Registered at {} as worker daemon {} with capacity {}, shuffle service at {}",
+        server_host, reply.from, capacity, shuffle_host);
+    println!("{} {} {}", READY_MARKER, reply.from, shuffle_host);
+
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    // 心跳一直发到连接断开为止.
+    let heartbeat_writer = Arc::clone(&writer);
+    thread::spawn(move || {
+        while send_message(&heartbeat_writer, &DaemonMessage::Heartbeat).is_ok() {
+            thread::sleep(HEARTBEAT_INTERVAL);
+        }
+    });
+    let poll = ThreadPoll::new(capacity);
+    for line in BufReader::new(stream).lines() {
+        match serde_json::from_str::<DaemonCommand>(&line?)? {
+            DaemonCommand::Run(mut assignment) => {
+                assignment.spec.scratch_dir = scratch_dir.clone();
+                let writer = Arc::clone(&writer);
+                poll.execute(move || {
+                    let (sender, receiver) = channel::<String>();
+                    execute_subtask(assignment.spec, &assignment.options, sender);
+                    // 每个子任务一定会发回一条结果.
+                    let info = receiver.recv().unwrap();
+                    let report = DaemonMessage::Report(SubtaskReport { id : assignment.id, info });
+                    if let Err(e) = send_message(&writer, &report) {
+                        eprintln!("Cannot report subtask {} to server : {}", assignment.id, e);
+                    }
+                });
+            }
+            DaemonCommand::Evict(task_id) => {
+                evict_task(task_id);
+                // 任务结束了, 它的中间文件也不再需要.
+                let task_dir = path_join(&scratch_dir, &format!("{}/", task_id));
+                if iowrapper_exist(&task_dir) {
+                    if let Err(e) = iowrapper_remove_dir_all(&task_dir) {
+                        eprintln!("Cannot remove {} : {}", task_dir, e);
+                    }
+                }
+            }
+        }
+    }
+    println!("Server closed the connection, worker daemon quits.");
+    // 还在执行的子任务随进程一起结束, 它们的结果已经没人要了.
+    let _ = iowrapper_remove_dir_all(&scratch_dir);
+    Ok(())
+}
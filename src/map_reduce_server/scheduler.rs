@@ -0,0 +1,183 @@
+// 把子任务分给本地的节点或者注册过的 worker daemon.
+use std::{
+    cmp::Reverse,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::{mpsc::{channel, Sender}, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::map_reduce::{Counters, JobOptions};
+use crate::map_reduce_server::masters::{FailureReason, MasterWorkerInfo};
+use crate::map_reduce_server::membership::{Membership, PendingSubtask, MAX_SUBTASK_ATTEMPTS};
+use crate::map_reduce_server::nodes::LocalNode;
+use crate::map_reduce_server::remote_worker::DaemonMessage;
+use crate::map_reduce_server::workers::{SubtaskSpec, execute_subtask};
+
+/// 所有 master 共用的子任务调度器. 每个子任务交给空闲槽位最多的那一方(某个活着的本地节点或者某个可用的 worker daemon),
+/// 一样多时优先本地; 全都没有空闲时在本地节点中排队. worker daemon 死掉或者节点被杀掉时, 它正在执行的子任务重新分配.
+pub struct Scheduler {
+    nodes : Vec<Arc<LocalNode>>,   // 本地节点, 编号就是下标.
+    membership : Arc<Mutex<Membership>>,
+}
+
+/// 报告子任务 subtask_id 因为执行它的 worker 没了而失败.
+fn report_lost(subtask_id : u32, result_path : String, sender : Sender<String>) {
+    let info = MasterWorkerInfo {
+        subtask_id,
+        successed : false,
+        result_path,
+        failure : Some(FailureReason::WorkerLost),
+        named_outputs : Default::default(),
+        counters : Counters::new(),
+    };
+    let _ = sender.send(serde_json::to_string(&info).unwrap());
+}
+
+impl Scheduler {
+    /// 新建调度器, 同时开一个线程检查 worker daemon 的心跳.
+    pub fn new(nodes : Vec<LocalNode>) -> Arc<Scheduler> {
+        let scheduler = Arc::new(Scheduler {
+            nodes : nodes.into_iter().map(Arc::new).collect(),
+            membership : Arc::new(Mutex::new(Membership::default())),
+        });
+        let membership = Arc::downgrade(&scheduler.membership);
+        thread::spawn(move || {
+            // 调度器没了就结束.
+            while let Some(membership) = membership.upgrade() {
+                membership.lock().unwrap().expire();
+                drop(membership);
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+        scheduler
+    }
+
+    /// 注册一个 worker daemon: 回复 type 10, 然后开一个线程读它发来的消息. 返回分给它的编号.
+    /// shuffle_host 是它的 shuffle 服务的地址, 可以为空(它执行的 mapper 的输出只能直接读).
+    pub fn register_remote(self : &Arc<Self>, mut stream : TcpStream, capacity : u32, shuffle_host : String)
+        -> Result<u32, Box<dyn std::error::Error>> {
+        if capacity == 0 {
+            return Err("worker daemon registered with zero capacity".into());
+        }
+        let reader = stream.try_clone()?;
+        let mut membership = self.membership.lock().unwrap();
+        let worker_id = membership.join(stream.try_clone()?, capacity, shuffle_host);
+        if let Err(e) = stream.write_all(format!("{{\"message_type\":10,\"from\":{}}}", worker_id).as_bytes()) {
+            membership.leave(worker_id);
+            return Err(Box::new(e));
+        }
+        drop(membership);
+
+        let scheduler = Arc::clone(self);
+        thread::spawn(move || {
+            if let Err(e) = scheduler.read_messages(worker_id, reader) {
+                eprintln!("Lost worker daemon {} : {}", worker_id, e);
+            }
+            scheduler.lose_remote(worker_id);
+        });
+        println!("Worker daemon {} registered with capacity {}", worker_id, capacity);
+        Ok(worker_id)
+    }
+
+    /// 一直读 worker daemon 发来的心跳和结果, 结果交给对应的 master, 直到连接断开.
+    fn read_messages(&self, worker_id : u32, reader : TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+        for line in BufReader::new(reader).lines() {
+            let message : DaemonMessage = serde_json::from_str(&line?)?;
+            let mut membership = self.membership.lock().unwrap();
+            membership.heartbeat(worker_id);
+            if let DaemonMessage::Report(report) = message {
+                let sender = membership.complete(worker_id, report.id, &report.info);
+                drop(membership);
+                match sender {
+                    // master 已经不在了的话结果也没用了.
+                    Some(sender) => { let _ = sender.send(report.info); }
+                    None => eprintln!("Worker daemon {} reported an unknown subtask {}", worker_id, report.id),
+                }
+            }
+        }
+        Err("connection closed".into())
+    }
+
+    /// worker daemon 断开了: 去掉它, 把它正在执行的子任务重新分配.
+    fn lose_remote(self : &Arc<Self>, worker_id : u32) {
+        let pending = self.membership.lock().unwrap().leave(worker_id);
+        for subtask in pending {
+            self.retry_lost(subtask, &format!("worker daemon {}", worker_id));
+        }
+    }
+
+    /// 执行子任务的 lost(断开的 worker daemon 或者被杀掉的节点)没了, 重新分配这个子任务; 已经尝试了太多次的报告失败.
+    fn retry_lost(self : &Arc<Self>, subtask : PendingSubtask, lost : &str) {
+        if subtask.attempt < MAX_SUBTASK_ATTEMPTS {
+            println!("Rescheduling subtask {} of task {} from lost {}", subtask.spec.subtask_id, subtask.spec.task_id, lost);
+            self.dispatch_attempt(subtask.spec, subtask.options, subtask.sender, subtask.attempt + 1);
+        } else {
+            let result_path = format!("{} was lost, after {} attempts", lost, subtask.attempt);
+            report_lost(subtask.spec.subtask_id, result_path, subtask.sender);
+        }
+    }
+
+    /// 执行一个子任务, 结果(MasterWorkerInfo的json)通过sender发回.
+    pub fn dispatch(self : &Arc<Self>, spec : SubtaskSpec, options : JobOptions, sender : Sender<String>) {
+        self.dispatch_attempt(spec, options, sender, 1);
+    }
+
+    fn dispatch_attempt(self : &Arc<Self>, mut spec : SubtaskSpec, options : JobOptions, mut sender : Sender<String>, attempt : u32) {
+        // 空闲线程最多的活着的节点, 一样多时取编号小的.
+        let node = self.nodes.iter().filter(|node| node.alive()).min_by_key(|node| Reverse(node.free_slots()));
+        loop {
+            let mut membership = self.membership.lock().unwrap();
+            match membership.most_free() {
+                Some((worker_id, free)) if node.is_none_or(|node| free as i64 > node.free_slots()) => {
+                    match membership.assign(worker_id, &spec, &options, sender, attempt) {
+                        Ok(()) => return,
+                        // 这个 worker 已经断开了, 重新挑一次.
+                        Err(back) => sender = back,
+                    }
+                }
+                _ => break,
+            }
+        }
+        let Some(node) = node else {
+            report_lost(spec.subtask_id, String::from("no alive node or worker daemon to execute it"), sender);
+            return;
+        };
+        spec.shuffle_host = node.shuffle_host().to_string();
+        spec.scratch_dir = node.scratch_dir().to_string();
+        let scheduler = Arc::clone(self);
+        let executor = Arc::clone(node);
+        node.execute(move || {
+            let lost = format!("node {}", executor.id());
+            // 排队的时候节点被杀掉了.
+            if !executor.alive() {
+                scheduler.retry_lost(PendingSubtask { spec, options, sender, attempt }, &lost);
+                return;
+            }
+            let (result_sender, result) = channel::<String>();
+            execute_subtask(spec.clone(), &options, result_sender);
+            let Ok(info) = result.recv() else { return };
+            // 执行的时候节点被杀掉了, 结果作废(它的输出文件已经没有了).
+            if executor.alive() {
+                // master 已经不在了的话结果也没用了.
+                let _ = sender.send(info);
+            } else {
+                scheduler.retry_lost(PendingSubtask { spec, options, sender, attempt }, &lost);
+            }
+        });
+    }
+
+    /// 杀掉模拟的节点 node_id, 见 nodes.
+    pub fn kill_node(&self, node_id : u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.nodes.get(node_id as usize).ok_or_else(|| format!("there is no node {}", node_id))?.kill()
+    }
+
+    /// 卸载任务 task_id 在各个 worker daemon 中缓存的插件(本地的由 master 自己卸载), 删除各个节点本地目录中这个任务的文件.
+    pub fn evict_task(&self, task_id : u32) {
+        self.membership.lock().unwrap().evict_task(task_id);
+        for node in &self.nodes {
+            node.remove_task_files(task_id);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// shuffle 服务: 每台执行 mapper 的机器(server 本身, 每个 worker daemon, 以及每个模拟的节点)都开一个, 通过 tcp 提供本机上 mapper 的分区文件,
+// reducer 从各个 mapper 所在的机器上取回第 i 个分区, 不再要求所有 worker 共享同一个文件系统.
+// mapper 输出的位置写成 shuffle://{shuffle 服务的地址}/{mapper 输出的文件夹}, 见 shuffle_location.
+// 请求是一行 json(ShuffleRequest), 回复是一行 json(ShuffleReply), 找到了的话后面紧跟着文件的全部内容.
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    thread,
+    time::Duration,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::MapReduceError;
+
+/// shuffle 位置的前缀, 和 hdfs:// 一样直接放在路径前面.
+pub const SHUFFLE_PATH_HEAD : &str = "shuffle://";
+/// 取一个分区最多尝试的次数.
+pub const FETCH_ATTEMPTS : u32 = 3;
+/// 两次尝试之间等待的时间.
+pub const FETCH_RETRY_INTERVAL : Duration = Duration::from_secs(1);
+/// 连接 shuffle 服务与等待它回复的超时时间.
+const FETCH_TIMEOUT : Duration = Duration::from_secs(30);
+
+/// reducer 向 shuffle 服务请求一个分区文件.
+#[derive(Deserialize, Serialize)]
+struct ShuffleRequest {
+    path : String,
+}
+
+/// shuffle 服务的回复: 找到了(后面跟着这么多字节的文件内容), 或者找不到的原因.
+#[derive(Deserialize, Serialize)]
+enum ShuffleReply {
+    Found(u64),
+    Missing(String),
+}
+
+/// 把 host 上的 shuffle 服务提供的文件夹 path 写成 shuffle 位置. 分区文件的位置直接用 path_join 接在后面.
+pub fn shuffle_location(host : &str, path : &str) -> String {
+    format!("{}{}/{}", SHUFFLE_PATH_HEAD, host, path)
+}
+
+/// 拆开 shuffle 位置: (shuffle 服务的地址, 那台机器上的路径). 不是 shuffle 位置的话返回 None.
+pub fn parse_location(location : &str) -> Option<(&str, &str)> {
+    location.strip_prefix(SHUFFLE_PATH_HEAD)?.split_once('/')
+}
+
+/// 分区文件是哪个 mapper 的输出. mapper 的输出文件夹是 base_dir(或者节点本地目录中的任务目录)/{mapper 的编号}/, 分区文件在它下面.
+pub fn mapper_of(partition : &str) -> Option<u32> {
+    let path = parse_location(partition).map_or(partition, |(_, path)| path);
+    Path::new(path).parent()?.file_name()?.to_str()?.parse().ok()
+}
+
+/// shuffle 服务只提供 mapper 的分区文件: 绝对路径, 不含 .., 文件名是 {分区号}.json.
+fn is_partition_file(path : &Path) -> bool {
+    path.is_absolute()
+        && path.components().all(|component| component != Component::ParentDir)
+        && path.file_name().and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".json"))
+            .is_some_and(|partition| !partition.is_empty() && partition.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// 在 ip 上(端口由系统分配)启动 shuffle 服务, 返回它的地址. 服务在后台线程中一直运行到进程结束.
+/// 只提供 root 文件夹(这台机器放 mapper 输出的地方)下的分区文件, 别的路径一律当作找不到.
+pub fn start_shuffle_service(ip : IpAddr, root : &str) -> io::Result<String> {
+    start_gated_shuffle_service(ip, root, Arc::new(AtomicBool::new(true)))
+}
+
+/// 同 start_shuffle_service, 但 alive 变成 false 之后不再回复任何请求, 直接断开连接, 就像这台机器死掉了一样.
+/// 模拟的节点被杀掉时用到.
+pub fn start_gated_shuffle_service(ip : IpAddr, root : &str, alive : Arc<AtomicBool>) -> io::Result<String> {
+    // 符号链接展开之后再比较, 请求的路径也一样.
+    let root = Arc::new(Path::new(root).canonicalize()?);
+    let listener = TcpListener::bind((ip, 0))?;
+    let host = listener.local_addr()?.to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(_) if !alive.load(Ordering::SeqCst) => {}
+                Ok(stream) => {
+                    let root = Arc::clone(&root);
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, &root) {
+                            eprintln!("Shuffle service failed to serve a request : {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Shuffle service couldn't accept a connection : {}", e),
+            }
+        }
+    });
+    Ok(host)
+}
+
+/// 在 root 下的分区文件: 展开符号链接之后的真实路径. 不是的话返回不提供它的原因.
+fn served_file(path : &Path, root : &Path) -> Result<PathBuf, String> {
+    if !is_partition_file(path) {
+        return Err(String::from("not a partition file"));
+    }
+    let path = path.canonicalize().map_err(|e| e.to_string())?;
+    if !path.starts_with(root) {
+        return Err(String::from("not a partition file of this node"));
+    }
+    Ok(path)
+}
+
+/// 处理一个请求.
+fn serve(stream : TcpStream, root : &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let request : ShuffleRequest = serde_json::from_str(&line)?;
+    let mut writer = BufWriter::new(stream);
+    let file = served_file(Path::new(&request.path), root)
+        .and_then(|path| File::open(path).map_err(|e| e.to_string()));
+    match file {
+        Ok(mut file) => {
+            let length = file.metadata()?.len();
+            writeln!(writer, "{}", serde_json::to_string(&ShuffleReply::Found(length))?)?;
+            io::copy(&mut (&mut file).take(length), &mut writer)?;
+        }
+        Err(e) => writeln!(writer, "{}", serde_json::to_string(&ShuffleReply::Missing(e))?)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// 从 shuffle 服务取一次分区文件, 写到本地的 to.
+fn fetch_once(host : &str, path : &str, to : &str) -> Result<(), Box<dyn std::error::Error>> {
+    let address = host.parse().map_err(|e| format!("invalid shuffle host {} : {}", host, e))?;
+    let mut stream = TcpStream::connect_timeout(&address, FETCH_TIMEOUT)?;
+    stream.set_read_timeout(Some(FETCH_TIMEOUT))?;
+    let request = ShuffleRequest { path : path.to_string() };
+    stream.write_all((serde_json::to_string(&request)? + "\n").as_bytes())?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    match serde_json::from_str(&line)? {
+        ShuffleReply::Found(length) => {
+            let mut writer = BufWriter::new(File::create(to)?);
+            let copied = io::copy(&mut reader.take(length), &mut writer)?;
+            writer.flush()?;
+            if copied != length {
+                return Err(format!("got {} of {} bytes", copied, length).into());
+            }
+            Ok(())
+        }
+        ShuffleReply::Missing(e) => Err(e.into()),
+    }
+}
+
+/// 把 shuffle 位置 location 上的分区文件取到本地的 to, 失败的话每隔 FETCH_RETRY_INTERVAL 重试, 最多 FETCH_ATTEMPTS 次.
+/// 一直取不到时返回 ShuffleFetchFailed, master 据此重新执行那个 mapper.
+pub fn fetch_partition(location : &str, to : &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (host, path) = parse_location(location).ok_or(MapReduceError::PathError)?;
+    let mut reason = String::new();
+    for attempt in 1..=FETCH_ATTEMPTS {
+        match fetch_once(host, path, to) {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                eprintln!("Fetching {} from {} failed (attempt {}/{}) : {}", path, host, attempt, FETCH_ATTEMPTS, e);
+                reason = e.to_string();
+            }
+        }
+        if attempt < FETCH_ATTEMPTS {
+            thread::sleep(FETCH_RETRY_INTERVAL);
+        }
+    }
+    Err(Box::new(MapReduceError::ShuffleFetchFailed {
+        mapper : mapper_of(location).ok_or(MapReduceError::PathError)?,
+        reason,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map_reduce_server::test_dir::TestDir;
+
+    /// 临时目录下的 root/ 与 other/, 各有一个分区文件 0.json.
+    fn make_dirs(name : &str) -> (TestDir, PathBuf, PathBuf) {
+        let dir = TestDir::new("shuffle", name);
+        let root = dir.path().join("root");
+        let other = dir.path().join("other");
+        for d in [&root, &other] {
+            std::fs::create_dir_all(d.join("0")).unwrap();
+            std::fs::write(d.join("0/0.json"), "[\"a\",1]\n").unwrap();
+        }
+        let (root, other) = (root.canonicalize().unwrap(), other.canonicalize().unwrap());
+        (dir, root, other)
+    }
+
+    #[test]
+    fn serves_partition_files_under_root() {
+        let (_dir, root, _) = make_dirs("under");
+        assert_eq!(served_file(&root.join("0/0.json"), &root), Ok(root.join("0/0.json")));
+        assert!(served_file(&root.join("0/1.json"), &root).is_err());
+    }
+
+    #[test]
+    fn rejects_files_outside_root() {
+        let (_dir, root, other) = make_dirs("outside");
+        assert!(served_file(&other.join("0/0.json"), &root).is_err());
+        assert!(served_file(&root.join("../other/0/0.json"), &root).is_err());
+        assert!(served_file(Path::new("0/0.json"), &root).is_err());
+    }
+
+    #[test]
+    fn rejects_symlinks_out_of_root() {
+        let (_dir, root, other) = make_dirs("symlink");
+        let link = root.join("1");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(other.join("0"), &link).unwrap();
+        assert!(served_file(&link.join("0.json"), &root).is_err());
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_partitions() {
+        let (_dir, root, _) = make_dirs("names");
+        std::fs::write(root.join("0/task.json"), "{}").unwrap();
+        assert!(served_file(&root.join("0/task.json"), &root).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+// mapper 端输出的缓冲与溢写, 以及有序的中间文件的归并.
+// emit 的键值对按分区放进内存中的缓冲区, 缓冲的内容超过 SPILL_THRESHOLD_BYTES 时,
+// 每个分区排好序(有 combiner 的话先 combine)写成一个溢写文件 {mid_dir}/{i}.spill{n}.json;
+// mapper 结束之后把同一个分区的所有溢写文件归并成中间文件 {mid_dir}/{i}.json.
+// 一次都没有溢写过时直接写中间文件. 所有这些文件的格式都与中间文件相同.
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    hash::{Hash, Hasher},
+    collections::hash_map::DefaultHasher,
+    io::{BufRead, BufReader, BufWriter, Lines, Write},
+};
+use serde_json::{self, Value};
+
+use crate::io_wrapper::*;
+use crate::plugin::{Emit, Plugin};
+
+/// 内存中缓冲的 mapper 输出(key 与 value 的 json 文本)的上限, 超过之后溢写到磁盘.
+pub const SPILL_THRESHOLD_BYTES : usize = 64 * 1024 * 1024;
+
+/// 一个 mapper 的输出缓冲区. 它接收 emit 的键值对, 最后由 finish 写出每个分区的中间文件.
+pub struct MapOutputBuffer<'a> {
+    plugin : &'a Plugin,
+    mid_dir : String,
+    partitions : Vec<Vec<(String, String)>>,   // 每个分区是(key的json文本, value的json文本)的列表.
+    buffered_bytes : usize,
+    spills : u32,   // 已经溢写的次数.
+}
+
+impl<'a> MapOutputBuffer<'a> {
+    pub fn new(plugin : &'a Plugin, mid_dir : String, reducer_num : u32) -> MapOutputBuffer<'a> {
+        MapOutputBuffer {
+            plugin,
+            mid_dir,
+            partitions : vec![Vec::new(); reducer_num as usize],
+            buffered_bytes : 0,
+            spills : 0,
+        }
+    }
+
+    /// 插件有 partitioner 就先问它, 否则哈希一下来shuffle.
+    fn partition_of(&self, key : &String) -> Result<usize, Box<dyn std::error::Error>> {
+        let reducer_num = self.partitions.len() as u32;
+        Ok(match self.plugin.partition(key, reducer_num)? {
+            Some(index) => index as usize,
+            None => {
+                let mut hasher = DefaultHasher::new();
+                // 通过new or default出来的DefaultHasher都是一样的.
+                key.hash(&mut hasher);
+                (hasher.finish() % (reducer_num as u64)) as usize
+            }
+        })
+    }
+
+    /// 把缓冲区中的每个分区写成一个溢写文件, 然后清空缓冲区.
+    fn spill(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for (i, partition) in self.partitions.iter_mut().enumerate() {
+            let path = path_join(&self.mid_dir, &format!("{}.spill{}.json", i, self.spills));
+            write_run(self.plugin, &path, std::mem::take(partition))?;
+        }
+        self.spills += 1;
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+
+    /// 写出每个分区的中间文件 {mid_dir}/{i}.json, 溢写过的话归并所有的溢写文件, 然后删掉它们.
+    pub fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.spills == 0 {
+            for (i, partition) in std::mem::take(&mut self.partitions).into_iter().enumerate() {
+                write_run(self.plugin, &path_join(&self.mid_dir, &format!("{}.json", i)), partition)?;
+            }
+            return Ok(());
+        }
+        self.spill()?;
+        for i in 0..self.partitions.len() {
+            let runs : Vec<String> = (0..self.spills)
+                .map(|n| path_join(&self.mid_dir, &format!("{}.spill{}.json", i, n)))
+                .collect();
+            let path = path_join(&self.mid_dir, &format!("{}.json", i));
+            iowrapper_create_file(&path)?;
+            let mut writer = BufWriter::new(IOWrapperFile::open_empty(&path)?);
+            for pair in MergedRuns::open(&runs)? {
+                let (k, v) = pair?;
+                writeln!(writer, "[{},{}]", k, v)?;
+            }
+            writer.flush()?;
+            for run in runs.iter() {
+                iowrapper_remove_file(run)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Emit for MapOutputBuffer<'_> {
+    fn emit(&mut self, key : &str, value : &str) -> Result<(), Box<dyn std::error::Error>> {
+        // 用 key 序列化之后的 json 文本来哈希、排序. 插件交来的 json 文本写法不一定相同
+        // (比如对象中字段的顺序), 统一成 serde_json::Value 的写法, 与 reducer 读中间文件时一致.
+        let key = serde_json::to_string(&serde_json::from_str::<Value>(key)?)?;
+        let index = self.partition_of(&key)?;
+        self.buffered_bytes += key.len() + value.len();
+        self.partitions[index].push((key, value.to_string()));
+        if self.buffered_bytes >= SPILL_THRESHOLD_BYTES {
+            self.spill()?;
+        }
+        Ok(())
+    }
+}
+
+/// 对一个已经按 key 排好序的分区执行 combiner: 把相邻的同一个 key 的 values 交给插件合并.
+fn combine_partition(plugin : &Plugin, partition : Vec<(String, String)>)
+    -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let mut ret = Vec::with_capacity(partition.len());
+    let mut iter = partition.into_iter().peekable();
+    while let Some((k, v)) = iter.next() {
+        let mut values = vec![v];
+        while let Some((_, v)) = iter.next_if(|(next_k, _)| *next_k == k) {
+            values.push(v);
+        }
+        let values = format!("[{}]", values.join(","));
+        // 到这里一定有 combiner, combine 不会返回 None.
+        let combined = plugin.combine(&k, &values)?.unwrap_or(values);
+        let combined : Vec<Value> = serde_json::from_str(&combined)?;
+        for v in combined {
+            ret.push((k.clone(), serde_json::to_string(&v)?));
+        }
+    }
+    Ok(ret)
+}
+
+/// 把一个分区排好序写成文件. json lines 格式, 每行一个 [key, value], 按 key 的 json 文本排序.
+fn write_run(plugin : &Plugin, path : &String, mut partition : Vec<(String, String)>)
+    -> Result<(), Box<dyn std::error::Error>> {
+    iowrapper_create_file(path)?;
+    partition.sort_by(|a, b| a.0.cmp(&b.0));   // 稳定排序, 同一个 key 的 value 保持 mapper 输出的顺序.
+    if plugin.info().combiner {
+        partition = combine_partition(plugin, partition)?;
+    }
+    let mut writer = BufWriter::new(IOWrapperFile::open_empty(path)?);
+    for (k, v) in partition.iter() {
+        writeln!(writer, "[{},{}]", k, v)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// 把若干个按 key 排好序的文件(格式与中间文件相同)归并成一个按 key 有序的 (key, value) 序列,
+/// 都是 json 文本. 每个文件同时只读入一行. key 相同时先给出前面的文件中的, 同一个文件中保持原来的顺序.
+pub struct MergedRuns {
+    runs : Vec<Lines<BufReader<IOWrapperFile>>>,
+    heads : BinaryHeap<Reverse<(String, usize, String)>>,   // 每个文件当前的一行: (key, 文件的序号, value).
+    records : i64,   // 已经读入的键值对数.
+    bytes : i64,     // 已经读入的字节数.
+}
+
+impl MergedRuns {
+    pub fn open(paths : &[String]) -> Result<MergedRuns, Box<dyn std::error::Error>> {
+        let mut merged = MergedRuns {
+            runs : Vec::with_capacity(paths.len()),
+            heads : BinaryHeap::with_capacity(paths.len()),
+            records : 0,
+            bytes : 0,
+        };
+        for (i, path) in paths.iter().enumerate() {
+            merged.runs.push(BufReader::new(IOWrapperFile::open_read(path)?).lines());
+            merged.read_next(i)?;
+        }
+        Ok(merged)
+    }
+
+    /// 已经从所有文件中读入的键值对数.
+    pub fn records(&self) -> i64 {
+        self.records
+    }
+
+    /// 已经从所有文件中读入的字节数(包括换行).
+    pub fn bytes(&self) -> i64 {
+        self.bytes
+    }
+
+    /// 读入第 i 个文件的下一行, 放进 heads.
+    fn read_next(&mut self, i : usize) -> Result<(), Box<dyn std::error::Error>> {
+        for line in self.runs[i].by_ref() {
+            let line = line?;
+            self.bytes += line.len() as i64 + 1;
+            if line.is_empty() {
+                continue;
+            }
+            let (k, v) : (Value, Value) = serde_json::from_str(&line)?;
+            self.heads.push(Reverse((serde_json::to_string(&k)?, i, serde_json::to_string(&v)?)));
+            self.records += 1;
+            break;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for MergedRuns {
+    type Item = Result<(String, String), Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((k, i, v)) = self.heads.pop()?;
+        if let Err(e) = self.read_next(i) {
+            return Some(Err(e));
+        }
+        Some(Ok((k, v)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map_reduce_server::test_dir::TestDir;
+
+    /// 在临时目录下写几个有序的文件, 返回这个目录与文件的路径.
+    fn write_runs(name : &str, runs : &[&str]) -> (TestDir, Vec<String>) {
+        let dir = TestDir::new("spill", name);
+        let paths = runs.iter().enumerate().map(|(i, content)| {
+            let path = dir.path().join(format!("{}.json", i));
+            std::fs::write(&path, content).unwrap();
+            path.to_string_lossy().into_owned()
+        }).collect();
+        (dir, paths)
+    }
+
+    fn merge(paths : &[String]) -> Vec<(String, String)> {
+        MergedRuns::open(paths).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn merges_runs_in_key_order() {
+        let (_dir, paths) = write_runs("order", &[
+            "[\"a\",1]\n[\"c\",3]\n",
+            "[\"b\",2]\n[\"d\",4]\n",
+        ]);
+        let keys : Vec<String> = merge(&paths).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["\"a\"", "\"b\"", "\"c\"", "\"d\""]);
+    }
+
+    #[test]
+    fn equal_keys_keep_file_then_line_order() {
+        let (_dir, paths) = write_runs("ties", &[
+            "[\"k\",\"first\"]\n[\"k\",\"second\"]\n",
+            "[\"k\",\"third\"]\n",
+        ]);
+        let values : Vec<String> = merge(&paths).into_iter().map(|(_, v)| v).collect();
+        assert_eq!(values, ["\"first\"", "\"second\"", "\"third\""]);
+    }
+
+    #[test]
+    fn numeric_keys_are_ordered_as_json_text() {
+        let (_dir, paths) = write_runs("numbers", &["[10,\"x\"]\n", "[9,\"y\"]\n"]);
+        let keys : Vec<String> = merge(&paths).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["10", "9"]);
+    }
+
+    #[test]
+    fn skips_empty_lines_and_files_and_counts_what_it_reads() {
+        let (_dir, paths) = write_runs("empty", &["", "\n[\"a\",1]\n\n[\"b\",2]\n"]);
+        let mut merged = MergedRuns::open(&paths).unwrap();
+        let pairs : Vec<(String, String)> = merged.by_ref().map(Result::unwrap).collect();
+        assert_eq!(pairs, [("\"a\"".to_string(), "1".to_string()), ("\"b\"".to_string(), "2".to_string())]);
+        assert_eq!(merged.records(), 2);
+        assert_eq!(merged.bytes(), 18);
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let (_dir, paths) = write_runs("malformed", &["[\"a\",1]\nnot json\n"]);
+        let results : Vec<_> = MergedRuns::open(&paths).unwrap().collect();
+        assert!(results.iter().any(Result::is_err));
+    }
+}
@@ -0,0 +1,133 @@
+// 输入文件的切分: master 把输入文件按字节切成若干段(InputSplit), 每段的边界对齐到行首,
+// mapper 直接从输入文件(可能在hdfs上)读自己的那一段, 输入文件不用再分块、复制.
+use crate::io_wrapper::*;
+use crate::map_reduce::{InputSplit, SplitSize};
+
+/// 文件最后剩下的部分不超过一段的 SPLIT_SLOP_PERCENT% 时并进最后一段, 免得最后一段太小.
+const SPLIT_SLOP_PERCENT : u64 = 110;
+/// 每段至少是 总大小 / MAX_SPLITS, 免得很小的 target(或者很多的 mapper)把输入切成太多段,
+/// 每段一个 mapper, 它们都要记在检查点里. 输入文件比这还多的时候每个文件至少一段, 段数会超过它.
+pub const MAX_SPLITS : u64 = 10_000;
+
+/// 把 inputs 中的文件切成若干段. 每段的大小是 split_size.target, 没有设置的话是 总大小 / split_num,
+/// 再限制在 split_size.min 与 split_size.max 之间, 并且不小于 总大小 / MAX_SPLITS. 一段不会跨过两个文件, 空文件没有任何一段.
+/// 边界要对齐到行首, 所以每段的大小不完全一样.
+pub fn plan_splits(inputs : &[String], split_num : u32, split_size : &SplitSize)
+    -> Result<Vec<InputSplit>, Box<dyn std::error::Error>> {
+    let min = split_size.min.unwrap_or(1).max(1);
+    let max = split_size.max.unwrap_or(u64::MAX);
+    if min > max {
+        return Err(format!("the minimum split size {} is larger than the maximum {}", min, max).into());
+    }
+    let mut sizes = Vec::with_capacity(inputs.len());
+    for path in inputs {
+        sizes.push(iowrapper_filesize(path)?);
+    }
+    let total : u64 = sizes.iter().sum();
+    let bytes = split_size.target
+        .unwrap_or_else(|| total.div_ceil(split_num.max(1) as u64))
+        .clamp(min, max);
+    let least = total.div_ceil(MAX_SPLITS);
+    if least > max {
+        return Err(format!("the maximum split size {} would split the input of {} bytes into more than {} parts",
+            max, total, MAX_SPLITS).into());
+    }
+    let bytes = bytes.max(least);
+    let mut splits = Vec::new();
+    for (path, size) in inputs.iter().zip(sizes) {
+        if size == 0 {
+            continue;
+        }
+        // 一个文件的所有边界用同一个 reader 找.
+        let mut finder = LineStartFinder::open(path)?;
+        let mut start = 0;
+        while start < size {
+            let rest = size - start;
+            let end = if rest <= max && rest.saturating_mul(100) <= bytes.saturating_mul(SPLIT_SLOP_PERCENT) {
+                size
+            } else {
+                finder.next_line_start(start + bytes)?
+            };
+            splits.push(InputSplit { path : path.clone(), start, end });
+            start = end;
+        }
+    }
+    Ok(splits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map_reduce_server::test_dir::TestDir;
+
+    /// 在 dir 下写一个输入文件, 返回它的路径.
+    fn write_input(dir : &TestDir, name : &str, content : &str) -> String {
+        let path = dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn size(target : Option<u64>, min : Option<u64>, max : Option<u64>) -> SplitSize {
+        SplitSize { target, min, max }
+    }
+
+    fn ranges(splits : &[InputSplit]) -> Vec<(u64, u64)> {
+        splits.iter().map(|split| (split.start, split.end)).collect()
+    }
+
+    #[test]
+    fn boundaries_are_aligned_to_line_starts() {
+        // 每行 10 个字节.
+        let dir = TestDir::new("splits", "aligned");
+        let path = write_input(&dir, "aligned.txt", &"abcdefghi\n".repeat(10));
+        let splits = plan_splits(std::slice::from_ref(&path), 1, &size(Some(25), None, None)).unwrap();
+        assert_eq!(ranges(&splits), vec![(0, 30), (30, 60), (60, 90), (90, 100)]);
+        let content = std::fs::read(&path).unwrap();
+        for split in &splits {
+            assert!(split.start == 0 || content[split.start as usize - 1] == b'\n');
+        }
+    }
+
+    #[test]
+    fn short_tail_is_merged_into_the_last_split() {
+        // 105 个字节, 每段 50: 最后剩下的 55 个字节不超过 50 的 110%, 并进最后一段.
+        let dir = TestDir::new("splits", "slop");
+        let path = write_input(&dir, "slop.txt", &("abcd\n".repeat(21)));
+        let splits = plan_splits(&[path], 1, &size(Some(50), None, None)).unwrap();
+        assert_eq!(ranges(&splits), vec![(0, 50), (50, 105)]);
+    }
+
+    #[test]
+    fn min_larger_than_max_is_an_error() {
+        let dir = TestDir::new("splits", "minmax");
+        let path = write_input(&dir, "minmax.txt", "a\n");
+        assert!(plan_splits(&[path], 1, &size(None, Some(10), Some(5))).is_err());
+    }
+
+    #[test]
+    fn empty_files_have_no_split() {
+        let dir = TestDir::new("splits", "empty");
+        let empty = write_input(&dir, "empty.txt", "");
+        let other = write_input(&dir, "other.txt", "a\nb\n");
+        let splits = plan_splits(&[empty, other.clone()], 1, &SplitSize::default()).unwrap();
+        assert_eq!(splits, vec![InputSplit { path : other, start : 0, end : 4 }]);
+    }
+
+    #[test]
+    fn all_empty_input_has_no_split() {
+        let dir = TestDir::new("splits", "all_empty");
+        let inputs = [write_input(&dir, "a.txt", ""), write_input(&dir, "b.txt", "")];
+        assert!(plan_splits(&inputs, 2, &SplitSize::default()).unwrap().is_empty());
+        assert!(plan_splits(&inputs, 2, &size(Some(10), Some(5), Some(20))).unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_count_is_capped() {
+        let dir = TestDir::new("splits", "capped");
+        let path = write_input(&dir, "capped.txt", &"a\n".repeat(MAX_SPLITS as usize * 2));
+        let splits = plan_splits(std::slice::from_ref(&path), 1, &size(Some(1), None, None)).unwrap();
+        assert!(splits.len() as u64 <= MAX_SPLITS);
+        assert_eq!(splits.last().unwrap().end, MAX_SPLITS * 4);
+        assert!(plan_splits(&[path], 1, &size(None, None, Some(2))).is_err());
+    }
+}
@@ -0,0 +1,24 @@
+// 测试用的临时目录.
+use std::path::{Path, PathBuf};
+
+/// 临时目录下的 mapreduce_{module}_test_{pid}_{name}, 创建时先清掉上一次留下的, drop 时连同其中的文件一起删掉.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(module : &str, name : &str) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("mapreduce_{}_test_{}_{}", module, std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,225 +1,470 @@
 // 真正执行map和reduce的workers所使用的线程函数.  
-use libloading::{Symbol, Library};
-use std::fmt::format;
-use std::sync::mpsc::Sender;
+use std::sync::{mpsc::Sender, Arc};
+use std::panic::{self, AssertUnwindSafe};
 use std::{
-    sync::mpsc, collections::HashMap, collections::BTreeMap,
-    hash::{Hash, Hasher},
-    collections::hash_map::DefaultHasher,
+    collections::BTreeMap,
+    io::{BufWriter, Write},
 };
-use serde_json;
+use serde::{Deserialize, Serialize};
 
 use crate::io_wrapper::*;
-use crate::map_reduce_server::masters::MasterWorkerInfo;
-
-use super::masters::Master;
-
-/// 链接并且执行mapper函数.
-/// 用户mapper定义: pub fn mapper(content:&String)->HashMap<String, Vec<String>>;
-fn load_execute_mapper(dllpath : &String, content:&String)->Result<HashMap<String,Vec<String>>,Box<dyn std::error::Error>>{
-    unsafe{
-        let lib = Library::new(dllpath)?;
-        let func : Symbol<fn(&String)->HashMap<String,Vec<String>>> = lib.get(b"mapper")?;
-        Ok(func(content))
-    }
-}
-
-/// 链接并且执行reducer函数.
-/// 用户reducer定义：pub fn reducer(k:&String, v:&Vec<String>)->Vec<String>;
-/// 直接丢入合并完的BTreeMap(它自己是有序的)的可变引用, 并且直接在它上面修改(in-place)
-fn load_execute_reducer(dllpath:&String, btree:&mut BTreeMap<String,Vec<String>>) 
-    -> Result<(), Box<dyn std::error::Error>> {
-    unsafe{
-        let lib = Library::new(dllpath)?;
-        let func:Symbol<fn(&String, &Vec<String>)->Vec<String>> = lib.get(b"reducer")?;
-        for (k, v) in btree {
-            *v = func(&k, &v);
+use crate::error::MapReduceError;
+use crate::job::{ReduceOutput, check_output_name};
+use crate::plugin::{Emit, NamedSink, ReduceSink};
+use crate::plugin::cache::plugin_for_task;
+use crate::plugin::abi::panic_message;
+use crate::map_reduce::{ExecutionMode, JobOptions, JobConfig, SubtaskLimits, Counters, InputSplit, add_counters};
+use crate::map_reduce::{MAP_INPUT_RECORDS, MAP_INPUT_BYTES, MAP_OUTPUT_RECORDS, MAP_OUTPUT_BYTES,
+    REDUCE_INPUT_GROUPS, REDUCE_INPUT_RECORDS, REDUCE_INPUT_BYTES, REDUCE_OUTPUT_RECORDS, REDUCE_OUTPUT_BYTES};
+use crate::map_reduce_server::masters::{MasterWorkerInfo, FailureReason};
+use crate::map_reduce_server::process_worker::run_in_process;
+use crate::map_reduce_server::spill::{MapOutputBuffer, MergedRuns};
+use crate::map_reduce_server::shuffle::{fetch_partition, mapper_of, parse_location, shuffle_location};
+
+/// 接收只有 map 的任务中 mapper 的输出, 按输出的顺序直接写进结果文件, 每行一个 [key, value].
+struct MapOnlyWriter(BufWriter<IOWrapperFile>);
+
+impl Emit for MapOnlyWriter {
+    fn emit(&mut self, key : &str, value : &str) -> Result<(), Box<dyn std::error::Error>> {
+        writeln!(self.0, "[{},{}]", key, value)?;
+        Ok(())
+    }
+}
+
+/// 数一数 mapper 输出了多少键值对、多少字节, 再交给真正接收输出的 inner.
+struct CountingEmit<E> {
+    inner : E,
+    records : i64,
+    bytes : i64,
+}
+
+impl<E : Emit> Emit for CountingEmit<E> {
+    fn emit(&mut self, key : &str, value : &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.records += 1;
+        self.bytes += (key.len() + value.len()) as i64;
+        self.inner.emit(key, value)
+    }
+}
+
+/// 把一个 reducer 的输出写成结果文件, 每行一个 [key, [outputs]].
+/// 输出记录一条一条地写, 一个 key 有再多的输出也不必放在内存里.
+struct ReduceResultWriter {
+    writer : BufWriter<IOWrapperFile>,
+    line_open : bool,      // 当前这一行还没有写完.
+    first_record : bool,   // 下一条记录是当前 key 的第一条记录.
+    groups : i64,    // 写过的 key 数.
+    records : i64,   // 写过的记录数.
+    bytes : i64,     // 写过的字节数.
+}
+
+impl ReduceResultWriter {
+    fn new(writer : BufWriter<IOWrapperFile>) -> ReduceResultWriter {
+        ReduceResultWriter { writer, line_open : false, first_record : true, groups : 0, records : 0, bytes : 0 }
+    }
+
+    fn write(&mut self, bytes : &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.writer.write_all(bytes)?;
+        self.bytes += bytes.len() as i64;
+        Ok(())
+    }
+
+    fn close_line(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.line_open {
+            self.write(b"]]\n")?;
+            self.line_open = false;
         }
         Ok(())
     }
+
+    /// 写完结果文件, 返回写结果文件的计数器.
+    fn finish(mut self) -> Result<Counters, Box<dyn std::error::Error>> {
+        self.close_line()?;
+        self.writer.flush()?;
+        Ok(Counters::from([
+            (REDUCE_INPUT_GROUPS.to_string(), self.groups),
+            (REDUCE_OUTPUT_RECORDS.to_string(), self.records),
+            (REDUCE_OUTPUT_BYTES.to_string(), self.bytes),
+        ]))
+    }
+}
+
+impl ReduceSink for ReduceResultWriter {
+    fn start(&mut self, key : &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.close_line()?;
+        self.write(format!("[{},[", key).as_bytes())?;
+        self.line_open = true;
+        self.first_record = true;
+        self.groups += 1;
+        Ok(())
+    }
+
+    fn output(&mut self, record : &str) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.first_record {
+            self.write(b",")?;
+        }
+        self.write(record.as_bytes())?;
+        self.first_record = false;
+        self.records += 1;
+        Ok(())
+    }
 }
 
+/// 创建文件夹; 同一个任务的多个worker可能同时创建同一个文件夹, 已经存在不算错误.
+fn create_dir_if_missing(dir : &String) -> Result<(), Box<dyn std::error::Error>> {
+    if let Err(e) = iowrapper_create_dir(dir) {
+        if !iowrapper_exist(dir) {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// 把命名输出的记录一条一条地写进结果文件 base_dir/outputs/{name}/{file_name}, json lines 格式, 每行一条记录.
+/// 每个输出的结果文件在它的第一条记录到来时才创建, 没有写过记录的输出没有结果文件.
+struct NamedOutputWriter {
+    outputs_dir : String,
+    file_name : String,
+    files : BTreeMap<String, (String, BufWriter<IOWrapperFile>)>,   // 输出名 -> (结果文件的路径, 写它的 writer).
+}
+
+impl NamedOutputWriter {
+    fn new(base_dir : &String, file_name : String) -> NamedOutputWriter {
+        NamedOutputWriter { outputs_dir : path_join(base_dir, &String::from("outputs/")), file_name, files : BTreeMap::new() }
+    }
+
+    /// 写完所有的结果文件, 返回 输出名 -> 结果文件的路径.
+    fn finish(self) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+        let mut ret = BTreeMap::new();
+        for (name, (path, mut writer)) in self.files {
+            writer.flush()?;
+            ret.insert(name, path);
+        }
+        Ok(ret)
+    }
+}
+
+impl NamedSink for NamedOutputWriter {
+    fn write(&mut self, name : &str, record : &str) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.files.contains_key(name) {
+            check_output_name(name)?;
+            create_dir_if_missing(&self.outputs_dir)?;
+            let dir = path_join(&self.outputs_dir, &format!("{}/", name));
+            create_dir_if_missing(&dir)?;
+            let path = path_join(&dir, &self.file_name);
+            iowrapper_create_file(&path)?;
+            let writer = BufWriter::new(IOWrapperFile::open_empty(&path)?);
+            self.files.insert(name.to_string(), (path, writer));
+        }
+        let (_, writer) = self.files.get_mut(name).unwrap();
+        writeln!(writer, "{}", record)?;
+        Ok(())
+    }
+}
+
+/// 子任务的种类.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub enum SubtaskKind {
+    Mapper,
+    Reducer,
+}
+
+/// 一个子任务的完整描述. 它可以直接交给本地的worker线程执行,
+/// 也可以序列化之后交给worker子进程执行.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SubtaskSpec {
+    pub kind : SubtaskKind,
+    pub task_id : u32,
+    pub subtask_id : u32,    // 对reducer来说是它在reducer中的编号(0..n).
+    pub base_dir : String,
+    pub inputpath : String,  // mapper是一个文件; reducer是用|分隔的许多文件(可以是 shuffle 位置).
+    #[serde(default)]
+    pub split : Option<InputSplit>,   // mapper 读的那一段输入, 没有的话读整个 inputpath.
+    pub dllpath : String,
+    pub reducer_num : u32,   // 只有mapper用得到, 为 0 时是只有 map 的任务.
+    #[serde(default)]
+    pub limits : SubtaskLimits,   // 加载 wasm 插件时用到.
+    #[serde(default)]
+    pub config : JobConfig,       // 任务的配置参数, 加载插件时放进任务上下文.
+    #[serde(default)]
+    pub shuffle_host : String,    // 执行它的机器上 shuffle 服务的地址, 由调度器填写. 为空时 mapper 报告本地路径.
+    #[serde(default)]
+    pub scratch_dir : String,     // 执行它的模拟节点(或 worker daemon)的本地目录, 由调度器(或 daemon)填写. 为空时中间文件放在 base_dir 下.
+}
+
+/// 在当前线程中执行一个子任务, 结果(MasterWorkerInfo的json)通过sender发回.
+pub fn run_subtask(spec : SubtaskSpec, sender : Sender<String>) {
+    // 中间文件(mapper 的分区文件, reducer 取来的分区文件)放在节点本地目录下的 {task_id}/ 中; 不是模拟的节点就放在 base_dir 下.
+    let local_dir = if spec.scratch_dir.is_empty() {
+        spec.base_dir.clone()
+    } else {
+        path_join(&spec.scratch_dir, &format!("{}/", spec.task_id))
+    };
+    match spec.kind {
+        SubtaskKind::Mapper => mapper(
+            spec.task_id, spec.subtask_id, spec.base_dir, local_dir, spec.inputpath, spec.split,
+            spec.dllpath, spec.reducer_num, spec.limits, spec.config, spec.shuffle_host, sender),
+        SubtaskKind::Reducer => reducer(
+            spec.task_id, spec.subtask_id, spec.base_dir, local_dir, spec.inputpath,
+            spec.dllpath, spec.limits, spec.config, spec.shuffle_host, sender),
+    }
+}
+
+/// 从子任务返回的错误判断失败原因: 用户代码在插件中panic也算作Panicked, wasm 插件超过限制也在这里区分.
+fn failure_reason_of(e : &(dyn std::error::Error + 'static)) -> FailureReason {
+    match e.downcast_ref::<MapReduceError>() {
+        Some(MapReduceError::UserCodePanicked { .. }) => FailureReason::Panicked,
+        Some(MapReduceError::UserCodeOutOfFuel { .. }) => FailureReason::FuelLimitExceeded,
+        Some(MapReduceError::UserCodeOutOfMemory { .. }) => FailureReason::MemoryLimitExceeded,
+        Some(MapReduceError::ShuffleFetchFailed { mapper, .. }) => FailureReason::FetchFailed { mapper : *mapper },
+        _ => FailureReason::Error,
+    }
+}
+
+/// 按任务指定的执行方式执行一个子任务: 在当前线程中, 或者在有资源限制的worker子进程中.
+pub fn execute_subtask(spec : SubtaskSpec, options : &JobOptions, sender : Sender<String>) {
+    match options.execution_mode {
+        ExecutionMode::Thread => run_subtask(spec, sender),
+        ExecutionMode::Process => run_in_process(spec, &options.limits, sender),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn mapper(
     task_id : u32,      // 创建文件夹用.
     subtask_id : u32,
     base_dir : String,
+    local_dir : String,        // 放中间文件的本地目录.
     inputfilepath : String,    //mapper的输入文件是一个文件.
+    split : Option<InputSplit>,   // 只读输入文件的这一段.
     dllpath : String,
     reducer_num : u32,
+    limits : SubtaskLimits,
+    config : JobConfig,
+    shuffle_host : String,   // 本机 shuffle 服务的地址.
     sender : Sender<String>
 ) {
-    //-----TODO---------
-    // 先把inputfile和dllpath复制到本地, 先不实现.
-    //------------------
-    if let Err(e) = do_mapper(
-        task_id, subtask_id, base_dir, inputfilepath, dllpath, reducer_num, &sender) {
+    // 输入直接从原来的位置(可能在hdfs上)读, 不复制(见 do_mapper); dll 由插件缓存按 server 上的路径加载.
+    // 用户代码(或者框架自己)panic 时也要向 master 报告失败, 否则 master 会一直等下去.
+    let ret = panic::catch_unwind(AssertUnwindSafe(|| do_mapper(
+        task_id, subtask_id, base_dir, local_dir, inputfilepath, split.as_ref(), dllpath, reducer_num, &limits, &config, &shuffle_host, &sender)));
+    let err = match ret {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some((failure_reason_of(e.as_ref()), format!("{}", e))),
+        Err(payload) => Some((FailureReason::Panicked, format!("mapper panicked: {}", panic_message(payload.as_ref())))),
+    };
+    if let Some((reason, e)) = err {
         eprintln!("mapper {} of task {} failed : {}",subtask_id, task_id, e);
         let err_info = MasterWorkerInfo{
             subtask_id,
             successed : false,
-            result_path : String::from(format!("{}",e))
+            result_path : e,
+            failure : Some(reason),
+            named_outputs : BTreeMap::new(),
+            counters : Counters::new(),
         };
         sender.send(serde_json::to_string(&err_info).unwrap()).unwrap();          
     }
-    //-----------TODO-----------
-    // 清理之前复制到“本地”的临时文件
-    //--------------------------
     println!("Mapper\t{}\tof task\t{}\tsuccessfully finished and quited.", subtask_id, task_id);
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn do_mapper(
     task_id : u32,      // 创建文件夹用.
     subtask_id : u32,
     base_dir : String,
+    local_dir : String,
     inputfilepath : String,    //mapper的输入文件是一个文件.
+    split : Option<&InputSplit>,
     dllpath : String,
     reducer_num : u32,
+    limits : &SubtaskLimits,
+    config : &JobConfig,
+    shuffle_host : &str,
     sender : &Sender<String>
 ) -> Result<(), Box<dyn std::error::Error>> {
 
-    let localinputfile = inputfilepath;  // 暂时先用这个替代. 注意是绝对路径.
     let localdllpath = dllpath;
+    // 重新分配的子任务可能已经由上一次尝试建好了本地目录.
+    create_dir_if_missing(&local_dir)?;
 
-    // 读取这个输入文件的所有内容.
-    let content = iowrapper_read_to_string(&localinputfile)?;  // 完整的文件内容.
-    
-    // 动态链接localdllpath.
-    let mapper_ret = load_execute_mapper(&localdllpath, &content)?;
-
-    // 取出 mapper_ret 中的键值对，哈希之后放入不同的文件里. 放置中间文件..
-    // 将中间文件放在"./{task_id}/{subtask_id}/XX.mid", 也就是base_dir/subtask_id/XX.mid
-    let mid_dir = path_join(&base_dir, &format!("{}/", subtask_id));
-    iowrapper_create_dir(&mid_dir)?;
-    // 创建n个中间文件，命名为{i}.mid(从0开始)，然后准备n个hashmap，把结果存入n个文件中.
-    // 感觉应该流式处理要好一点，但没找到动态地向json文件中加东西的方法.
-    let mut hashmaps: Vec<HashMap<String,Vec<String>>> = Vec::with_capacity(reducer_num as usize);
-    for i in 0..reducer_num {
-        let mid_file_name = String::from(format!("{}.json", i));
-        let mid_file_path = path_join(&mid_dir, &mid_file_name);
-        // 创建文件.
-        iowrapper_create_file(&mid_file_path)?;
-        // 一个空的hashmap.
-        hashmaps.push(HashMap::new());
-    }
-    // 把mapper_ret中的键值对决定放入哪个文件后放入相应的hashmap中.
+    // 就地读取输入中属于这个 mapper 的那一段, 没有给出的话是整个输入文件.
+    let content = match split {
+        Some(split) => iowrapper_read_range(&split.path, split.start, split.end)?,
+        None => iowrapper_read_to_string(&inputfilepath)?,
+    };
     
-    for (k, v) in mapper_ret {
-        let mut hasher = DefaultHasher::new();  // 哈希一下来shuffle
-        // 通过new or default出来的DefaultHasher都是一样的.
-        k.hash(&mut hasher);
-        let index = (hasher.finish() % (reducer_num as u64)) as usize;
-        // hashmap中不可能有键值重复.
-        hashmaps[index].insert(k, v);
-    }
-    // 保存进文件.
-    for i in 0..reducer_num {
-        let p = String::from(format!("{}.json", i));
-        let p = path_join(&mid_dir, &p);
-        let content = serde_json::to_string_pretty(&hashmaps[i as usize])?;
-        iowrapper_write_file_all(&p, &content)?;
-    }
+    // 动态链接localdllpath. 插件从任务的插件缓存中取.
+    let plugin = plugin_for_task(task_id, &localdllpath, &base_dir, limits, config)?;
+
+    // mapper 一个一个地交出键值对.
+    // 一般的任务放进输出缓冲区, 分区、排好序之后写成中间文件(缓冲区满了会先溢写到磁盘).
+    // 将中间文件放在"./{task_id}/{subtask_id}/XX.json", 也就是local_dir/subtask_id/XX.json
+    // 只有 map 的任务(reducer_num 为 0): mapper 的输出直接就是结果, 不分区也不排序,
+    // 按输出的顺序写进结果文件 ./task_id/ret{subtask_id}.json.
+    // 命名输出的记录直接就是结果, 边执行边写进 base_dir/outputs/{name}/m{subtask_id}.json.
+    let mut named = NamedOutputWriter::new(&base_dir, format!("m{}.json", subtask_id));
+    let (result_path, mapper_ret, records, bytes) = if reducer_num == 0 {
+        let ret_path = path_join(&base_dir, &format!("ret{}.json", subtask_id));
+        iowrapper_create_file(&ret_path)?;
+        let writer = MapOnlyWriter(BufWriter::new(IOWrapperFile::open_empty(&ret_path)?));
+        let mut writer = CountingEmit { inner : writer, records : 0, bytes : 0 };
+        let mapper_ret = plugin.map_emit(&content, &mut writer, &mut named)?;
+        writer.inner.0.flush()?;
+        (ret_path, mapper_ret, writer.records, writer.bytes)
+    } else {
+        let mid_dir = path_join(&local_dir, &format!("{}/", subtask_id));
+        // 重新分配的子任务可能已经由上一次尝试建好了这个目录.
+        create_dir_if_missing(&mid_dir)?;
+        let buffer = MapOutputBuffer::new(&plugin, mid_dir.clone(), reducer_num);
+        let mut buffer = CountingEmit { inner : buffer, records : 0, bytes : 0 };
+        let mapper_ret = plugin.map_emit(&content, &mut buffer, &mut named)?;
+        buffer.inner.finish()?;
+        // reducer 通过本机的 shuffle 服务取这些分区文件; 没有 shuffle 服务时直接读这个文件夹.
+        let location = if shuffle_host.is_empty() { mid_dir } else { shuffle_location(shuffle_host, &mid_dir) };
+        (location, mapper_ret, buffer.records, buffer.bytes)
+    };
+
+    let named_outputs = named.finish()?;
+    // 用户计数器加上框架内置的计数器.
+    let mut counters = mapper_ret.counters;
+    add_counters(&mut counters, &Counters::from([
+        (MAP_INPUT_RECORDS.to_string(), content.lines().count() as i64),
+        (MAP_INPUT_BYTES.to_string(), content.len() as i64),
+        (MAP_OUTPUT_RECORDS.to_string(), records),
+        (MAP_OUTPUT_BYTES.to_string(), bytes),
+    ]));
     // 发消息
     let success_info = MasterWorkerInfo{
         subtask_id,
         successed: true,
-        result_path : mid_dir,
+        result_path,
+        failure : None,
+        named_outputs,
+        counters,
     };
     sender.send(serde_json::to_string(&success_info)?)?;
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn reducer(
     task_id : u32,
     subtask_id : u32,
     base_dir : String,
+    local_dir : String,       // 放取来的分区文件的本地目录.
     inputfilepath : String,   // 这个是许多用|分隔的许多文件路径.
     dllpath : String,
+    limits : SubtaskLimits,
+    config : JobConfig,
+    shuffle_host : String,    // 本机 shuffle 服务的地址, 本机的分区文件直接读.
     sender : Sender<String>
 ) {
-    //------TODO----------
-    // 把“不同机器上”的文件(包括dllpath)复制到本机，暂且略.
-    //--------------------
-    if let Err(e) = do_reducer(
-        task_id, subtask_id, base_dir, inputfilepath, dllpath, &sender) {
+    // 其他机器上的分区文件在 do_reducer 中通过 shuffle 服务取到本地, 用完就删; dll 由插件缓存按 server 上的路径加载.
+    let ret = panic::catch_unwind(AssertUnwindSafe(|| do_reducer(
+        task_id, subtask_id, base_dir, local_dir, inputfilepath, dllpath, &limits, &config, &shuffle_host, &sender)));
+    let err = match ret {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some((failure_reason_of(e.as_ref()), format!("{}", e))),
+        Err(payload) => Some((FailureReason::Panicked, format!("reducer panicked: {}", panic_message(payload.as_ref())))),
+    };
+    if let Some((reason, e)) = err {
         eprintln!("Reducer {} of task {} failed : {}",subtask_id, task_id, e);
         let err_info = MasterWorkerInfo{
             subtask_id,
             successed : false,
-            result_path : String::from(format!("{}",e))
+            result_path : e,
+            failure : Some(reason),
+            named_outputs : BTreeMap::new(),
+            counters : Counters::new(),
         };
         sender.send(serde_json::to_string(&err_info).unwrap()).unwrap();
     }
-    // ----------TODO---------------------
-    // 清理之前“复制到本地”的临时文件；暂时不用管.
-    // -----------------------------------
     println!("Reducer\t{}\tof task\t{}\tsuccessfully finished and quited.", subtask_id, task_id);
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn do_reducer(
     task_id : u32,
     subtask_id : u32,
     base_dir : String,
+    local_dir : String,
     inputfilepath : String,   // 这个是许多用|分隔的许多文件路径.
     dllpath : String,
+    limits : &SubtaskLimits,
+    config : &JobConfig,
+    shuffle_host : &str,
     sender : &Sender<String>
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let inputfiles:Vec<&str> = inputfilepath.split('|').collect();  // 多个输入文件的路径.
-    let local_inputfiles = inputfiles;
+    // 多个输入文件的路径. 输入是空的任务没有 mapper, reducer 也就没有输入文件, 写出一个空的结果文件.
+    let inputfiles:Vec<String> = inputfilepath.split('|').filter(|path| !path.is_empty()).map(String::from).collect();
     let local_dllpath = dllpath;
 
-    // 将所有文件的内容读进来，放进一个hashmap中，同一个键的只是append，然后将各键值对分别丢入reducer.
-    // 注意，要根据key来排序！所以使用有序的BTreeMap
-    let mut btree: BTreeMap<String, Vec<String>> = BTreeMap::new();
-    for file in local_inputfiles {
-        // 遍历完之后local_inputfiles没用了, 内部成员所有权被移走.
-        let json_str = iowrapper_read_to_string(&String::from(file))?;
-        let subhashmap:HashMap<String,Vec<String>> = serde_json::from_str(&json_str[..])?;
-        for (k, mut v) in subhashmap {
-            // 对其中一个文件，如果btree中有就append到已有项中; 如果没有就插入键值对.
-            match btree.get_mut(&k) {
-                Some(entry) => {
-                    entry.append(&mut v);  // 这个要求 moves all elements in "other" and then append.
-                }
-                None => {
-                    btree.insert(k, v);
+    // 在其他机器上的分区文件先通过 shuffle 服务取到本地的 local_dir/shuffle{subtask_id}/ 中.
+    // 本机 shuffle 服务提供的分区文件直接读, 不用再经过 tcp 复制一份.
+    let fetch_dir = path_join(&local_dir, &format!("shuffle{}/", subtask_id));
+    let mut local_inputfiles = Vec::with_capacity(inputfiles.len());
+    for (k, inputfile) in inputfiles.into_iter().enumerate() {
+        match parse_location(&inputfile) {
+            Some((host, path)) if host == shuffle_host => {
+                // 和取不到一样, 让 master 重新执行那个 mapper.
+                if !iowrapper_exist(&path.to_string()) {
+                    return Err(Box::new(MapReduceError::ShuffleFetchFailed {
+                        mapper : mapper_of(&inputfile).ok_or(MapReduceError::PathError)?,
+                        reason : format!("{} does not exist", path),
+                    }));
                 }
+                local_inputfiles.push(path.to_string());
             }
+            Some(_) => {
+                create_dir_if_missing(&local_dir)?;
+                create_dir_if_missing(&fetch_dir)?;
+                let local_inputfile = path_join(&fetch_dir, &format!("{}.json", k));
+                fetch_partition(&inputfile, &local_inputfile)?;
+                local_inputfiles.push(local_inputfile);
+            }
+            None => local_inputfiles.push(inputfile),
         }
     }
-    // 执行reducer, 注意下面的直接把结果修改在了btree里，是in-place的
-    load_execute_reducer(&local_dllpath, &mut btree)?;
-        // Ok(_) => { },   // do nothing
-        // Err(_) => {
-        //     println!("Failed when loading and executing user's reducer");
-        //     // 发失败消息.
-        //     let err_info = MasterWorkerInfo{
-        //         subtask_id,
-        //         successed : false,
-        //         result_path : String::from("Failed to load and execute reducer")
-        //     };
-        //     sender.send(serde_json::to_string(&err_info).unwrap()).unwrap();
-        //     //--------TODO------------
-        //     // 清理刚才复制过来的临时文件, 因为现在没考虑这个，先不管
-        //     //------------------------
-        //     return;   // 结束整个reducer过程.
-        // }
-    // }
-    // 现在这个btree就是结果了，把它保存到结果文件中.
+
+    // 每个中间文件都已经按 key 排好序, 把它们归并成一个有序的序列, 同一个 key 的 values 连在一起,
+    // 一边读一边把各个 key 的 values 一个一个地交给 reducer, 不把所有文件的内容都读进内存.
+    let mut pairs = MergedRuns::open(&local_inputfiles)?;
+    // 执行reducer. 插件从任务的插件缓存中取.
+    let plugin = plugin_for_task(task_id, &local_dllpath, &base_dir, limits, config)?;
+    // 把结果保存到结果文件中, 同样是 json lines 格式, 每行一个 [key, [outputs]].
     // 文件路径为 ./task_id/ret{subtask_id}.json
     let ret_path = path_join(
         &base_dir, &format!("ret{}.json",subtask_id)
     );
     iowrapper_create_file(&ret_path)?;
-    let ret_json_str = serde_json::to_string_pretty(&btree)?;
-    iowrapper_write_file_all(&ret_path, &ret_json_str);
+    let mut writer = ReduceResultWriter::new(BufWriter::new(IOWrapperFile::open_empty(&ret_path)?));
+    // 命名输出的结果文件是 base_dir/outputs/{name}/ret{subtask_id}.json.
+    let mut named = NamedOutputWriter::new(&base_dir, format!("ret{}.json", subtask_id));
+    let reducer_ret = plugin.reduce_sorted(&mut pairs, &mut writer, &mut named)?;
+    // 用户计数器加上框架内置的计数器.
+    let mut counters = reducer_ret.counters;
+    add_counters(&mut counters, &writer.finish()?);
+    add_counters(&mut counters, &Counters::from([
+        (REDUCE_INPUT_RECORDS.to_string(), pairs.records()),
+        (REDUCE_INPUT_BYTES.to_string(), pairs.bytes()),
+    ]));
+    let named_outputs = named.finish()?;
+    // 取来的分区文件用完了.
+    drop(pairs);
+    if iowrapper_exist(&fetch_dir) {
+        iowrapper_remove_dir_all(&fetch_dir)?;
+    }
     // 发送成功的消息.
     let success_info = MasterWorkerInfo{
         subtask_id,
         successed : true,
         result_path : ret_path,
+        failure : None,
+        named_outputs,
+        counters,
     };
     sender.send(serde_json::to_string(&success_info).unwrap()).unwrap();
 
     Ok(())  // Over
     
-}
\ No newline at end of file
+}
@@ -0,0 +1,436 @@
+/// 插件(用户dll)与框架之间的 C ABI.
+/// 跨越动态库边界的只有 C 的基本类型和 RawBuffer, 数据内容都是 utf-8 的 json 文本,
+/// 所以用不同版本 rustc 编译的插件也能被安全地加载. \
+/// 插件需要导出的符号: \
+/// mapreduce_abi_version : extern "C" fn() -> u32, 加载时首先检查它是否等于 PLUGIN_ABI_VERSION \
+/// mapreduce_plugin_info : extern "C" fn(out:*mut RawBuffer) -> i32, 输出 PluginInfo 的 json \
+/// mapreduce_mapper : extern "C" fn(input:*const u8, input_len:usize, out:*mut RawBuffer) -> i32 \
+/// mapreduce_reducer : extern "C" fn(key:*const u8, key_len:usize, values:*const u8, values_len:usize, out:*mut RawBuffer) -> i32 \
+/// mapreduce_free : extern "C" fn(ptr:*mut u8, len:usize, cap:usize), 释放插件分配的 RawBuffer \
+/// mapreduce_alloc : extern "C" fn(len:usize) -> *mut u8, 在插件中分配 len 字节, 只有 wasm 插件用得到:
+/// 框架用它在模块的线性内存中放置输入, 用完之后以 mapreduce_free(ptr, 0, len) 释放 \
+/// mapreduce_set_context : 与 mapreduce_mapper 的签名相同, 输入 TaskContext 的 json, 加载之后调用一次;
+/// 没有导出它的旧插件不会收到任务上下文 \
+/// mapreduce_mapper_emit : emit 风格的 mapper, 原生插件是 extern "C" fn(input:*const u8, input_len:usize,
+/// host:*mut c_void, host_call:HostCallFn, out:*mut RawBuffer) -> i32, 每个键值对都通过 host_call(host, HOST_EMIT, ...)
+/// 立刻交给框架; wasm 插件没有 host, host_call 两个参数, 改为导入 mapreduce 模块中的 host_call 函数.
+/// 命名输出的记录同样通过 host_call(host, HOST_NAMED, ...) 交出, out 中是只有计数器的 MapOutput. 没有导出它的旧插件使用 mapreduce_mapper \
+/// mapreduce_reducer_stream : 流式的 reducer, 参数与 mapreduce_mapper_emit 相同, 输入的是 key 的 json 文本;
+/// 它通过 HOST_NEXT_VALUE, HOST_READ_VALUE 一个一个地取 value, 通过 HOST_OUTPUT 交出输出记录,
+/// 通过 HOST_NAMED 交出命名输出的记录, out 中是只有计数器的 ReduceOutput. 没有导出它的旧插件使用 mapreduce_reducer \
+/// 可选的符号(PluginInfo 中的 combiner, partitioner 为 true 时必须导出): \
+/// mapreduce_combiner : 与 mapreduce_reducer 的签名相同, 输出合并后的 values \
+/// mapreduce_partitioner : extern "C" fn(key:*const u8, key_len:usize, reducer_num:u32, partition:*mut u32) -> i32,
+/// 返回 STATUS_UNHANDLED 表示使用框架默认的哈希分区 \
+/// 一般不需要手写这些符号, 用 export_job! 宏从 Job 的实现生成即可. \
+/// 返回值为 STATUS_OK 时 out 中是结果, 否则 out 中是错误信息(STATUS_PANIC 时是 panic 信息).
+/// 用户代码中的 panic 会在插件内部被捕获, 不会跨过 extern "C" 的边界. out 中的内存一定要交还给插件的 mapreduce_free 释放.
+use std::any::Any;
+use std::ffi::c_void;
+use std::mem::ManuallyDrop;
+use std::panic::{self, AssertUnwindSafe};
+use serde::{Deserialize, Serialize};
+
+use crate::job::{Job, job_map_entry, job_reduce_entry, job_combine_entry, job_partition_entry, job_set_context_entry,
+    job_map_emit_entry, job_reduce_stream_entry};
+
+/// 当前的插件 ABI 版本号, ABI 有任何不兼容的改动都要增加它.
+pub const PLUGIN_ABI_VERSION : u32 = 1;
+
+pub const STATUS_OK : i32 = 0;
+pub const STATUS_ERROR : i32 = 1;
+pub const STATUS_UNHANDLED : i32 = 2;
+pub const STATUS_PANIC : i32 = 3;
+
+/// emit 风格的 mapper 与流式的 reducer 回调框架时的操作:
+/// HOST_EMIT 的两个参数是 key 和 value 的 json 文本, HOST_OUTPUT 的第一个参数是输出记录的 json 文本,
+/// HOST_NAMED 的两个参数是命名输出的名字和记录的 json 文本, emit 风格的 mapper 与流式的 reducer 都可以用;
+/// HOST_PROGRESS 没有参数, 这四个操作中框架返回 STATUS_OK 以外的状态码表示不再接收输出;
+/// HOST_PROGRESS 只用来询问这一点, 框架不做别的处理(不会延长任何限制). \
+/// HOST_NEXT_VALUE 没有参数, 框架前进到下一个 value, 返回它的字节数, 没有更多的 value 时返回 HOST_NO_VALUE,
+/// 出错时返回其它的负数; 之后 HOST_READ_VALUE 的第一个参数是插件分配的同样字节数的缓冲区, 框架把 value 写进去.
+pub const HOST_EMIT : u32 = 0;
+pub const HOST_PROGRESS : u32 = 1;
+pub const HOST_OUTPUT : u32 = 2;
+pub const HOST_NEXT_VALUE : u32 = 3;
+pub const HOST_READ_VALUE : u32 = 4;
+pub const HOST_NAMED : u32 = 5;
+pub const HOST_NO_VALUE : i32 = -1;
+
+/// 由插件分配、交给框架读取的一段内存, 实际上是一个被拆开的 Vec<u8>.
+#[repr(C)]
+pub struct RawBuffer {
+    pub ptr : *mut u8,
+    pub len : usize,
+    pub cap : usize,
+}
+
+impl RawBuffer {
+    pub fn empty() -> RawBuffer {
+        RawBuffer { ptr : std::ptr::null_mut(), len : 0, cap : 0 }
+    }
+}
+
+/// 插件的元信息, 以 json 的形式由 mapreduce_plugin_info 输出.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PluginInfo {
+    pub abi_version : u32,
+    pub name : String,
+    pub version : String,
+    #[serde(default)]
+    pub combiner : bool,
+    #[serde(default)]
+    pub partitioner : bool,
+}
+
+pub type AbiVersionFn = unsafe extern "C" fn() -> u32;
+pub type PluginInfoFn = unsafe extern "C" fn(*mut RawBuffer) -> i32;
+pub type MapperFn = unsafe extern "C" fn(*const u8, usize, *mut RawBuffer) -> i32;
+pub type SetContextFn = MapperFn;
+pub type ReducerFn = unsafe extern "C" fn(*const u8, usize, *const u8, usize, *mut RawBuffer) -> i32;
+pub type FreeFn = unsafe extern "C" fn(*mut u8, usize, usize);
+pub type CombinerFn = ReducerFn;
+pub type PartitionerFn = unsafe extern "C" fn(*const u8, usize, u32, *mut u32) -> i32;
+pub type HostCallFn = unsafe extern "C" fn(*mut c_void, u32, *const u8, usize, *const u8, usize) -> i32;
+pub type MapperEmitFn = unsafe extern "C" fn(*const u8, usize, *mut c_void, HostCallFn, *mut RawBuffer) -> i32;
+pub type ReducerStreamFn = MapperEmitFn;
+
+#[cfg(target_arch = "wasm32")]
+#[link(wasm_import_module = "mapreduce")]
+extern "C" {
+    /// wasm 插件中回调框架的函数, 参数与 HostCallFn 相同, 只是没有 host.
+    #[link_name = "host_call"]
+    fn mapreduce_host_call(op : u32, a : *const u8, a_len : usize, b : *const u8, b_len : usize) -> i32;
+}
+
+// ---------------- 下面是给插件(用户crate)一侧用的辅助函数 ----------------
+
+/// 把一个 String 的所有权交给 out, 之后由 mapreduce_free 释放.
+/// # Safety
+/// out 必须指向一个有效的 RawBuffer.
+pub unsafe fn write_buffer(out : *mut RawBuffer, content : String) {
+    let mut content = ManuallyDrop::new(content.into_bytes());
+    *out = RawBuffer {
+        ptr : content.as_mut_ptr(),
+        len : content.len(),
+        cap : content.capacity(),
+    };
+}
+
+/// 释放 write_buffer 交出去的内存, 插件导出的 mapreduce_free 直接调用它.
+/// # Safety
+/// ptr, len, cap 必须来自同一个插件中 write_buffer 产生的 RawBuffer.
+pub unsafe fn free_buffer(ptr : *mut u8, len : usize, cap : usize) {
+    if !ptr.is_null() {
+        drop(Vec::from_raw_parts(ptr, len, cap));
+    }
+}
+
+/// 分配一段 len 字节的内存交给框架写入, 插件导出的 mapreduce_alloc 直接调用它.
+pub fn alloc_buffer(len : usize) -> *mut u8 {
+    ManuallyDrop::new(Vec::<u8>::with_capacity(len)).as_mut_ptr()
+}
+
+/// 把框架传入的指针与长度还原成 &str.
+/// # Safety
+/// ptr 必须指向至少 len 个有效字节, 并且在返回的引用使用期间一直有效.
+pub unsafe fn read_str<'a>(ptr : *const u8, len : usize) -> Result<&'a str, String> {
+    if len == 0 {
+        return Ok("");
+    }
+    std::str::from_utf8(std::slice::from_raw_parts(ptr, len)).map_err(|e| e.to_string())
+}
+
+/// 把结果写入 out 并转换成返回的状态码.
+/// # Safety
+/// out 必须指向一个有效的 RawBuffer.
+pub unsafe fn finish_call(out : *mut RawBuffer, ret : Result<String, String>) -> i32 {
+    match ret {
+        Ok(content) => {
+            write_buffer(out, content);
+            STATUS_OK
+        }
+        Err(e) => {
+            write_buffer(out, e);
+            STATUS_ERROR
+        }
+    }
+}
+
+/// 从 panic 的 payload 中取出 panic 信息. 插件和框架两边都用它.
+pub fn panic_message(payload : &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic payload")
+    }
+}
+
+/// 执行用户代码并把结果写入 out; 用户代码 panic 时返回 STATUS_PANIC, out 中是 panic 信息.
+/// # Safety
+/// out 必须指向一个有效的 RawBuffer.
+pub unsafe fn guarded_call<F>(out : *mut RawBuffer, f : F) -> i32
+    where F : FnOnce() -> Result<String, String>
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(ret) => finish_call(out, ret),
+        Err(payload) => {
+            write_buffer(out, panic_message(payload.as_ref()));
+            STATUS_PANIC
+        }
+    }
+}
+
+/// mapreduce_plugin_info 的通用实现. options 是 export_job! 中给出的可选项, 如 "combiner".
+/// # Safety
+/// out 必须指向一个有效的 RawBuffer.
+pub unsafe fn plugin_info_entry(name : &str, version : &str, options : &[&str], out : *mut RawBuffer) -> i32 {
+    let info = PluginInfo {
+        abi_version : PLUGIN_ABI_VERSION,
+        name : name.to_string(),
+        version : version.to_string(),
+        combiner : options.contains(&"combiner"),
+        partitioner : options.contains(&"partitioner"),
+    };
+    finish_call(out, serde_json::to_string(&info).map_err(|e| e.to_string()))
+}
+
+/// mapreduce_mapper 的通用实现.
+/// # Safety
+/// input 必须指向 input_len 个有效字节, out 必须指向一个有效的 RawBuffer.
+pub unsafe fn mapper_entry<J : Job>(input : *const u8, input_len : usize, out : *mut RawBuffer) -> i32 {
+    guarded_call(out, || read_str(input, input_len).and_then(job_map_entry::<J>))
+}
+
+/// 原生插件中 mapreduce_mapper_emit 的通用实现.
+/// # Safety
+/// input 必须指向 input_len 个有效字节, host 与 host_call 是框架给出的, out 必须指向一个有效的 RawBuffer.
+#[cfg(not(target_arch = "wasm32"))]
+pub unsafe fn mapper_emit_entry<J : Job>(
+    input : *const u8, input_len : usize,
+    host : *mut c_void, host_call : HostCallFn,
+    out : *mut RawBuffer) -> i32 {
+    guarded_call(out, || read_str(input, input_len).and_then(|content| {
+        job_map_emit_entry::<J>(content, &mut |op, a, b| {
+            host_call(host, op, a.as_ptr(), a.len(), b.as_ptr(), b.len()) == STATUS_OK
+        })
+    }))
+}
+
+/// wasm 插件中 mapreduce_mapper_emit 的通用实现, 通过导入的 host_call 回调框架.
+/// # Safety
+/// input 必须指向 input_len 个有效字节, out 必须指向一个有效的 RawBuffer.
+#[cfg(target_arch = "wasm32")]
+pub unsafe fn mapper_emit_entry<J : Job>(input : *const u8, input_len : usize, out : *mut RawBuffer) -> i32 {
+    guarded_call(out, || read_str(input, input_len).and_then(|content| {
+        job_map_emit_entry::<J>(content, &mut |op, a, b| {
+            mapreduce_host_call(op, a.as_ptr(), a.len(), b.as_ptr(), b.len()) == STATUS_OK
+        })
+    }))
+}
+
+/// 通过 call(op, buf, buf_len) 回调框架, 按 HOST_NEXT_VALUE, HOST_READ_VALUE 取出下一个 value 的 json 文本.
+/// # Safety
+/// call 必须是框架给出的回调.
+unsafe fn next_value_from<F>(mut call : F) -> Result<Option<String>, String>
+    where F : FnMut(u32, *const u8, usize) -> i32
+{
+    let len = call(HOST_NEXT_VALUE, std::ptr::null(), 0);
+    if len == HOST_NO_VALUE {
+        return Ok(None);
+    }
+    if len < 0 {
+        return Err(String::from("the framework failed to read the next value"));
+    }
+    let mut value = vec![0u8; len as usize];
+    if call(HOST_READ_VALUE, value.as_mut_ptr(), value.len()) != STATUS_OK {
+        return Err(String::from("the framework failed to read the next value"));
+    }
+    String::from_utf8(value).map(Some).map_err(|e| e.to_string())
+}
+
+/// 原生插件中 mapreduce_reducer_stream 的通用实现.
+/// # Safety
+/// key 必须指向 key_len 个有效字节, host 与 host_call 是框架给出的, out 必须指向一个有效的 RawBuffer.
+#[cfg(not(target_arch = "wasm32"))]
+pub unsafe fn reducer_stream_entry<J : Job>(
+    key : *const u8, key_len : usize,
+    host : *mut c_void, host_call : HostCallFn,
+    out : *mut RawBuffer) -> i32 {
+    guarded_call(out, || read_str(key, key_len).and_then(|key| {
+        job_reduce_stream_entry::<J>(key,
+            &mut |op, a, b| host_call(host, op, a.as_ptr(), a.len(), b.as_ptr(), b.len()) == STATUS_OK,
+            &mut || next_value_from(|op, buf, len| host_call(host, op, buf, len, std::ptr::null(), 0)))
+    }))
+}
+
+/// wasm 插件中 mapreduce_reducer_stream 的通用实现, 通过导入的 host_call 回调框架.
+/// # Safety
+/// key 必须指向 key_len 个有效字节, out 必须指向一个有效的 RawBuffer.
+#[cfg(target_arch = "wasm32")]
+pub unsafe fn reducer_stream_entry<J : Job>(key : *const u8, key_len : usize, out : *mut RawBuffer) -> i32 {
+    guarded_call(out, || read_str(key, key_len).and_then(|key| {
+        job_reduce_stream_entry::<J>(key,
+            &mut |op, a, b| mapreduce_host_call(op, a.as_ptr(), a.len(), b.as_ptr(), b.len()) == STATUS_OK,
+            &mut || next_value_from(|op, buf, len| mapreduce_host_call(op, buf, len, std::ptr::null(), 0)))
+    }))
+}
+
+/// mapreduce_set_context 的通用实现.
+/// # Safety
+/// context 必须指向 context_len 个有效字节, out 必须指向一个有效的 RawBuffer.
+pub unsafe fn set_context_entry(context : *const u8, context_len : usize, out : *mut RawBuffer) -> i32 {
+    guarded_call(out, || read_str(context, context_len).and_then(job_set_context_entry))
+}
+
+/// mapreduce_reducer 的通用实现.
+/// # Safety
+/// key, values 必须分别指向 key_len, values_len 个有效字节, out 必须指向一个有效的 RawBuffer.
+pub unsafe fn reducer_entry<J : Job>(
+    key : *const u8, key_len : usize,
+    values : *const u8, values_len : usize,
+    out : *mut RawBuffer) -> i32 {
+    guarded_call(out, || read_str(key, key_len).and_then(|key| {
+        read_str(values, values_len).and_then(|values| job_reduce_entry::<J>(key, values))
+    }))
+}
+
+/// mapreduce_combiner 的通用实现.
+/// # Safety
+/// 与 reducer_entry 相同.
+pub unsafe fn combiner_entry<J : Job>(
+    key : *const u8, key_len : usize,
+    values : *const u8, values_len : usize,
+    out : *mut RawBuffer) -> i32 {
+    guarded_call(out, || read_str(key, key_len).and_then(|key| {
+        read_str(values, values_len).and_then(|values| job_combine_entry::<J>(key, values))
+    }))
+}
+
+/// mapreduce_partitioner 的通用实现. 出错时没有办法带回错误信息, 只返回 STATUS_ERROR 或 STATUS_PANIC.
+/// # Safety
+/// key 必须指向 key_len 个有效字节, partition 必须指向一个有效的 u32.
+pub unsafe fn partitioner_entry<J : Job>(
+    key : *const u8, key_len : usize, reducer_num : u32, partition : *mut u32) -> i32 {
+    let ret = panic::catch_unwind(|| {
+        read_str(key, key_len).and_then(|key| job_partition_entry::<J>(key, reducer_num))
+    });
+    match ret {
+        Ok(Ok(Some(p))) => {
+            *partition = p;
+            STATUS_OK
+        }
+        Ok(Ok(None)) => STATUS_UNHANDLED,
+        Ok(Err(_)) => STATUS_ERROR,
+        Err(_) => STATUS_PANIC,
+    }
+}
+
+/// 从一个实现了 Job(以及 Default) 的类型生成插件需要导出的全部符号.
+/// 插件名是类型名, 版本号是用户 crate 的版本号. 后面可以跟可选项 combiner, partitioner,
+/// 此时会额外导出对应的符号, 框架才会调用 Job::combine, Job::partition. \
+/// 同一份代码既可以编译成原生动态库(cdylib), 也可以编译成 wasm32 的模块. \
+/// 例: `MapReduce::export_job!(WordCount, combiner);`
+#[macro_export]
+macro_rules! export_job {
+    (@core $job:ty, [$($option:ident),*]) => {
+        #[no_mangle]
+        pub extern "C" fn mapreduce_abi_version() -> u32 {
+            $crate::plugin::abi::PLUGIN_ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_plugin_info(out : *mut $crate::plugin::abi::RawBuffer) -> i32 {
+            let options : &[&str] = &[$(stringify!($option)),*];
+            $crate::plugin::abi::plugin_info_entry(stringify!($job), env!("CARGO_PKG_VERSION"), options, out)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_mapper(
+            input : *const u8, input_len : usize,
+            out : *mut $crate::plugin::abi::RawBuffer) -> i32 {
+            $crate::plugin::abi::mapper_entry::<$job>(input, input_len, out)
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_mapper_emit(
+            input : *const u8, input_len : usize,
+            host : *mut ::std::ffi::c_void, host_call : $crate::plugin::abi::HostCallFn,
+            out : *mut $crate::plugin::abi::RawBuffer) -> i32 {
+            $crate::plugin::abi::mapper_emit_entry::<$job>(input, input_len, host, host_call, out)
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_mapper_emit(
+            input : *const u8, input_len : usize,
+            out : *mut $crate::plugin::abi::RawBuffer) -> i32 {
+            $crate::plugin::abi::mapper_emit_entry::<$job>(input, input_len, out)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_reducer(
+            key : *const u8, key_len : usize,
+            values : *const u8, values_len : usize,
+            out : *mut $crate::plugin::abi::RawBuffer) -> i32 {
+            $crate::plugin::abi::reducer_entry::<$job>(key, key_len, values, values_len, out)
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_reducer_stream(
+            key : *const u8, key_len : usize,
+            host : *mut ::std::ffi::c_void, host_call : $crate::plugin::abi::HostCallFn,
+            out : *mut $crate::plugin::abi::RawBuffer) -> i32 {
+            $crate::plugin::abi::reducer_stream_entry::<$job>(key, key_len, host, host_call, out)
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_reducer_stream(
+            key : *const u8, key_len : usize,
+            out : *mut $crate::plugin::abi::RawBuffer) -> i32 {
+            $crate::plugin::abi::reducer_stream_entry::<$job>(key, key_len, out)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_set_context(
+            context : *const u8, context_len : usize,
+            out : *mut $crate::plugin::abi::RawBuffer) -> i32 {
+            $crate::plugin::abi::set_context_entry(context, context_len, out)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_free(ptr : *mut u8, len : usize, cap : usize) {
+            $crate::plugin::abi::free_buffer(ptr, len, cap)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mapreduce_alloc(len : usize) -> *mut u8 {
+            $crate::plugin::abi::alloc_buffer(len)
+        }
+    };
+    (@combiner $job:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_combiner(
+            key : *const u8, key_len : usize,
+            values : *const u8, values_len : usize,
+            out : *mut $crate::plugin::abi::RawBuffer) -> i32 {
+            $crate::plugin::abi::combiner_entry::<$job>(key, key_len, values, values_len, out)
+        }
+    };
+    (@partitioner $job:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn mapreduce_partitioner(
+            key : *const u8, key_len : usize, reducer_num : u32, partition : *mut u32) -> i32 {
+            $crate::plugin::abi::partitioner_entry::<$job>(key, key_len, reducer_num, partition)
+        }
+    };
+    ($job:ty $(, $option:ident)* $(,)?) => {
+        $crate::export_job!(@core $job, [$($option),*]);
+        $( $crate::export_job!(@$option $job); )*
+    };
+}
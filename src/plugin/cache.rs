@@ -0,0 +1,111 @@
+/// 按任务缓存已经加载好的插件, 同一个任务的所有 mapper/reducer 子任务共用一份.
+/// 插件在第一次用到时加载并检查, 在 master 的清理阶段卸载.
+///
+/// 动态库加载器会按路径复用已经打开的库: 两个任务的插件路径名相同(或者文件被覆盖)时,
+/// 第二个任务会拿到第一个任务的库. 所以加载原生动态库之前先把它复制成这个任务私有的文件.
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use once_cell::sync::{Lazy, OnceCell};
+
+use crate::error::MapReduceError;
+use crate::io_wrapper::{iowrapper_exist, iowrapper_get_filename, iowrapper_read_dir_into_strings, path_join};
+use crate::job::TaskContext;
+use crate::map_reduce::{JobConfig, SubtaskLimits, CACHE_DIR};
+use crate::plugin::{Plugin, PluginKind};
+
+/// 一个任务的缓存位置. 插件在 loaded 中加载, 加载期间不持有整个缓存的锁, 其他任务的子任务不必等待.
+struct CachedPlugin {
+    dllpath : String,          // 任务给出的原始路径, 用来检查同一个任务是不是换了插件.
+    loaded : OnceCell<LoadedPlugin>,
+}
+
+struct LoadedPlugin {
+    local_copy : Option<PathBuf>,   // 实际加载的私有副本, 只有原生动态库才有.
+    plugin : Arc<Plugin>,
+}
+
+static PLUGIN_CACHE : Lazy<Mutex<HashMap<u32, Arc<CachedPlugin>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 插件私有副本的位置: 临时目录下, 以进程号与任务号区分.
+fn local_copy_path(task_id : u32, dllpath : &str) -> PathBuf {
+    let file_name = Path::new(dllpath).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("plugin"));
+    env::temp_dir().join(format!("mapreduce_plugin_{}_{}_{}", std::process::id(), task_id, file_name))
+}
+
+/// 任务的上下文: server 已经把附属文件放在任务目录 base_dir 下的 CACHE_DIR 中, config 是任务的配置参数.
+fn task_context(task_id : u32, base_dir : &String, config : &JobConfig) -> Result<TaskContext, MapReduceError> {
+    let mut context = TaskContext { task_id, config : config.clone(), ..TaskContext::default() };
+    let cache_dir = path_join(base_dir, &CACHE_DIR.to_string());
+    if iowrapper_exist(&cache_dir) {
+        for path in iowrapper_read_dir_into_strings(&cache_dir)? {
+            context.cache_files.insert(iowrapper_get_filename(&path)?, path);
+        }
+        context.cache_dir = Some(cache_dir);
+    }
+    Ok(context)
+}
+
+/// 取得任务 task_id 的插件, 第一次调用时加载. base_dir 是任务目录, limits 是这个任务的资源限制, wasm 插件要用到;
+/// config 是任务的配置参数. 同一个任务的多个 worker 线程同时到来时, 只有一个加载, 其余的等它加载完;
+/// 加载失败的话下一个到来的线程重新加载.
+pub fn plugin_for_task(task_id : u32, dllpath : &String, base_dir : &String, limits : &SubtaskLimits, config : &JobConfig)
+    -> Result<Arc<Plugin>, MapReduceError> {
+    let cached = {
+        let mut cache = PLUGIN_CACHE.lock().unwrap();
+        cache.entry(task_id)
+            .or_insert_with(|| Arc::new(CachedPlugin { dllpath : dllpath.clone(), loaded : OnceCell::new() }))
+            .clone()
+    };
+    if cached.dllpath != *dllpath {
+        return Err(MapReduceError::DllLoadingError {
+            fntype : String::from("library"),
+            reason : format!("task {} already loaded plugin {}, but {} is requested", task_id, cached.dllpath, dllpath),
+        });
+    }
+    let loaded = cached.loaded.get_or_try_init(|| load_plugin(task_id, dllpath, base_dir, limits, config))?;
+    Ok(loaded.plugin.clone())
+}
+
+/// 加载任务 task_id 的插件, 原生动态库先复制成私有副本.
+fn load_plugin(task_id : u32, dllpath : &String, base_dir : &String, limits : &SubtaskLimits, config : &JobConfig)
+    -> Result<LoadedPlugin, MapReduceError> {
+    // 只有原生动态库需要私有副本; streaming 的 manifest 要按原来的位置找到可执行文件.
+    let local_copy = match PluginKind::detect(dllpath)? {
+        PluginKind::Native => {
+            let local_copy = local_copy_path(task_id, dllpath);
+            fs::copy(dllpath, &local_copy)?;
+            Some(local_copy)
+        }
+        _ => None,
+    };
+    let load_path = match &local_copy {
+        Some(local_copy) => local_copy.to_string_lossy().into_owned(),
+        None => dllpath.clone(),
+    };
+    match task_context(task_id, base_dir, config).and_then(|context| Plugin::load(&load_path, limits, &context)) {
+        Ok(plugin) => Ok(LoadedPlugin { local_copy, plugin : Arc::new(plugin) }),
+        Err(e) => {
+            if let Some(local_copy) = &local_copy {
+                let _ = fs::remove_file(local_copy);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// 卸载任务 task_id 的插件并删除私有副本. 还在使用插件的子任务手里有 Arc, 用完之后库才真正关闭.
+pub fn evict_task(task_id : u32) {
+    let cached = PLUGIN_CACHE.lock().unwrap().remove(&task_id);
+    if let Some(loaded) = cached.as_ref().and_then(|cached| cached.loaded.get()) {
+        // 库已经映射进内存, 删除文件不影响仍在使用的副本.
+        if let Some(local_copy) = &loaded.local_copy {
+            let _ = fs::remove_file(local_copy);
+        }
+    }
+}
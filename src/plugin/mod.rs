@@ -0,0 +1,392 @@
+/// 用户插件: abi 是插件与框架共同遵守的 C ABI, 插件一侧也要用到;
+/// 其余的是框架一侧加载、调用插件的代码, cache 按任务缓存加载好的插件. \
+/// 插件可以是原生动态库, 也可以是 wasm 模块, 两者导出同样的函数;
+/// 还可以是 streaming 任务的 manifest, 它指向作为 mapper, reducer 的两个可执行文件;
+/// 或者是带着 rhai 脚本源码的 manifest. 插件的种类由文件内容区分.
+pub mod abi;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod cache;
+#[cfg(not(target_arch = "wasm32"))]
+mod native;
+#[cfg(not(target_arch = "wasm32"))]
+mod wasm;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod streaming;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod script;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use host::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod host {
+    use std::collections::BTreeMap;
+    use std::io::Read;
+    use std::iter::Peekable;
+    use serde_json::Value;
+
+    use crate::error::MapReduceError;
+    use crate::job::{MapOutput, ReduceOutput, TaskContext};
+    use crate::map_reduce::SubtaskLimits;
+    use crate::plugin::abi::*;
+    pub(crate) use crate::plugin::native::NativePlugin;
+    pub(crate) use crate::plugin::wasm::WasmPlugin;
+    pub(crate) use crate::plugin::streaming::StreamingPlugin;
+    pub(crate) use crate::plugin::script::ScriptPlugin;
+
+    /// wasm 模块文件开头的魔数.
+    const WASM_MAGIC : &[u8; 4] = b"\0asm";
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PluginKind {
+        Native,
+        Wasm,
+        Streaming,
+        Script,
+    }
+
+    impl PluginKind {
+        /// 根据文件内容判断插件的种类. server 上的插件文件名是固定的, 所以不看扩展名.
+        /// 以 { 开头的是 json manifest, 其中有 script 的是脚本, 否则是 streaming 任务.
+        pub fn detect(path : &String) -> Result<PluginKind, MapReduceError> {
+            let mut magic = [0u8; 4];
+            let mut file = std::fs::File::open(path)?;
+            match file.read_exact(&mut magic) {
+                Ok(()) if &magic == WASM_MAGIC => Ok(PluginKind::Wasm),
+                Ok(()) if magic[0] == b'{' => {
+                    let manifest : Value = serde_json::from_str(&std::fs::read_to_string(path)?)
+                        .map_err(|e| MapReduceError::DllLoadingError {
+                            fntype : String::from("manifest"),
+                            reason : e.to_string(),
+                        })?;
+                    match manifest.get("script") {
+                        Some(_) => Ok(PluginKind::Script),
+                        None => Ok(PluginKind::Streaming),
+                    }
+                }
+                _ => Ok(PluginKind::Native),
+            }
+        }
+    }
+
+    /// 一个加载好的插件, 框架只通过它调用用户代码.
+    pub enum Plugin {
+        Native(NativePlugin),
+        Wasm(WasmPlugin),
+        Streaming(StreamingPlugin),
+        Script(Box<ScriptPlugin>),   // rhai 的 Engine 很大, 放到堆上.
+    }
+
+    impl Plugin {
+        /// 加载插件并检查需要的导出. limits 中 wasm 的限制只对 wasm 插件有效(wasm_fuel 也限制脚本的操作数);
+        /// context 是任务的上下文, 其中有任务的配置参数与附属文件(wasm 插件用不了附属文件).
+        pub fn load(path : &String, limits : &SubtaskLimits, context : &TaskContext) -> Result<Plugin, MapReduceError> {
+            match PluginKind::detect(path)? {
+                PluginKind::Native => Ok(Plugin::Native(NativePlugin::load(path, context)?)),
+                PluginKind::Wasm => Ok(Plugin::Wasm(WasmPlugin::load(path, limits, context)?)),
+                PluginKind::Streaming => Ok(Plugin::Streaming(StreamingPlugin::load(path, context)?)),
+                PluginKind::Script => Ok(Plugin::Script(Box::new(ScriptPlugin::load(path, limits, context)?))),
+            }
+        }
+
+        pub fn kind(&self) -> PluginKind {
+            match self {
+                Plugin::Native(_) => PluginKind::Native,
+                Plugin::Wasm(_) => PluginKind::Wasm,
+                Plugin::Streaming(_) => PluginKind::Streaming,
+                Plugin::Script(_) => PluginKind::Script,
+            }
+        }
+
+        pub fn info(&self) -> &PluginInfo {
+            match self {
+                Plugin::Native(plugin) => plugin.info(),
+                Plugin::Wasm(plugin) => plugin.info(),
+                Plugin::Streaming(plugin) => plugin.info(),
+                Plugin::Script(plugin) => plugin.info(),
+            }
+        }
+
+        pub fn map(&self, content : &str) -> Result<MapOutput, Box<dyn std::error::Error>> {
+            match self {
+                Plugin::Native(plugin) => plugin.map(content),
+                Plugin::Wasm(plugin) => plugin.map(content),
+                Plugin::Streaming(plugin) => plugin.map(content),
+                Plugin::Script(plugin) => plugin.map(content),
+            }
+        }
+
+        /// 执行 mapper, 键值对一个一个地交给 emitter, 命名输出的记录一条一条地交给 named, 返回只有计数器的 MapOutput.
+        /// 导出了 mapreduce_mapper_emit 的插件边执行边交出; streaming 任务边读 mapper 的输出边交出;
+        /// 旧的插件和脚本先得到全部的键值对再逐个交出.
+        pub fn map_emit(&self, content : &str, emitter : &mut dyn Emit, named : &mut dyn NamedSink)
+            -> Result<MapOutput, Box<dyn std::error::Error>> {
+            let mut output = match self {
+                Plugin::Native(plugin) => plugin.map_emit(content, emitter, named)?,
+                Plugin::Wasm(plugin) => plugin.map_emit(content, emitter, named)?,
+                Plugin::Streaming(plugin) => plugin.map_emit(content, emitter)?,
+                Plugin::Script(plugin) => emit_pairs(plugin.map(content)?, emitter)?,
+            };
+            write_named(std::mem::take(&mut output.named), named)?;
+            Ok(output)
+        }
+
+        pub fn reduce(&self, key : &str, values : &str) -> Result<String, Box<dyn std::error::Error>> {
+            match self {
+                Plugin::Native(plugin) => plugin.reduce(key, values),
+                Plugin::Wasm(plugin) => plugin.reduce(key, values),
+                Plugin::Streaming(plugin) => plugin.reduce(key, values),
+                Plugin::Script(plugin) => plugin.reduce(key, values),
+            }
+        }
+
+        /// 对一个 key 执行 reducer: value 从 values 中一个一个地取, 输出记录一条一条地交给 values,
+        /// 命名输出的记录一条一条地交给 named. 返回只有计数器的 ReduceOutput.
+        /// 导出了 mapreduce_reducer_stream 的插件边执行边取 value, 其余的插件先取出全部的 value 再调用 reducer.
+        pub fn reduce_stream(&self, key : &str, values : &mut dyn ReduceStream, named : &mut dyn NamedSink)
+            -> Result<ReduceOutput, Box<dyn std::error::Error>> {
+            let mut output = match self {
+                Plugin::Native(plugin) => plugin.reduce_stream(key, values, named)?,
+                Plugin::Wasm(plugin) => plugin.reduce_stream(key, values, named)?,
+                Plugin::Streaming(_) | Plugin::Script(_) => reduce_collected(|v| self.reduce(key, v), values)?,
+            };
+            write_named(std::mem::take(&mut output.named), named)?;
+            Ok(output)
+        }
+
+        /// 对一个分区中所有的 key 执行 reducer. pairs 是按 key 排好序的 (key, value) 序列, 同一个 key 的连在一起;
+        /// 每个 key 先 sink.start, 然后是它的输出记录; 命名输出的记录交给 named. 返回所有 key 的计数器.
+        /// streaming 插件整个分区只启动一次 reducer.
+        pub fn reduce_sorted(&self, pairs : &mut SortedPairs, sink : &mut dyn ReduceSink, named : &mut dyn NamedSink)
+            -> Result<ReduceOutput, Box<dyn std::error::Error>> {
+            if let Plugin::Streaming(plugin) = self {
+                return plugin.reduce_sorted(pairs, sink);
+            }
+            let mut pairs = pairs.peekable();
+            let mut ret = ReduceOutput::default();
+            while let Some(pair) = pairs.next() {
+                let (key, value) = pair?;
+                sink.start(&key)?;
+                let mut values = KeyValues { key : &key, first : Some(value), pairs : &mut pairs, sink };
+                let output = self.reduce_stream(&key, &mut values, named)?;
+                // 用户代码没有读完的 value 跳过.
+                while values.next_value()?.is_some() {}
+                for (name, amount) in output.counters {
+                    *ret.counters.entry(name).or_default() += amount;
+                }
+            }
+            Ok(ret)
+        }
+
+        pub fn combine(&self, key : &str, values : &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+            match self {
+                Plugin::Native(plugin) => plugin.combine(key, values),
+                Plugin::Wasm(plugin) => plugin.combine(key, values),
+                Plugin::Streaming(_) => Ok(None),
+                Plugin::Script(plugin) => plugin.combine(key, values),
+            }
+        }
+
+        pub fn partition(&self, key : &str, reducer_num : u32) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+            match self {
+                Plugin::Native(plugin) => plugin.partition(key, reducer_num),
+                Plugin::Wasm(plugin) => plugin.partition(key, reducer_num),
+                Plugin::Streaming(_) => Ok(None),
+                Plugin::Script(plugin) => plugin.partition(key, reducer_num),
+            }
+        }
+    }
+
+    /// 接收 mapper 输出的键值对, key 与 value 都是 json 文本.
+    pub trait Emit {
+        fn emit(&mut self, key : &str, value : &str) -> Result<(), Box<dyn std::error::Error>>;
+    }
+
+    /// 一个 key 的 reducer 的输入和输出: 一个一个地给出 value, 一条一条地接收输出记录, 都是 json 文本.
+    pub trait ReduceStream {
+        fn next_value(&mut self) -> Result<Option<String>, Box<dyn std::error::Error>>;
+        fn output(&mut self, record : &str) -> Result<(), Box<dyn std::error::Error>>;
+    }
+
+    /// 接收一个分区的 reducer 输出: 每个 key 先 start(key 的 json 文本), 然后是它的输出记录.
+    pub trait ReduceSink {
+        fn start(&mut self, key : &str) -> Result<(), Box<dyn std::error::Error>>;
+        fn output(&mut self, record : &str) -> Result<(), Box<dyn std::error::Error>>;
+    }
+
+    /// 接收命名输出的记录: 输出名与记录的 json 文本.
+    pub trait NamedSink {
+        fn write(&mut self, name : &str, record : &str) -> Result<(), Box<dyn std::error::Error>>;
+    }
+
+    /// 按 key 排好序的 (key, value) 序列, 都是 json 文本. 中间文件归并之后就是这样的序列.
+    /// streaming 插件在另一个线程中把它写给 reducer, 所以要求 Send.
+    pub type SortedPairs<'a> = dyn Iterator<Item = Result<(String, String), Box<dyn std::error::Error>>> + Send + 'a;
+
+    /// reduce_sorted 中一个 key 的 values: 从 pairs 中取出同一个 key 的 value, 直到下一个 key 为止.
+    struct KeyValues<'a, 'b, 'c> {
+        key : &'a str,
+        first : Option<String>,
+        pairs : &'a mut Peekable<&'b mut SortedPairs<'c>>,
+        sink : &'a mut dyn ReduceSink,
+    }
+
+    impl ReduceStream for KeyValues<'_, '_, '_> {
+        fn next_value(&mut self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+            if let Some(value) = self.first.take() {
+                return Ok(Some(value));
+            }
+            let key = self.key;
+            match self.pairs.next_if(|pair| matches!(pair, Ok((k, _)) if k == key)) {
+                Some(pair) => Ok(Some(pair?.1)),
+                None => match self.pairs.next_if(|pair| pair.is_err()) {
+                    Some(pair) => Err(pair.unwrap_err()),
+                    None => Ok(None),
+                },
+            }
+        }
+
+        fn output(&mut self, record : &str) -> Result<(), Box<dyn std::error::Error>> {
+            self.sink.output(record)
+        }
+    }
+
+    /// 给不支持流式 reducer 的插件用: 先取出全部的 value, 以 json 数组文本交给 reduce,
+    /// 再把输出记录逐个交出, 返回去掉了输出记录的 ReduceOutput.
+    pub(crate) fn reduce_collected<F>(reduce : F, values : &mut dyn ReduceStream) -> Result<ReduceOutput, Box<dyn std::error::Error>>
+        where F : FnOnce(&str) -> Result<String, Box<dyn std::error::Error>>
+    {
+        let mut all = Vec::new();
+        while let Some(value) = values.next_value()? {
+            all.push(value);
+        }
+        let output = reduce(&format!("[{}]", all.join(",")))?;
+        let mut output = ReduceOutput::from_value(serde_json::from_str(&output)?)
+            .map_err(|e| format!("user reducer returned a wrong value: {}", e))?;
+        for record in std::mem::take(&mut output.outputs) {
+            values.output(&serde_json::to_string(&record)?)?;
+        }
+        Ok(output)
+    }
+
+    /// 把一次返回的全部键值对逐个交给 emitter, 返回去掉了键值对的 MapOutput.
+    pub(crate) fn emit_pairs(mut output : MapOutput, emitter : &mut dyn Emit) -> Result<MapOutput, Box<dyn std::error::Error>> {
+        for (k, v) in std::mem::take(&mut output.pairs) {
+            emitter.emit(&serde_json::to_string(&k)?, &serde_json::to_string(&v)?)?;
+        }
+        Ok(output)
+    }
+
+    /// 把一次返回的全部命名输出的记录逐个交给 named.
+    pub(crate) fn write_named(records : BTreeMap<String, Vec<Value>>, named : &mut dyn NamedSink) -> Result<(), Box<dyn std::error::Error>> {
+        for (name, records) in records {
+            for record in records {
+                named.write(&name, &serde_json::to_string(&record)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 回调出错时返回给插件的状态码: HOST_NEXT_VALUE 返回的是字节数, 出错时是 HOST_NO_VALUE 以外的负数.
+    fn failed_status(op : u32) -> i32 {
+        if op == HOST_NEXT_VALUE { HOST_NO_VALUE - 1 } else { STATUS_ERROR }
+    }
+
+    /// 插件回调框架时由谁处理: emit 风格的 mapper 或者流式的 reducer.
+    enum HostHandler<'a> {
+        Map(&'a mut dyn Emit),
+        Reduce(&'a mut dyn ReduceStream),
+    }
+
+    /// 一次 emit 风格的 mapper 或者流式的 reducer 调用中, 插件回调框架时用到的状态. 第一次出错之后就不再接收输出,
+    /// 这个错误在调用结束之后代替插件的返回值报告出去.
+    pub(crate) struct HostCallState<'a> {
+        handler : HostHandler<'a>,
+        named : &'a mut dyn NamedSink,
+        value : Option<String>,   // HOST_NEXT_VALUE 取出、还没有被 HOST_READ_VALUE 读走的 value.
+        error : Option<Box<dyn std::error::Error>>,
+    }
+
+    impl<'a> HostCallState<'a> {
+        pub fn new(emitter : &'a mut dyn Emit, named : &'a mut dyn NamedSink) -> HostCallState<'a> {
+            HostCallState { handler : HostHandler::Map(emitter), named, value : None, error : None }
+        }
+
+        pub fn for_reduce(values : &'a mut dyn ReduceStream, named : &'a mut dyn NamedSink) -> HostCallState<'a> {
+            HostCallState { handler : HostHandler::Reduce(values), named, value : None, error : None }
+        }
+
+        /// 处理插件的一次回调, 参数是插件内存中的字节, 返回给插件的状态码(HOST_NEXT_VALUE 时是 value 的字节数).
+        /// HOST_READ_VALUE 要写插件的内存, 由调用者通过 take_value 处理.
+        pub fn call(&mut self, op : u32, a : &[u8], b : &[u8]) -> i32 {
+            if self.error.is_some() {
+                return failed_status(op);
+            }
+            let ret = match (op, &mut self.handler) {
+                (HOST_EMIT, HostHandler::Map(emitter)) => std::str::from_utf8(a)
+                    .and_then(|key| std::str::from_utf8(b).map(|value| (key, value)))
+                    .map_err(|e| e.into())
+                    .and_then(|(key, value)| emitter.emit(key, value))
+                    .map(|()| STATUS_OK),
+                (HOST_OUTPUT, HostHandler::Reduce(values)) => std::str::from_utf8(a)
+                    .map_err(|e| e.into())
+                    .and_then(|record| values.output(record))
+                    .map(|()| STATUS_OK),
+                (HOST_NEXT_VALUE, HostHandler::Reduce(values)) => values.next_value().and_then(|value| match value {
+                    Some(value) => {
+                        let len = i32::try_from(value.len()).map_err(|_| "a value is too large for the plugin")?;
+                        self.value = Some(value);
+                        Ok(len)
+                    }
+                    None => Ok(HOST_NO_VALUE),
+                }),
+                (HOST_NAMED, _) => std::str::from_utf8(a)
+                    .and_then(|name| std::str::from_utf8(b).map(|record| (name, record)))
+                    .map_err(|e| e.into())
+                    .and_then(|(name, record)| self.named.write(name, record))
+                    .map(|()| STATUS_OK),
+                // 没有出错就是还在接收输出; 出错的话上面已经返回了失败的状态码.
+                (HOST_PROGRESS, _) => Ok(STATUS_OK),
+                _ => Err(format!("user code made an unexpected host call {}", op).into()),
+            };
+            match ret {
+                Ok(status) => status,
+                Err(e) => {
+                    self.error = Some(e);
+                    failed_status(op)
+                }
+            }
+        }
+
+        /// 处理 HOST_READ_VALUE: 取出 HOST_NEXT_VALUE 给出的 value, len 是插件缓冲区的大小, 必须与 value 一样长.
+        pub fn take_value(&mut self, len : usize) -> Option<String> {
+            match self.value.take() {
+                Some(value) if value.len() == len => Some(value),
+                _ => {
+                    self.error.get_or_insert_with(|| "user code read a value incorrectly".into());
+                    None
+                }
+            }
+        }
+
+        /// 调用结束之后取出回调中的错误.
+        pub fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+            match self.error {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// 状态码不是 STATUS_OK 时, content 就是插件给出的错误信息或 panic 信息.
+    pub(crate) fn check_status(fntype : &str, status : i32, content : String) -> Result<String, Box<dyn std::error::Error>> {
+        match status {
+            STATUS_OK => Ok(content),
+            STATUS_PANIC => Err(Box::new(MapReduceError::UserCodePanicked {
+                fntype : fntype.to_string(),
+                message : content,
+            })),
+            _ => Err(format!("user {} failed: {}", fntype, content).into()),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+/// 加载原生动态库形式的插件, 只通过 abi 中定义的 extern "C" 符号与它交互.
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use libloading::Library;
+
+use crate::error::MapReduceError;
+use crate::job::{MapOutput, ReduceOutput, TaskContext};
+use crate::plugin::abi::*;
+use crate::plugin::{check_status, emit_pairs, reduce_collected, Emit, HostCallState, NamedSink, ReduceStream};
+
+pub struct NativePlugin {
+    info : PluginInfo,
+    mapper : MapperFn,
+    mapper_emit : Option<MapperEmitFn>,   // 旧的插件没有.
+    reducer : ReducerFn,
+    reducer_stream : Option<ReducerStreamFn>,   // 旧的插件没有.
+    combiner : Option<CombinerFn>,
+    partitioner : Option<PartitionerFn>,
+    free : FreeFn,
+    // 上面的函数指针都指向 lib 里面, lib 必须比它们活得久, 所以放在最后(最后 drop).
+    _lib : Library,
+}
+
+/// 取出一个符号并复制出函数指针, 失败时给出是哪个符号.
+unsafe fn get_symbol<T : Copy>(lib : &Library, name : &str) -> Result<T, MapReduceError> {
+    match lib.get::<T>(name.as_bytes()) {
+        Ok(symbol) => Ok(*symbol),
+        Err(e) => Err(MapReduceError::DllLoadingError {
+            fntype : name.to_string(),
+            reason : e.to_string(),
+        }),
+    }
+}
+
+impl NativePlugin {
+    /// 加载插件: 先检查 ABI 版本号, 版本一致之后再检查其余的符号并读取插件信息,
+    /// 最后把任务的上下文交给插件.
+    pub fn load(path : &String, context : &TaskContext) -> Result<NativePlugin, MapReduceError> {
+        unsafe {
+            let lib = Library::new(path).map_err(|e| MapReduceError::DllLoadingError {
+                fntype : String::from("library"),
+                reason : e.to_string(),
+            })?;
+            let abi_version : AbiVersionFn = get_symbol(&lib, "mapreduce_abi_version")?;
+            let found = abi_version();
+            if found != PLUGIN_ABI_VERSION {
+                return Err(MapReduceError::DllLoadingError {
+                    fntype : String::from("mapreduce_abi_version"),
+                    reason : format!("plugin ABI version is {}, but {} is expected", found, PLUGIN_ABI_VERSION),
+                });
+            }
+            let plugin_info : PluginInfoFn = get_symbol(&lib, "mapreduce_plugin_info")?;
+            let mapper : MapperFn = get_symbol(&lib, "mapreduce_mapper")?;
+            let mapper_emit : Option<MapperEmitFn> = get_symbol(&lib, "mapreduce_mapper_emit").ok();
+            let reducer : ReducerFn = get_symbol(&lib, "mapreduce_reducer")?;
+            let reducer_stream : Option<ReducerStreamFn> = get_symbol(&lib, "mapreduce_reducer_stream").ok();
+            let free : FreeFn = get_symbol(&lib, "mapreduce_free")?;
+
+            let mut out = RawBuffer::empty();
+            let status = plugin_info(&mut out);
+            let info = take_buffer(free, out);
+            let info : PluginInfo = match (status, info) {
+                (STATUS_OK, Ok(info)) => serde_json::from_str(&info).map_err(|e| MapReduceError::DllLoadingError {
+                    fntype : String::from("mapreduce_plugin_info"),
+                    reason : e.to_string(),
+                })?,
+                (_, Ok(reason)) | (_, Err(reason)) => {
+                    return Err(MapReduceError::DllLoadingError {
+                        fntype : String::from("mapreduce_plugin_info"),
+                        reason,
+                    });
+                }
+            };
+            if info.abi_version != PLUGIN_ABI_VERSION {
+                return Err(MapReduceError::DllLoadingError {
+                    fntype : String::from("mapreduce_plugin_info"),
+                    reason : format!("plugin info reports ABI version {}, but {} is expected", info.abi_version, PLUGIN_ABI_VERSION),
+                });
+            }
+            // 可选的符号: 插件信息里声明了才去取, 声明了却没有导出同样是错误.
+            let combiner : Option<CombinerFn> = if info.combiner {
+                Some(get_symbol(&lib, "mapreduce_combiner")?)
+            } else {
+                None
+            };
+            let partitioner : Option<PartitionerFn> = if info.partitioner {
+                Some(get_symbol(&lib, "mapreduce_partitioner")?)
+            } else {
+                None
+            };
+            // 旧的插件没有 mapreduce_set_context, 它们不需要上下文.
+            if let Ok(set_context) = get_symbol::<SetContextFn>(&lib, "mapreduce_set_context") {
+                let context = serde_json::to_string(context).map_err(|e| MapReduceError::DllLoadingError {
+                    fntype : String::from("mapreduce_set_context"),
+                    reason : e.to_string(),
+                })?;
+                let mut out = RawBuffer::empty();
+                let status = set_context(context.as_ptr(), context.len(), &mut out);
+                let content = take_buffer(free, out).unwrap_or_default();
+                if status != STATUS_OK {
+                    return Err(MapReduceError::DllLoadingError {
+                        fntype : String::from("mapreduce_set_context"),
+                        reason : content,
+                    });
+                }
+            }
+            Ok(NativePlugin { info, mapper, mapper_emit, reducer, reducer_stream, combiner, partitioner, free, _lib : lib })
+        }
+    }
+
+    pub fn info(&self) -> &PluginInfo {
+        &self.info
+    }
+
+    /// 调用插件的 mapper.
+    pub fn map(&self, content : &str) -> Result<MapOutput, Box<dyn std::error::Error>> {
+        let output = unsafe {
+            let mut out = RawBuffer::empty();
+            let status = (self.mapper)(content.as_ptr(), content.len(), &mut out);
+            check_status("mapper", status, take_buffer(self.free, out)?)?
+        };
+        Ok(serde_json::from_str(&output)?)
+    }
+
+    /// 调用插件的 emit 风格的 mapper, 键值对与命名输出的记录通过 native_host_call 交给 emitter.
+    pub fn map_emit(&self, content : &str, emitter : &mut dyn Emit, named : &mut dyn NamedSink)
+        -> Result<MapOutput, Box<dyn std::error::Error>> {
+        let mapper_emit = match self.mapper_emit {
+            Some(mapper_emit) => mapper_emit,
+            None => return emit_pairs(self.map(content)?, emitter),
+        };
+        let mut state = HostCallState::new(emitter, named);
+        let output = unsafe {
+            let mut out = RawBuffer::empty();
+            let host = &mut state as *mut HostCallState as *mut c_void;
+            let status = mapper_emit(content.as_ptr(), content.len(), host, native_host_call, &mut out);
+            let output = take_buffer(self.free, out)?;
+            state.finish()?;
+            check_status("mapper", status, output)?
+        };
+        Ok(serde_json::from_str(&output)?)
+    }
+
+    /// 调用插件的 reducer, key 与 values 都是 json 文本, 返回输出记录的 json 数组文本.
+    pub fn reduce(&self, key : &str, values : &str) -> Result<String, Box<dyn std::error::Error>> {
+        unsafe {
+            let mut out = RawBuffer::empty();
+            let status = (self.reducer)(key.as_ptr(), key.len(), values.as_ptr(), values.len(), &mut out);
+            check_status("reducer", status, take_buffer(self.free, out)?)
+        }
+    }
+
+    /// 调用插件的流式的 reducer, value, 输出记录与命名输出的记录都通过 native_host_call 交换.
+    pub fn reduce_stream(&self, key : &str, values : &mut dyn ReduceStream, named : &mut dyn NamedSink)
+        -> Result<ReduceOutput, Box<dyn std::error::Error>> {
+        let reducer_stream = match self.reducer_stream {
+            Some(reducer_stream) => reducer_stream,
+            None => return reduce_collected(|v| self.reduce(key, v), values),
+        };
+        let mut state = HostCallState::for_reduce(values, named);
+        let output = unsafe {
+            let mut out = RawBuffer::empty();
+            let host = &mut state as *mut HostCallState as *mut c_void;
+            let status = reducer_stream(key.as_ptr(), key.len(), host, native_host_call, &mut out);
+            let output = take_buffer(self.free, out)?;
+            state.finish()?;
+            check_status("reducer", status, output)?
+        };
+        ReduceOutput::from_value(serde_json::from_str(&output)?)
+            .map_err(|e| format!("user reducer returned a wrong value: {}", e).into())
+    }
+
+    /// 调用插件的 combiner, 输入输出与 reduce 相同; 插件没有 combiner 时返回 None.
+    pub fn combine(&self, key : &str, values : &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let combiner = match self.combiner {
+            Some(combiner) => combiner,
+            None => return Ok(None),
+        };
+        unsafe {
+            let mut out = RawBuffer::empty();
+            let status = combiner(key.as_ptr(), key.len(), values.as_ptr(), values.len(), &mut out);
+            Ok(Some(check_status("combiner", status, take_buffer(self.free, out)?)?))
+        }
+    }
+
+    /// 调用插件的 partitioner; 插件没有 partitioner 或者它不处理这个 key 时返回 None.
+    pub fn partition(&self, key : &str, reducer_num : u32) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+        let partitioner = match self.partitioner {
+            Some(partitioner) => partitioner,
+            None => return Ok(None),
+        };
+        let mut partition : u32 = 0;
+        let status = unsafe { partitioner(key.as_ptr(), key.len(), reducer_num, &mut partition) };
+        match status {
+            STATUS_OK if partition < reducer_num => Ok(Some(partition)),
+            STATUS_OK => Err(format!("user partitioner returned {} for key {}, but there are only {} reducers",
+                                        partition, key, reducer_num).into()),
+            STATUS_UNHANDLED => Ok(None),
+            STATUS_PANIC => Err(Box::new(MapReduceError::UserCodePanicked {
+                fntype : String::from("partitioner"),
+                message : format!("panicked for key {}", key),
+            })),
+            _ => Err(format!("user partitioner failed for key {}", key).into()),
+        }
+    }
+}
+
+/// 交给原生插件的 host_call, host 是 map_emit 或 reduce_stream 中的 HostCallState.
+/// HOST_READ_VALUE 时 a 是插件的缓冲区, 框架把 value 写进去. 框架的 panic 不能跨过 extern "C" 的边界.
+unsafe extern "C" fn native_host_call(
+    host : *mut c_void, op : u32,
+    a : *const u8, a_len : usize,
+    b : *const u8, b_len : usize) -> i32 {
+    let state = &mut *(host as *mut HostCallState);
+    if op == HOST_READ_VALUE {
+        return match state.take_value(a_len) {
+            Some(value) => {
+                std::ptr::copy_nonoverlapping(value.as_ptr(), a as *mut u8, a_len);
+                STATUS_OK
+            }
+            None => STATUS_ERROR,
+        };
+    }
+    let a = if a_len == 0 { &[][..] } else { std::slice::from_raw_parts(a, a_len) };
+    let b = if b_len == 0 { &[][..] } else { std::slice::from_raw_parts(b, b_len) };
+    panic::catch_unwind(AssertUnwindSafe(|| state.call(op, a, b))).unwrap_or(STATUS_PANIC)
+}
+
+/// 把插件返回的 RawBuffer 复制成 String, 然后交还给插件释放.
+unsafe fn take_buffer(free : FreeFn, buf : RawBuffer) -> Result<String, String> {
+    if buf.ptr.is_null() {
+        return Ok(String::new());
+    }
+    let content = std::slice::from_raw_parts(buf.ptr, buf.len).to_vec();
+    free(buf.ptr, buf.len, buf.cap);
+    String::from_utf8(content).map_err(|e| e.to_string())
+}
@@ -0,0 +1,186 @@
+/// 用内嵌的脚本语言(rhai)写的 mapper/reducer, 适合临时的一次性查询, 不需要编译 cdylib.
+/// 脚本随任务一起提交, 插件文件是一个 json 的 ScriptManifest, 每个任务只编译一次. 脚本中需要定义: \
+/// fn map(content) : 返回 [[key, value], ...] \
+/// fn reduce(key, values) : 返回输出记录的数组 \
+/// 需要命名输出时, map 返回 #{pairs: [...], named: #{名字: [记录, ...]}},
+/// reduce 返回 #{outputs: [...], named: #{名字: [记录, ...]}}. \
+/// 可选的 fn combine(key, values) 返回合并后的 values 数组; fn partition(key, reducer_num) 返回分区号,
+/// 返回 () 表示使用框架默认的哈希分区. \
+/// key, value 可以是任何能表示成 json 的值. 脚本中 throw 算作返回错误, 其余的运行时错误算作 panic. \
+/// 脚本可以用 config(key) 读取任务的配置参数(没有这个参数时是 ()),
+/// 用 cache_file(name) 取得附属文件的路径(没有这个文件时是 ()), 用 read_cache_file(name) 读出它的内容. \
+/// 每次调用脚本最多执行 SubtaskLimits::wasm_fuel 个操作(没有设置时是 DEFAULT_MAX_OPERATIONS), 超过之后报告燃料耗尽,
+/// 这样脚本中的死循环不会一直占着 worker 线程.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::MapReduceError;
+use crate::job::{MapOutput, ReduceOutput, TaskContext};
+use crate::map_reduce::SubtaskLimits;
+use crate::plugin::abi::{PluginInfo, PLUGIN_ABI_VERSION};
+
+/// 没有设置 wasm_fuel 时, 每次调用脚本最多执行的操作数.
+pub const DEFAULT_MAX_OPERATIONS : u64 = 1_000_000_000;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ScriptManifest {
+    pub script : String,
+}
+
+pub struct ScriptPlugin {
+    info : PluginInfo,
+    engine : Engine,
+    ast : AST,
+}
+
+/// 把脚本的运行时错误转换成与原生插件相同的错误: throw 是用户返回的错误, 操作数超过限制与 wasm 插件燃料耗尽相同,
+/// 其余的算 panic.
+fn call_error(fntype : &str, e : Box<EvalAltResult>) -> Box<dyn std::error::Error> {
+    let mut e = e;
+    // 嵌套的函数调用会把真正的错误包起来.
+    while let EvalAltResult::ErrorInFunctionCall(_, _, inner, _) = *e {
+        e = inner;
+    }
+    match *e {
+        EvalAltResult::ErrorRuntime(value, _) => format!("user {} failed: {}", fntype, value).into(),
+        EvalAltResult::ErrorTooManyOperations(_) => Box::new(MapReduceError::UserCodeOutOfFuel {
+            fntype : fntype.to_string(),
+        }),
+        other => Box::new(MapReduceError::UserCodePanicked {
+            fntype : fntype.to_string(),
+            message : other.to_string(),
+        }),
+    }
+}
+
+fn to_dynamic(value : &Value) -> Result<Dynamic, Box<dyn std::error::Error>> {
+    Ok(rhai::serde::to_dynamic(value)?)
+}
+
+impl ScriptPlugin {
+    /// 读取 ScriptManifest 并编译其中的脚本, 再注册读取配置参数与附属文件的函数. limits 中的 wasm_fuel 限制每次调用的操作数.
+    pub fn load(path : &String, limits : &SubtaskLimits, context : &TaskContext) -> Result<ScriptPlugin, MapReduceError> {
+        let manifest = std::fs::read_to_string(path)?;
+        let manifest : ScriptManifest = serde_json::from_str(&manifest).map_err(|e| MapReduceError::DllLoadingError {
+            fntype : String::from("script manifest"),
+            reason : e.to_string(),
+        })?;
+        let mut plugin = ScriptPlugin::compile(&manifest.script)?;
+        if let Some(fuel) = limits.wasm_fuel {
+            plugin.engine.set_max_operations(fuel);
+        }
+        let config = context.config.clone();
+        plugin.engine.register_fn("config", move |key : &str| -> Dynamic {
+            match config.get(key) {
+                Some(value) => value.clone().into(),
+                None => Dynamic::UNIT,
+            }
+        });
+        let files = Arc::new(context.cache_files.clone());
+        let paths = files.clone();
+        plugin.engine.register_fn("cache_file", move |name : &str| -> Dynamic {
+            match paths.get(name) {
+                Some(path) => path.clone().into(),
+                None => Dynamic::UNIT,
+            }
+        });
+        plugin.engine.register_fn("read_cache_file", move |name : &str| -> Result<String, Box<EvalAltResult>> {
+            let path = files.get(name).ok_or_else(|| format!("no cache file named {}", name))?;
+            std::fs::read_to_string(path).map_err(|e| format!("cannot read cache file {}: {}", name, e).into())
+        });
+        Ok(plugin)
+    }
+
+    /// 编译脚本并检查需要的函数, client 提交之前也用它检查脚本.
+    pub fn compile(script : &str) -> Result<ScriptPlugin, MapReduceError> {
+        let mut engine = Engine::new();
+        // rhai 在 debug 编译下默认的表达式深度限制很小, 普通的 map 函数就会超过, 这里用 release 下的默认值.
+        // 调用层数保持默认, 它防止脚本的深递归撑爆 worker 线程的栈.
+        engine.set_max_expr_depths(64, 32);
+        engine.set_max_operations(DEFAULT_MAX_OPERATIONS);
+        let ast = engine.compile(script).map_err(|e| MapReduceError::DllLoadingError {
+            fntype : String::from("script"),
+            reason : e.to_string(),
+        })?;
+        let has_fn = |name : &str, params : usize| {
+            ast.iter_functions().any(|f| f.name == name && f.params.len() == params)
+        };
+        for (name, params) in [("map", 1), ("reduce", 2)] {
+            if !has_fn(name, params) {
+                return Err(MapReduceError::DllLoadingError {
+                    fntype : name.to_string(),
+                    reason : format!("the script does not define fn {} with {} parameters", name, params),
+                });
+            }
+        }
+        let info = PluginInfo {
+            abi_version : PLUGIN_ABI_VERSION,
+            name : String::from("script"),
+            version : String::from("rhai"),
+            combiner : has_fn("combine", 2),
+            partitioner : has_fn("partition", 2),
+        };
+        Ok(ScriptPlugin { info, engine, ast })
+    }
+
+    pub fn info(&self) -> &PluginInfo {
+        &self.info
+    }
+
+    fn call(&self, fntype : &str, name : &str, args : impl rhai::FuncArgs) -> Result<Dynamic, Box<dyn std::error::Error>> {
+        self.engine.call_fn::<Dynamic>(&mut Scope::new(), &self.ast, name, args)
+            .map_err(|e| call_error(fntype, e))
+    }
+
+    /// 把脚本返回的值转换成 json, 类型不对同样算作用户代码的错误.
+    fn from_dynamic<T : serde::de::DeserializeOwned>(fntype : &str, value : &Dynamic) -> Result<T, Box<dyn std::error::Error>> {
+        rhai::serde::from_dynamic(value).map_err(|e| format!("user {} returned a wrong value: {}", fntype, e).into())
+    }
+
+    pub fn map(&self, content : &str) -> Result<MapOutput, Box<dyn std::error::Error>> {
+        let ret = self.call("mapper", "map", (content.to_string(),))?;
+        if ret.is_map() {
+            return Self::from_dynamic("mapper", &ret);
+        }
+        Ok(MapOutput { pairs : Self::from_dynamic("mapper", &ret)?, ..MapOutput::default() })
+    }
+
+    /// key 与 values 都是 json 文本, 返回 ReduceOutput 的 json 文本.
+    pub fn reduce(&self, key : &str, values : &str) -> Result<String, Box<dyn std::error::Error>> {
+        let key = to_dynamic(&serde_json::from_str::<Value>(key)?)?;
+        let values = to_dynamic(&serde_json::from_str::<Value>(values)?)?;
+        let ret = self.call("reducer", "reduce", (key, values))?;
+        let output = ReduceOutput::from_value(Self::from_dynamic("reducer", &ret)?)
+            .map_err(|e| format!("user reducer returned a wrong value: {}", e))?;
+        Ok(output.to_json()?)
+    }
+
+    pub fn combine(&self, key : &str, values : &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if !self.info.combiner {
+            return Ok(None);
+        }
+        let key = to_dynamic(&serde_json::from_str::<Value>(key)?)?;
+        let values = to_dynamic(&serde_json::from_str::<Value>(values)?)?;
+        let ret = self.call("combiner", "combine", (key, values))?;
+        Ok(Some(serde_json::to_string(&Self::from_dynamic::<Vec<Value>>("combiner", &ret)?)?))
+    }
+
+    pub fn partition(&self, key : &str, reducer_num : u32) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+        if !self.info.partitioner {
+            return Ok(None);
+        }
+        let key_value = to_dynamic(&serde_json::from_str::<Value>(key)?)?;
+        let ret = self.call("partitioner", "partition", (key_value, reducer_num as rhai::INT))?;
+        if ret.is_unit() {
+            return Ok(None);
+        }
+        match ret.as_int() {
+            Ok(partition) if partition >= 0 && partition < reducer_num as rhai::INT => Ok(Some(partition as u32)),
+            _ => Err(format!("user partitioner returned {} for key {}, but there are only {} reducers",
+                                ret, key, reducer_num).into()),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+/// Hadoop Streaming 风格的任务: mapper 和 reducer 是两个可执行文件(shell, awk, python 脚本都可以).
+/// mapper 从 stdin 读入整个输入分块, 在 stdout 上每行输出一个 key\tvalue;
+/// reducer 从 stdin 读入按 key 排好序的 key\tvalue 行(同一个 key 的行连在一起), 每行输出一个 key\toutput. \
+/// 一行中没有 \t 时整行是 key, value 为空字符串. key 和 value 都当作字符串处理. \
+/// 写给 reducer 的 key 中不能有 \t 和换行, value 中不能有换行(value 中的 \t 没有歧义, 因为只有第一个 \t 是分隔符),
+/// 否则 reducer 读到的行拆不回原来的键值对, 这样的键值对会让 reducer 失败. \
+/// 任务的插件文件是一个 json 的 StreamingManifest, 给出两个可执行文件相对于它所在目录的路径. \
+/// 任务有附属文件时, 环境变量 MAPREDUCE_CACHE_DIR 是存放它们的文件夹.
+/// 任务的每个配置参数 key 是一个环境变量 MAPREDUCE_CONF_<key>, key 中字母、数字以外的字符换成 _.
+use std::{
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{ChildStdin, Command, Stdio},
+    thread,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::MapReduceError;
+use crate::job::{MapOutput, ReduceOutput, TaskContext};
+use crate::plugin::abi::{PluginInfo, PLUGIN_ABI_VERSION};
+use crate::plugin::{Emit, ReduceSink, SortedPairs};
+
+/// client 上传到 server 的两个可执行文件的文件名, 与插件文件放在同一个目录.
+pub const STREAMING_MAPPER_FILE : &str = "uesr_mapper.exe";
+pub const STREAMING_REDUCER_FILE : &str = "uesr_reducer.exe";
+/// 告诉 mapper/reducer 附属文件所在文件夹的环境变量.
+pub const CACHE_DIR_ENV : &str = "MAPREDUCE_CACHE_DIR";
+/// 配置参数对应的环境变量名的前缀.
+pub const CONFIG_ENV_PREFIX : &str = "MAPREDUCE_CONF_";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StreamingManifest {
+    pub mapper : String,
+    pub reducer : String,
+}
+
+pub struct StreamingPlugin {
+    info : PluginInfo,
+    mapper : PathBuf,
+    reducer : PathBuf,
+    env : Vec<(String, String)>,   // 传给 mapper/reducer 的环境变量.
+}
+
+/// 检查可执行文件是否存在, 并加上可执行权限(从 hdfs 复制过来的文件没有).
+fn prepare_executable(fntype : &str, path : &Path) -> Result<(), MapReduceError> {
+    let metadata = std::fs::metadata(path).map_err(|e| MapReduceError::DllLoadingError {
+        fntype : fntype.to_string(),
+        reason : format!("{}: {}", path.display(), e),
+    })?;
+    let mut permissions = metadata.permissions();
+    if permissions.mode() & 0o111 != 0o111 {
+        permissions.set_mode(permissions.mode() | 0o111);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+/// 把 json 值写成 streaming 的文本: 字符串写原文, 其它的写 json 文本.
+fn to_text(value : &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// 写给 reducer 的一行: 检查 key 和 value 中没有会破坏行格式的字符.
+fn to_line(key : &str, value : &str) -> Result<String, Box<dyn std::error::Error>> {
+    if key.contains(['\t', '\n', '\r']) {
+        return Err(format!("key {:?} contains a tab or a line break and cannot be passed to a streaming reducer", key).into());
+    }
+    if value.contains(['\n', '\r']) {
+        return Err(format!("value {:?} contains a line break and cannot be passed to a streaming reducer", value).into());
+    }
+    Ok(format!("{}\t{}", key, value))
+}
+
+/// 把一行输出拆成 key 和 value.
+fn split_line(line : &str) -> (&str, &str) {
+    line.split_once('\t').unwrap_or((line, ""))
+}
+
+/// 任务上下文对应的环境变量.
+fn context_env(context : &TaskContext) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    if let Some(cache_dir) = &context.cache_dir {
+        env.push((CACHE_DIR_ENV.to_string(), cache_dir.clone()));
+    }
+    for (key, value) in &context.config {
+        let key : String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        env.push((format!("{}{}", CONFIG_ENV_PREFIX, key), value.clone()));
+    }
+    env
+}
+
+/// 运行一个可执行文件, 把 input 写到它的 stdin, 它的 stdout 每读到一行就交给 on_line.
+/// 写 stdin 放在另一个线程, 否则程序的输出填满管道之后两边会互相等待.
+/// on_line 出错时杀掉程序, 返回这个错误.
+fn run<F>(fntype : &str, program : &Path, env : &[(String, String)], input : String, mut on_line : F)
+    -> Result<(), Box<dyn std::error::Error>>
+    where F : FnMut(&str) -> Result<(), Box<dyn std::error::Error>>
+{
+    let mut child = Command::new(program)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let mut ret = Ok(());
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        ret = line.map_err(|e| e.into()).and_then(|line| on_line(&line));
+        if ret.is_err() {
+            let _ = child.kill();
+            break;
+        }
+    }
+    let status = child.wait()?;
+    // 程序可能不读完输入就退出, 这时写 stdin 出错(broken pipe)不算错误, 以退出状态为准.
+    let _ = writer.join();
+    ret?;
+    if !status.success() {
+        return Err(format!("user {} {} exited with {}", fntype, program.display(), status).into());
+    }
+    Ok(())
+}
+
+/// 运行一个可执行文件, 由 write_input 在另一个线程中写它的 stdin, 它的 stdout 每读到一行就在当前线程中交给 on_line.
+/// 两边分在两个线程的理由与 run 相同. write_input 的错误中, 写 stdin 时的 broken pipe 不算错误;
+/// on_line 出错时杀掉程序, 返回这个错误.
+fn run_feeding<W, F>(fntype : &str, program : &Path, env : &[(String, String)], write_input : W, mut on_line : F)
+    -> Result<(), Box<dyn std::error::Error>>
+    where W : FnOnce(&mut BufWriter<ChildStdin>) -> Result<(), Box<dyn std::error::Error>> + Send,
+          F : FnMut(&str) -> Result<(), Box<dyn std::error::Error>>
+{
+    let mut child = Command::new(program)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    // write_input 借用着调用者的输入, 所以用 scope 中的线程.
+    let (ret, status, written) = thread::scope(|scope| {
+        let writer = scope.spawn(move || {
+            // 线程结束时关掉 stdin, 程序才知道输入结束了.
+            let mut stdin = BufWriter::new(stdin);
+            match write_input(&mut stdin).and_then(|()| Ok(stdin.flush()?)) {
+                Err(e) if !matches!(e.downcast_ref::<std::io::Error>(), Some(e) if e.kind() == ErrorKind::BrokenPipe) => Err(e.to_string()),
+                _ => Ok(()),
+            }
+        });
+        let mut ret = Ok(());
+        for line in BufReader::new(stdout).lines() {
+            ret = line.map_err(|e| e.into()).and_then(|line| on_line(&line));
+            if ret.is_err() {
+                let _ = child.kill();
+                break;
+            }
+        }
+        let status = child.wait();
+        let written = writer.join().unwrap_or_else(|_| Err(format!("failed to write the input of user {}", fntype)));
+        (ret, status, written)
+    });
+    written?;
+    ret?;
+    let status = status?;
+    if !status.success() {
+        return Err(format!("user {} {} exited with {}", fntype, program.display(), status).into());
+    }
+    Ok(())
+}
+
+impl StreamingPlugin {
+    /// 读取 StreamingManifest, 可执行文件的相对路径相对于 manifest 所在的目录.
+    pub fn load(path : &String, context : &TaskContext) -> Result<StreamingPlugin, MapReduceError> {
+        let manifest = std::fs::read_to_string(path)?;
+        let manifest : StreamingManifest = serde_json::from_str(&manifest).map_err(|e| MapReduceError::DllLoadingError {
+            fntype : String::from("streaming manifest"),
+            reason : e.to_string(),
+        })?;
+        let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+        let mapper = dir.join(&manifest.mapper);
+        let reducer = dir.join(&manifest.reducer);
+        prepare_executable("mapper", &mapper)?;
+        prepare_executable("reducer", &reducer)?;
+        let info = PluginInfo {
+            abi_version : PLUGIN_ABI_VERSION,
+            name : format!("{} | {}", manifest.mapper, manifest.reducer),
+            version : String::from("streaming"),
+            combiner : false,
+            partitioner : false,
+        };
+        Ok(StreamingPlugin { info, mapper, reducer, env : context_env(context) })
+    }
+
+    pub fn info(&self) -> &PluginInfo {
+        &self.info
+    }
+
+    /// 把输入分块交给 mapper, 每行输出一个键值对.
+    pub fn map(&self, content : &str) -> Result<MapOutput, Box<dyn std::error::Error>> {
+        let mut pairs = Vec::new();
+        run("mapper", &self.mapper, &self.env, content.to_string(), |line| {
+            if !line.is_empty() {
+                let (k, v) = split_line(line);
+                pairs.push((Value::String(k.to_string()), Value::String(v.to_string())));
+            }
+            Ok(())
+        })?;
+        Ok(MapOutput { pairs, ..MapOutput::default() })
+    }
+
+    /// 与 map 相同, 但是 mapper 每输出一行就把它交给 emitter.
+    pub fn map_emit(&self, content : &str, emitter : &mut dyn Emit) -> Result<MapOutput, Box<dyn std::error::Error>> {
+        run("mapper", &self.mapper, &self.env, content.to_string(), |line| {
+            if line.is_empty() {
+                return Ok(());
+            }
+            let (k, v) = split_line(line);
+            emitter.emit(&serde_json::to_string(k)?, &serde_json::to_string(v)?)
+        })?;
+        Ok(MapOutput::default())
+    }
+
+    /// 整个分区只启动一次 reducer: pairs 是按 key 排好序的 (key, value) 序列, 一边读一边写给 reducer,
+    /// reducer 每输出一行就按 key 归组交给 sink. streaming 任务没有命名输出和计数器.
+    pub fn reduce_sorted(&self, pairs : &mut SortedPairs, sink : &mut dyn ReduceSink)
+        -> Result<ReduceOutput, Box<dyn std::error::Error>> {
+        let mut last : Option<String> = None;
+        run_feeding("reducer", &self.reducer, &self.env, |stdin| {
+            for pair in pairs {
+                let (k, v) = pair?;
+                let k = to_text(&serde_json::from_str(&k)?);
+                let v = to_text(&serde_json::from_str(&v)?);
+                writeln!(stdin, "{}", to_line(&k, &v)?)?;
+            }
+            Ok(())
+        }, |line| {
+            if line.is_empty() {
+                return Ok(());
+            }
+            let (k, v) = split_line(line);
+            let k = serde_json::to_string(k)?;
+            if last.as_ref() != Some(&k) {
+                sink.start(&k)?;
+                last = Some(k);
+            }
+            sink.output(&serde_json::to_string(v)?)
+        })?;
+        Ok(ReduceOutput::default())
+    }
+
+    /// 只对一个 key 执行 reducer, 返回这个 key 的输出记录的 json 数组文本.
+    pub fn reduce(&self, key : &str, values : &str) -> Result<String, Box<dyn std::error::Error>> {
+        let values : Vec<Value> = serde_json::from_str(values)?;
+        let mut pairs = values.iter().map(|v| Ok((key.to_string(), serde_json::to_string(v)?)));
+        let mut outputs = CollectOutputs(Vec::new());
+        self.reduce_sorted(&mut pairs, &mut outputs)?;
+        Ok(format!("[{}]", outputs.0.join(",")))
+    }
+}
+
+/// 收集一个 key 的所有输出记录.
+struct CollectOutputs(Vec<String>);
+
+impl ReduceSink for CollectOutputs {
+    fn start(&mut self, _key : &str) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn output(&mut self, record : &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.0.push(record.to_string());
+        Ok(())
+    }
+}
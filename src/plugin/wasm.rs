@@ -0,0 +1,419 @@
+/// 在内嵌的 wasm 运行时(wasmi)中执行 WebAssembly 模块形式的插件.
+/// 模块导出的函数与原生插件同名、参数相同(指针和 usize 都是 i32), RawBuffer 在模块的线性内存中,
+/// 另外模块还要导出 memory 以及 mapreduce_alloc, 框架用它在模块内存中放置输入. \
+/// 插件只能导入 mapreduce 模块中的 host_call(emit 风格的 mapper 和流式的 reducer 用它与框架交换数据),
+/// 所以它碰不到文件、网络等宿主资源; 每次调用的燃料和线性内存的大小
+/// 受 SubtaskLimits 中 wasm_fuel, wasm_memory_bytes 的限制. 每个实例创建之后都会收到任务的上下文,
+/// 其中的附属文件路径对 wasm 插件没有用, 配置参数可以用.
+use std::ffi::c_void;
+use std::sync::Mutex;
+use wasmi::{Caller, Config, Engine, Error as WasmError, Extern, Instance, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder, TrapCode, TypedFunc};
+use wasmi::errors::{ErrorKind, FuelError};
+
+use crate::error::MapReduceError;
+use crate::job::{MapOutput, ReduceOutput, TaskContext};
+use crate::map_reduce::SubtaskLimits;
+use crate::plugin::abi::*;
+use crate::plugin::{check_status, emit_pairs, reduce_collected, Emit, HostCallState, NamedSink, ReduceStream};
+
+/// wasm32 上 RawBuffer 的大小: 三个 u32.
+const RAW_BUFFER_SIZE : u32 = 12;
+
+// 与 abi 中的函数类型一一对应, 指针和 usize 都是 u32.
+type WasmAllocFn = TypedFunc<u32, u32>;
+type WasmFreeFn = TypedFunc<(u32, u32, u32), ()>;
+type WasmMapperFn = TypedFunc<(u32, u32, u32), i32>;
+type WasmReducerFn = TypedFunc<(u32, u32, u32, u32, u32), i32>;
+type WasmPartitionerFn = TypedFunc<(u32, u32, u32, u32), i32>;
+
+/// store 中的宿主数据. host 只在 map_emit, reduce_stream 调用插件期间指向它们的 HostCallState, 其余时间是空指针.
+struct HostData {
+    limits : StoreLimits,
+    host : *mut c_void,
+}
+
+// host 只在持有实例的线程调用插件期间有效, 实例在线程之间移动时它总是空指针.
+unsafe impl Send for HostData {}
+
+/// 模块导入的 mapreduce.host_call: 从模块内存中读出参数, 交给当前调用的 HostCallState;
+/// HOST_READ_VALUE 时把 value 写到模块内存中 a 处.
+fn wasm_host_call(mut caller : Caller<'_, HostData>, op : u32, a : u32, a_len : u32, b : u32, b_len : u32) -> i32 {
+    let host = caller.data().host;
+    if host.is_null() {
+        return STATUS_ERROR;
+    }
+    let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+        Some(memory) => memory,
+        None => return STATUS_ERROR,
+    };
+    if op == HOST_READ_VALUE {
+        let state = unsafe { &mut *(host as *mut HostCallState) };
+        return match state.take_value(a_len as usize) {
+            Some(value) if memory.write(&mut caller, a as usize, value.as_bytes()).is_ok() => STATUS_OK,
+            _ => STATUS_ERROR,
+        };
+    }
+    let mut a_bytes = vec![0u8; a_len as usize];
+    let mut b_bytes = vec![0u8; b_len as usize];
+    if memory.read(&caller, a as usize, &mut a_bytes).is_err() || memory.read(&caller, b as usize, &mut b_bytes).is_err() {
+        return STATUS_ERROR;
+    }
+    let state = unsafe { &mut *(host as *mut HostCallState) };
+    state.call(op, &a_bytes, &b_bytes)
+}
+
+/// 模块的一个实例以及从中取出的导出函数. 实例不能被多个线程同时使用.
+struct WasmInstance {
+    store : Store<HostData>,
+    instance : Instance,
+    memory : Memory,
+    alloc : WasmAllocFn,
+    free : WasmFreeFn,
+    mapper : WasmMapperFn,
+    mapper_emit : Option<WasmMapperFn>,   // 旧的插件没有.
+    reducer : WasmReducerFn,
+    reducer_stream : Option<WasmMapperFn>,   // 旧的插件没有; 参数与 mapper 相同.
+    combiner : Option<WasmReducerFn>,
+    partitioner : Option<WasmPartitionerFn>,
+    set_context : Option<WasmMapperFn>,   // 旧的插件没有.
+}
+
+pub struct WasmPlugin {
+    info : PluginInfo,
+    mapper_emit : bool,   // 模块是否导出了 mapreduce_mapper_emit.
+    reducer_stream : bool,   // 模块是否导出了 mapreduce_reducer_stream.
+    engine : Engine,
+    module : Module,
+    fuel : Option<u64>,
+    memory_bytes : Option<usize>,
+    context : String,   // 任务上下文的 json, 交给每个新的实例.
+    // 空闲的实例. 同一个任务的多个 worker 线程各自取一个用, 用完放回来.
+    idle : Mutex<Vec<WasmInstance>>,
+}
+
+fn loading_error(fntype : &str, e : impl ToString) -> MapReduceError {
+    MapReduceError::DllLoadingError {
+        fntype : fntype.to_string(),
+        reason : e.to_string(),
+    }
+}
+
+/// 燃料耗尽的错误. 除了 OutOfFuel 这个 trap 以外, wasmi 有时也会报告成 ResumableOutOfFuel.
+fn is_out_of_fuel(e : &WasmError) -> bool {
+    matches!(e.kind(),
+        ErrorKind::TrapCode(TrapCode::OutOfFuel)
+        | ErrorKind::ResumableOutOfFuel(_)
+        | ErrorKind::Fuel(FuelError::OutOfFuel { .. }))
+}
+
+/// 调用插件时 wasm 运行时报告的错误: 燃料耗尽、内存超限, 其余的 trap(包括用户代码 panic) 都算 panic.
+fn call_error(fntype : &str, e : WasmError) -> MapReduceError {
+    let fntype = fntype.to_string();
+    if is_out_of_fuel(&e) {
+        MapReduceError::UserCodeOutOfFuel { fntype }
+    } else if e.as_trap_code() == Some(TrapCode::GrowthOperationLimited) {
+        MapReduceError::UserCodeOutOfMemory { fntype }
+    } else {
+        MapReduceError::UserCodePanicked { fntype, message : e.to_string() }
+    }
+}
+
+impl WasmInstance {
+    fn new(plugin : &WasmPlugin) -> Result<WasmInstance, MapReduceError> {
+        let mut limits = StoreLimitsBuilder::new().trap_on_grow_failure(true);
+        if let Some(bytes) = plugin.memory_bytes {
+            limits = limits.memory_size(bytes);
+        }
+        let mut store = Store::new(&plugin.engine, HostData { limits : limits.build(), host : std::ptr::null_mut() });
+        store.limiter(|data| &mut data.limits);
+        if let Some(fuel) = plugin.fuel {
+            // 模块的初始化也要消耗燃料.
+            store.set_fuel(fuel).map_err(|e| loading_error("module", e))?;
+        }
+        let mut linker = Linker::<HostData>::new(&plugin.engine);
+        linker.func_wrap("mapreduce", "host_call", wasm_host_call).map_err(|e| loading_error("host_call", e))?;
+        // 初始化时超过限制同样按超过限制报告, 其余的错误是模块本身的问题.
+        let instance = linker.instantiate_and_start(&mut store, &plugin.module)
+            .map_err(|e| match call_error("module", e) {
+                MapReduceError::UserCodePanicked { message, .. } => loading_error("module", message),
+                e => e,
+            })?;
+        let memory = instance.get_memory(&store, "memory")
+            .ok_or_else(|| loading_error("memory", "the module does not export its memory"))?;
+        let combiner = if plugin.info.combiner {
+            Some(get_func(&instance, &store, "mapreduce_combiner")?)
+        } else {
+            None
+        };
+        let partitioner = if plugin.info.partitioner {
+            Some(get_func(&instance, &store, "mapreduce_partitioner")?)
+        } else {
+            None
+        };
+        let set_context = instance.get_typed_func(&store, "mapreduce_set_context").ok();
+        let mapper_emit = instance.get_typed_func(&store, "mapreduce_mapper_emit").ok();
+        let reducer_stream = instance.get_typed_func(&store, "mapreduce_reducer_stream").ok();
+        let mut wasm_instance = WasmInstance {
+            alloc : get_func(&instance, &store, "mapreduce_alloc")?,
+            free : get_func(&instance, &store, "mapreduce_free")?,
+            mapper : get_func(&instance, &store, "mapreduce_mapper")?,
+            mapper_emit,
+            reducer : get_func(&instance, &store, "mapreduce_reducer")?,
+            reducer_stream,
+            combiner,
+            partitioner,
+            set_context,
+            memory,
+            instance,
+            store,
+        };
+        if wasm_instance.set_context.is_some() {
+            wasm_instance.call_with_inputs("set_context", plugin.fuel, &[&plugin.context])
+                .map_err(|e| loading_error("mapreduce_set_context", e))?;
+        }
+        Ok(wasm_instance)
+    }
+
+    /// 每次调用插件之前重新装满燃料.
+    fn refuel(&mut self, fuel : Option<u64>) -> Result<(), WasmError> {
+        match fuel {
+            Some(fuel) => self.store.set_fuel(fuel),
+            None => Ok(()),
+        }
+    }
+
+    /// 在模块内存中分配一段空间并写入 content, 返回它的地址.
+    fn write_input(&mut self, content : &[u8]) -> Result<u32, WasmError> {
+        let ptr = self.alloc.call(&mut self.store, content.len() as u32)?;
+        self.memory.write(&mut self.store, ptr as usize, content)?;
+        Ok(ptr)
+    }
+
+    /// 释放 write_input 分配的空间.
+    fn free_input(&mut self, ptr : u32, len : u32) -> Result<(), WasmError> {
+        self.free.call(&mut self.store, (ptr, 0, len))
+    }
+
+    /// 分配一个空的 RawBuffer 给插件写结果.
+    fn alloc_out(&mut self) -> Result<u32, WasmError> {
+        self.write_input(&[0; RAW_BUFFER_SIZE as usize])
+    }
+
+    /// 读出插件写在 out 中的结果, 然后把结果和 out 本身都交还给插件释放.
+    fn take_out(&mut self, out : u32) -> Result<String, Box<dyn std::error::Error>> {
+        let mut raw = [0u8; RAW_BUFFER_SIZE as usize];
+        self.memory.read(&self.store, out as usize, &mut raw)?;
+        let field = |i : usize| u32::from_le_bytes([raw[i * 4], raw[i * 4 + 1], raw[i * 4 + 2], raw[i * 4 + 3]]);
+        let (ptr, len, cap) = (field(0), field(1), field(2));
+        let mut content = vec![0u8; len as usize];
+        if ptr != 0 {
+            self.memory.read(&self.store, ptr as usize, &mut content)?;
+            self.free.call(&mut self.store, (ptr, len, cap))?;
+        }
+        self.free_input(out, RAW_BUFFER_SIZE)?;
+        Ok(String::from_utf8(content)?)
+    }
+
+    /// 调用一个参数是 (content, out) 或 (key, values, out) 形式的导出函数.
+    fn call_with_inputs(&mut self, fntype : &str, fuel : Option<u64>, inputs : &[&str])
+        -> Result<String, Box<dyn std::error::Error>> {
+        self.refuel(fuel).map_err(|e| call_error(fntype, e))?;
+        let mut args = Vec::with_capacity(inputs.len() * 2 + 1);
+        for input in inputs {
+            args.push(self.write_input(input.as_bytes()).map_err(|e| call_error(fntype, e))?);
+            args.push(input.len() as u32);
+        }
+        let out = self.alloc_out().map_err(|e| call_error(fntype, e))?;
+        let status = match (fntype, args.as_slice()) {
+            ("mapper", [ptr, len]) => self.mapper.call(&mut self.store, (*ptr, *len, out)),
+            ("mapper_emit", [ptr, len]) => match self.mapper_emit {
+                Some(mapper_emit) => mapper_emit.call(&mut self.store, (*ptr, *len, out)),
+                None => return Err(format!("plugin does not export mapreduce_{}", fntype).into()),
+            },
+            ("set_context", [ptr, len]) => match self.set_context {
+                Some(set_context) => set_context.call(&mut self.store, (*ptr, *len, out)),
+                None => return Err(format!("plugin does not export mapreduce_{}", fntype).into()),
+            },
+            ("reducer_stream", [ptr, len]) => match self.reducer_stream {
+                Some(reducer_stream) => reducer_stream.call(&mut self.store, (*ptr, *len, out)),
+                None => return Err(format!("plugin does not export mapreduce_{}", fntype).into()),
+            },
+            ("reducer", [kptr, klen, vptr, vlen]) =>
+                self.reducer.call(&mut self.store, (*kptr, *klen, *vptr, *vlen, out)),
+            ("combiner", [kptr, klen, vptr, vlen]) => match self.combiner {
+                Some(combiner) => combiner.call(&mut self.store, (*kptr, *klen, *vptr, *vlen, out)),
+                None => return Err(format!("plugin does not export {}", fntype).into()),
+            },
+            _ => return Err(format!("wrong arguments for user {}", fntype).into()),
+        }.map_err(|e| call_error(fntype, e))?;
+        let content = self.take_out(out)?;
+        for pair in args.chunks(2) {
+            self.free_input(pair[0], pair[1]).map_err(|e| call_error(fntype, e))?;
+        }
+        check_status(fntype, status, content)
+    }
+}
+
+fn get_func<Params, Results>(instance : &Instance, store : &Store<HostData>, name : &str)
+    -> Result<TypedFunc<Params, Results>, MapReduceError>
+    where Params : wasmi::WasmParams, Results : wasmi::WasmResults
+{
+    instance.get_typed_func::<Params, Results>(store, name).map_err(|e| loading_error(name, e))
+}
+
+impl WasmPlugin {
+    /// 编译模块并检查导出的符号: 与原生插件一样先检查 ABI 版本号, 再读取插件信息.
+    pub fn load(path : &String, limits : &SubtaskLimits, context : &TaskContext) -> Result<WasmPlugin, MapReduceError> {
+        let bytes = std::fs::read(path)?;
+        let mut config = Config::default();
+        config.consume_fuel(limits.wasm_fuel.is_some());
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &bytes).map_err(|e| loading_error("module", e))?;
+        let mut plugin = WasmPlugin {
+            info : PluginInfo {
+                abi_version : PLUGIN_ABI_VERSION,
+                name : String::new(),
+                version : String::new(),
+                combiner : false,
+                partitioner : false,
+            },
+            mapper_emit : false,
+            reducer_stream : false,
+            engine,
+            module,
+            fuel : limits.wasm_fuel,
+            memory_bytes : limits.wasm_memory_bytes.map(|bytes| bytes as usize),
+            context : serde_json::to_string(context).map_err(|e| loading_error("mapreduce_set_context", e))?,
+            idle : Mutex::new(Vec::new()),
+        };
+        // 先用一个不带可选函数的实例读出插件信息, 之后的实例再按信息取可选函数.
+        let mut instance = WasmInstance::new(&plugin)?;
+        let abi_version : TypedFunc<(), u32> = get_func(&instance.instance, &instance.store, "mapreduce_abi_version")?;
+        let found = abi_version.call(&mut instance.store, ()).map_err(|e| call_error("abi_version", e))?;
+        if found != PLUGIN_ABI_VERSION {
+            return Err(loading_error("mapreduce_abi_version",
+                format!("plugin ABI version is {}, but {} is expected", found, PLUGIN_ABI_VERSION)));
+        }
+        let plugin_info : TypedFunc<u32, i32> = get_func(&instance.instance, &instance.store, "mapreduce_plugin_info")?;
+        instance.refuel(plugin.fuel).map_err(|e| call_error("plugin_info", e))?;
+        let out = instance.alloc_out().map_err(|e| call_error("plugin_info", e))?;
+        let status = plugin_info.call(&mut instance.store, out).map_err(|e| call_error("plugin_info", e))?;
+        let info = instance.take_out(out).map_err(|e| loading_error("mapreduce_plugin_info", e))?;
+        if status != STATUS_OK {
+            return Err(loading_error("mapreduce_plugin_info", info));
+        }
+        let info : PluginInfo = serde_json::from_str(&info).map_err(|e| loading_error("mapreduce_plugin_info", e))?;
+        if info.abi_version != PLUGIN_ABI_VERSION {
+            return Err(loading_error("mapreduce_plugin_info",
+                format!("plugin info reports ABI version {}, but {} is expected", info.abi_version, PLUGIN_ABI_VERSION)));
+        }
+        plugin.info = info;
+        plugin.mapper_emit = instance.mapper_emit.is_some();
+        plugin.reducer_stream = instance.reducer_stream.is_some();
+        // 声明了的可选函数也要检查一遍.
+        let instance = WasmInstance::new(&plugin)?;
+        plugin.idle.lock().unwrap().push(instance);
+        Ok(plugin)
+    }
+
+    pub fn info(&self) -> &PluginInfo {
+        &self.info
+    }
+
+    /// 取一个空闲的实例执行 f, 成功之后把实例放回去.
+    /// 出错的实例状态未知(可能停在 trap 的地方), 直接丢掉.
+    fn with_instance<T, F>(&self, f : F) -> Result<T, Box<dyn std::error::Error>>
+        where F : FnOnce(&mut WasmInstance) -> Result<T, Box<dyn std::error::Error>>
+    {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut instance = match idle {
+            Some(instance) => instance,
+            None => WasmInstance::new(self)?,
+        };
+        let ret = f(&mut instance)?;
+        self.idle.lock().unwrap().push(instance);
+        Ok(ret)
+    }
+
+    pub fn map(&self, content : &str) -> Result<MapOutput, Box<dyn std::error::Error>> {
+        let output = self.with_instance(|instance| instance.call_with_inputs("mapper", self.fuel, &[content]))?;
+        Ok(serde_json::from_str(&output)?)
+    }
+
+    /// 调用插件的 emit 风格的 mapper, 键值对与命名输出的记录通过导入的 host_call 交给 emitter.
+    pub fn map_emit(&self, content : &str, emitter : &mut dyn Emit, named : &mut dyn NamedSink)
+        -> Result<MapOutput, Box<dyn std::error::Error>> {
+        if !self.mapper_emit {
+            return emit_pairs(self.map(content)?, emitter);
+        }
+        let mut state = HostCallState::new(emitter, named);
+        let output = self.with_instance(|instance| {
+            instance.store.data_mut().host = &mut state as *mut HostCallState as *mut c_void;
+            let ret = instance.call_with_inputs("mapper_emit", self.fuel, &[content]);
+            instance.store.data_mut().host = std::ptr::null_mut();
+            ret
+        });
+        state.finish()?;
+        Ok(serde_json::from_str(&output?)?)
+    }
+
+    pub fn reduce(&self, key : &str, values : &str) -> Result<String, Box<dyn std::error::Error>> {
+        self.with_instance(|instance| instance.call_with_inputs("reducer", self.fuel, &[key, values]))
+    }
+
+    /// 调用插件的流式的 reducer, value, 输出记录与命名输出的记录都通过导入的 host_call 交换.
+    pub fn reduce_stream(&self, key : &str, values : &mut dyn ReduceStream, named : &mut dyn NamedSink)
+        -> Result<ReduceOutput, Box<dyn std::error::Error>> {
+        if !self.reducer_stream {
+            return reduce_collected(|v| self.reduce(key, v), values);
+        }
+        let mut state = HostCallState::for_reduce(values, named);
+        let output = self.with_instance(|instance| {
+            instance.store.data_mut().host = &mut state as *mut HostCallState as *mut c_void;
+            let ret = instance.call_with_inputs("reducer_stream", self.fuel, &[key]);
+            instance.store.data_mut().host = std::ptr::null_mut();
+            ret
+        });
+        state.finish()?;
+        ReduceOutput::from_value(serde_json::from_str(&output?)?)
+            .map_err(|e| format!("user reducer returned a wrong value: {}", e).into())
+    }
+
+    pub fn combine(&self, key : &str, values : &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if !self.info.combiner {
+            return Ok(None);
+        }
+        self.with_instance(|instance| instance.call_with_inputs("combiner", self.fuel, &[key, values])).map(Some)
+    }
+
+    pub fn partition(&self, key : &str, reducer_num : u32) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+        if !self.info.partitioner {
+            return Ok(None);
+        }
+        let (status, partition) = self.with_instance(|instance| {
+            let partitioner = instance.partitioner.ok_or("plugin does not export mapreduce_partitioner")?;
+            instance.refuel(self.fuel).map_err(|e| call_error("partitioner", e))?;
+            let ptr = instance.write_input(key.as_bytes()).map_err(|e| call_error("partitioner", e))?;
+            let out = instance.write_input(&[0; 4]).map_err(|e| call_error("partitioner", e))?;
+            let status = partitioner.call(&mut instance.store, (ptr, key.len() as u32, reducer_num, out))
+                .map_err(|e| call_error("partitioner", e))?;
+            let mut partition = [0u8; 4];
+            instance.memory.read(&instance.store, out as usize, &mut partition)?;
+            instance.free_input(ptr, key.len() as u32).map_err(|e| call_error("partitioner", e))?;
+            instance.free_input(out, 4).map_err(|e| call_error("partitioner", e))?;
+            Ok((status, u32::from_le_bytes(partition)))
+        })?;
+        match status {
+            STATUS_OK if partition < reducer_num => Ok(Some(partition)),
+            STATUS_OK => Err(format!("user partitioner returned {} for key {}, but there are only {} reducers",
+                                        partition, key, reducer_num).into()),
+            STATUS_UNHANDLED => Ok(None),
+            STATUS_PANIC => Err(Box::new(MapReduceError::UserCodePanicked {
+                fntype : String::from("partitioner"),
+                message : format!("panicked for key {}", key),
+            })),
+            _ => Err(format!("user partitioner failed for key {}", key).into()),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::sync::mpsc::{Sender, channel, Receiver};
 use std::thread::{self, JoinHandle, Thread};
 use std::sync::{mpsc, Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
 
 pub struct ThreadPoll{
     workers:Vec<Worker>,
@@ -26,6 +27,11 @@ impl ThreadPoll {
         }
     }
 
+    /// 线程的数量.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
     pub fn execute<F>(&self, f:F)
         where F:FnOnce() + Send + 'static,
     {
@@ -62,7 +68,10 @@ impl Worker {
             match message {
                 Ok(job) => {
                     //print!("Worker {id} gets a job. Executing.");
-                    job();
+                    // job panic 的话线程不能跟着死掉, 否则线程池就永远少了一个线程.
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        eprintln!("Worker {id} caught a panic from its job, keep running.");
+                    }
                     //print!("Worker {id} completes the job.");
                 }
                 Err(_) => {
@@ -14,7 +14,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "127.0.0.1:7878",
         "./ret",
         4,
-        2
+        2,
+        &[]
     )?;
     println!("MapReduce Client established.");
     client.execute()?;